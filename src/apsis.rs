@@ -0,0 +1,251 @@
+use std::{error::Error, f64::consts::PI};
+
+use krpc_mars::RPCClient;
+
+use crate::{
+    orbit::OrbitalElements,
+    services::space_center::{Node, Vessel},
+    vector::{Vec3D, Vector},
+};
+
+/// Node parameters planned offline, the planners take radii from the primary's centre
+#[derive(Debug, Copy, Clone)]
+pub struct Burn {
+    pub ut: f64,
+    /// Burn as (prograde, normal, radial)
+    pub burn: Vec3D,
+}
+
+impl Burn {
+    /// Add the planned node to the ship
+    pub fn add(&self, client: &mut RPCClient, ship: &Vessel) -> Result<Node, Box<dyn Error>> {
+        let (prograde, normal, radial) = self.burn;
+        let control = ship.get_control().mk_call(client)?;
+        let node = control
+            .add_node(self.ut, prograde as f32, normal as f32, radial as f32)
+            .mk_call(client)?;
+        Ok(node)
+    }
+
+    /// Burn changing the velocity at `ut` to the given one
+    fn to_velocity(orbit: &OrbitalElements, ut: f64, velocity: Vec3D) -> Self {
+        let (_, current) = orbit.state_at(ut);
+        Self {
+            ut,
+            burn: orbit.node_components(ut, velocity.sub(current)),
+        }
+    }
+}
+
+/// Raise or lower the apoapsis with a prograde burn at the next periapsis
+pub fn change_apoapsis(
+    orbit: &OrbitalElements,
+    apoapsis: f64,
+    after: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    let periapsis = orbit.periapsis();
+    if apoapsis < periapsis {
+        return Err("Apoapsis must be above the periapsis".into());
+    }
+    let ut = orbit.ut_at_true_anomaly(0.0, after);
+    let speed = speed_at(orbit.mu, periapsis, (periapsis + apoapsis) / 2.0);
+    Ok(Burn {
+        ut,
+        burn: (speed - orbit.speed_at_radius(periapsis), 0.0, 0.0),
+    })
+}
+
+/// Raise or lower the periapsis with a prograde burn at the next apoapsis
+pub fn change_periapsis(
+    orbit: &OrbitalElements,
+    periapsis: f64,
+    after: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    if orbit.is_hyperbolic() {
+        return Err("Orbit must be closed".into());
+    }
+    let apoapsis = orbit.apoapsis();
+    if periapsis > apoapsis {
+        return Err("Periapsis must be below the apoapsis".into());
+    }
+    let ut = orbit.ut_at_true_anomaly(PI, after);
+    let speed = speed_at(orbit.mu, apoapsis, (periapsis + apoapsis) / 2.0);
+    Ok(Burn {
+        ut,
+        burn: (speed - orbit.speed_at_radius(apoapsis), 0.0, 0.0),
+    })
+}
+
+/// Circularize where the orbit next passes the radius, which must lie between its apsides
+pub fn circularize_at(
+    orbit: &OrbitalElements,
+    radius: f64,
+    after: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    let ut = ut_at_radius(orbit, radius, after)?;
+    let (position, velocity) = orbit.state_at(ut);
+    let along = position.cross(velocity).cross(position).normalize();
+    Ok(Burn::to_velocity(
+        orbit,
+        ut,
+        along.scale((orbit.mu / radius).sqrt()),
+    ))
+}
+
+/// Prograde or retrograde burn at `ut` to the given semi-major axis
+pub fn set_semi_major_axis(
+    orbit: &OrbitalElements,
+    semi_major_axis: f64,
+    ut: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    let radius = orbit.radius_at(ut);
+    if semi_major_axis > 0.0 && 2.0 * semi_major_axis < radius {
+        return Err("Semi-major axis too small to pass the burn point".into());
+    }
+    Ok(Burn {
+        ut,
+        burn: (
+            speed_at(orbit.mu, radius, semi_major_axis) - orbit.speed_at_radius(radius),
+            0.0,
+            0.0,
+        ),
+    })
+}
+
+/// Orbit whose period is `numerator / denominator` of the current one
+/// Releasing a satellite every revolution of an `(n - 1) / n` or `(n + 1) / n` orbit spaces `n` of them evenly
+/// Shorter orbits are entered at the apoapsis and longer ones at the periapsis, so the burn point stays one of the apsides
+pub fn resonant(
+    orbit: &OrbitalElements,
+    numerator: u32,
+    denominator: u32,
+    after: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    if orbit.is_hyperbolic() {
+        return Err("Orbit must be closed".into());
+    }
+    if numerator == 0 || denominator == 0 {
+        return Err("Resonance must be a positive ratio".into());
+    }
+    let ratio = numerator as f64 / denominator as f64;
+    let apsis = if ratio < 1.0 { PI } else { 0.0 };
+    let ut = orbit.ut_at_true_anomaly(apsis, after);
+    set_semi_major_axis(orbit, orbit.semi_major_axis * ratio.powf(2.0 / 3.0), ut)
+}
+
+/// Burn at `ut` into the orbit with the given apsides, in the same plane and direction of travel
+/// The radius at `ut` must lie between the new apsides, keeping the sign of the radial speed
+pub fn change_at(
+    orbit: &OrbitalElements,
+    ut: f64,
+    periapsis: f64,
+    apoapsis: f64,
+) -> Result<Burn, Box<dyn Error>> {
+    let (position, velocity) = orbit.state_at(ut);
+    let radius = position.mag();
+    if !(periapsis <= radius && radius <= apoapsis) {
+        return Err("Burn point must lie between the new apsides".into());
+    }
+    let semi_major_axis = (periapsis + apoapsis) / 2.0;
+    let semi_latus_rectum = 2.0 * periapsis * apoapsis / (periapsis + apoapsis);
+    let speed = speed_at(orbit.mu, radius, semi_major_axis);
+    let horizontal = (orbit.mu * semi_latus_rectum).sqrt() / radius;
+    let radial = (speed.powi(2) - horizontal.powi(2)).max(0.0).sqrt();
+    let up = position.normalize();
+    let radial = if velocity.dot(up) < 0.0 {
+        -radial
+    } else {
+        radial
+    };
+    let along = position.cross(velocity).cross(position).normalize();
+    Ok(Burn::to_velocity(
+        orbit,
+        ut,
+        up.scale(radial).add(along.scale(horizontal)),
+    ))
+}
+
+/// Speed at the radius on an orbit of the given semi-major axis
+fn speed_at(mu: f64, radius: f64, semi_major_axis: f64) -> f64 {
+    (mu * (2.0 / radius - 1.0 / semi_major_axis)).sqrt()
+}
+
+/// First UT at or after `after` the orbit passes the radius
+pub fn ut_at_radius(
+    orbit: &OrbitalElements,
+    radius: f64,
+    after: f64,
+) -> Result<f64, Box<dyn Error>> {
+    if orbit.eccentricity == 0.0 {
+        return if (radius - orbit.semi_major_axis).abs() < 1.0 {
+            Ok(after)
+        } else {
+            Err("Orbit never reaches the radius".into())
+        };
+    }
+    let cos = (orbit.semi_latus_rectum() / radius - 1.0) / orbit.eccentricity;
+    if !(-1.0..=1.0).contains(&cos) {
+        return Err("Orbit never reaches the radius".into());
+    }
+    let true_anomaly = cos.acos();
+    Ok(orbit
+        .ut_at_true_anomaly(true_anomaly, after)
+        .min(orbit.ut_at_true_anomaly(-true_anomaly, after)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        apsis::{change_apoapsis, change_at, circularize_at, resonant, Burn},
+        orbit::OrbitalElements,
+        vector::Vector,
+    };
+
+    fn orbit() -> OrbitalElements {
+        OrbitalElements {
+            semi_major_axis: 800_000.0,
+            eccentricity: 0.1,
+            inclination: 0.3,
+            lan: 1.2,
+            aop: 0.7,
+            mean_anomaly_at_epoch: 0.5,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        }
+    }
+
+    /// Orbit after applying the burn
+    fn after(orbit: &OrbitalElements, burn: &Burn) -> OrbitalElements {
+        let (position, velocity) = orbit.state_at(burn.ut);
+        let prograde = velocity.normalize();
+        let normal = position.cross(velocity).normalize();
+        let radial = prograde.cross(normal);
+        let (p, n, r) = burn.burn;
+        let delta_v = prograde.scale(p).add(normal.scale(n)).add(radial.scale(r));
+        OrbitalElements::from_state(orbit.mu, position, velocity.add(delta_v), burn.ut)
+    }
+
+    #[test]
+    fn test_apsis_changes() {
+        let orbit = orbit();
+        let raised = after(&orbit, &change_apoapsis(&orbit, 1_000_000.0, 10.0).unwrap());
+        assert!((raised.apoapsis() - 1_000_000.0).abs() < 1e-3);
+        assert!((raised.periapsis() - orbit.periapsis()).abs() < 1e-3);
+
+        let circular = after(&orbit, &circularize_at(&orbit, 800_000.0, 10.0).unwrap());
+        assert!(circular.eccentricity < 1e-9);
+        assert!(circularize_at(&orbit, 950_000.0, 10.0).is_err());
+
+        let burn = change_at(&orbit, 500.0, 700_000.0, 1_200_000.0).unwrap();
+        let changed = after(&orbit, &burn);
+        assert!((changed.periapsis() - 700_000.0).abs() < 1e-3);
+        assert!((changed.apoapsis() - 1_200_000.0).abs() < 1e-3);
+        assert!(changed.normal().vang(orbit.normal()) < 1e-9);
+
+        let burn = resonant(&orbit, 2, 3, 10.0).unwrap();
+        assert_eq!(burn.burn.1, 0.0);
+        let deploy = after(&orbit, &burn);
+        assert!((deploy.period() / orbit.period() - 2.0 / 3.0).abs() < 1e-9);
+        assert!((deploy.apoapsis() - orbit.apoapsis()).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{error::Error, f64::consts::TAU};
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    services::space_center::{self, Orbit, ReferenceFrame},
+    vector::{Vec3D, Vector},
+};
+
+/// Seconds between the two positions spanning the orbit's plane
+const NORMAL_STEP: f64 = 10.0;
+
+/// Normal of the orbit's plane along its angular momentum, right-handed with z towards the north pole
+/// `rf` must be the primary's non-rotating reference frame
+/// Two nearby positions span the plane, so open orbits without a period work too
+pub fn plane_normal(
+    client: &mut RPCClient,
+    orbit: &Orbit,
+    rf: ReferenceFrame,
+) -> Result<Vec3D, Box<dyn Error>> {
+    let ut = space_center::get_ut().mk_call(client)?;
+    let (now, later) = batch_call_unwrap!(
+        client,
+        (
+            &orbit.position_at(ut, rf),
+            &orbit.position_at(ut + NORMAL_STEP, rf),
+        )
+    )?;
+    Ok(now
+        .flip_handedness()
+        .cross(later.flip_handedness())
+        .normalize())
+}
+
+/// Compass heading in radians that steers the horizontal velocity towards the target plane
+/// Position and velocity are inertial and right-handed, with z towards the north pole
+/// The heading points along the velocity still missing to reach `orbit_speed` in the plane,
+/// so the surface's rotation and any cross-plane drift are flown out during the ascent
+pub fn heading(position: Vec3D, velocity: Vec3D, normal: Vec3D, orbit_speed: f64) -> f64 {
+    let up = position.normalize();
+    let along = normal.cross(up).normalize();
+    let missing = along.scale(orbit_speed).sub(velocity.reject(up));
+    // Past the orbit speed, hold the plane's own heading
+    let steer = if missing.dot(along) > 0.0 {
+        missing
+    } else {
+        along
+    };
+    let east = (0.0, 0.0, 1.0).cross(up).normalize();
+    let north = up.cross(east);
+    steer.dot(east).atan2(steer.dot(north)).rem_euclid(TAU)
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::FRAC_PI_2;
+
+    use crate::azimuth::heading;
+
+    #[test]
+    fn test_heading_cancels_cross_plane_velocity() {
+        let position = (600_000.0, 0.0, 0.0);
+        // Equatorial plane, flown due east from rest
+        let equatorial = (0.0, 0.0, 1.0);
+        assert!((heading(position, (0.0, 0.0, 0.0), equatorial, 2200.0) - FRAC_PI_2).abs() < 1e-12);
+        // Drifting north, the heading turns south of east
+        let drifting = heading(position, (0.0, 1000.0, 100.0), equatorial, 2200.0);
+        assert!(drifting > FRAC_PI_2);
+        let correction = (drifting - FRAC_PI_2).tan();
+        assert!((correction - 100.0 / 1200.0).abs() < 1e-9);
+
+        // A polar plane from a rotating equatorial site heads west of north
+        let polar = (0.0, -1.0, 0.0);
+        let surface = (0.0, 174.0, 0.0);
+        let polar_heading = heading(position, surface, polar, 2200.0);
+        assert!(polar_heading > 3.0 * FRAC_PI_2);
+        let (east, north) = polar_heading.sin_cos();
+        assert!((east / north + 174.0 / 2200.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{error::Error, f64::consts::TAU, path::Path};
+
+use image::{DynamicImage, GrayImage};
+use krpc_mars::RPCClient;
+
+use crate::services::docking_camera::Camera;
+
+#[derive(Debug, Copy, Clone)]
+pub struct MarkerOptions {
+    /// Brightness from which a pixel belongs to a marker dot
+    pub threshold: u8,
+    /// Fewest pixels in a dot, smaller blobs are noise
+    pub min_area: usize,
+}
+
+impl Default for MarkerOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 200,
+            min_area: 4,
+        }
+    }
+}
+
+/// Target marker seen by the camera: bright dots around the port, the largest one marking its top
+#[derive(Debug, Copy, Clone)]
+pub struct Marker {
+    /// Centre of the dots from the image centre, right and up, in half image widths and heights
+    pub offset: (f64, f64),
+    /// Angle in radians from the image's up to the largest dot, clockwise
+    pub roll: f64,
+    /// RMS distance of the dots from their centre in pixels, shrinking with range
+    pub spread: f64,
+    pub dots: usize,
+}
+
+/// Bright connected blob
+#[derive(Debug, Copy, Clone)]
+struct Dot {
+    x: f64,
+    y: f64,
+    area: usize,
+}
+
+/// Grab and decode a frame from the camera
+pub fn capture(client: &mut RPCClient, camera: &Camera) -> Result<DynamicImage, Box<dyn Error>> {
+    let bytes = camera.get_image().mk_call(client)?;
+    if bytes.is_empty() {
+        return Err("Camera returned no image".into());
+    }
+    Ok(image::load_from_memory(&bytes)?)
+}
+
+/// Grab a frame and save it, the format following the path's extension
+pub fn save(
+    client: &mut RPCClient,
+    camera: &Camera,
+    path: impl AsRef<Path>,
+) -> Result<DynamicImage, Box<dyn Error>> {
+    let frame = capture(client, camera)?;
+    frame.save(path)?;
+    Ok(frame)
+}
+
+/// Find the marker in the frame, if any dot is visible
+pub fn detect(frame: &GrayImage, options: &MarkerOptions) -> Option<Marker> {
+    let dots: Vec<Dot> = dots(frame, options)
+        .into_iter()
+        .filter(|d| d.area >= options.min_area)
+        .collect();
+    let key = *dots.iter().max_by_key(|d| d.area)?;
+    let total: usize = dots.iter().map(|d| d.area).sum();
+    let (cx, cy) = dots.iter().fold((0.0, 0.0), |(x, y), d| {
+        let weight = d.area as f64 / total as f64;
+        (x + d.x * weight, y + d.y * weight)
+    });
+    let spread = (dots
+        .iter()
+        .map(|d| (d.x - cx).powi(2) + (d.y - cy).powi(2))
+        .sum::<f64>()
+        / dots.len() as f64)
+        .sqrt();
+    let (half_width, half_height) = (frame.width() as f64 / 2.0, frame.height() as f64 / 2.0);
+    Some(Marker {
+        offset: (
+            (cx - half_width) / half_width,
+            (half_height - cy) / half_height,
+        ),
+        roll: (key.x - cx).atan2(cy - key.y).rem_euclid(TAU),
+        spread,
+        dots: dots.len(),
+    })
+}
+
+/// Bright blobs joined through their edges, centres in pixel coordinates
+fn dots(frame: &GrayImage, options: &MarkerOptions) -> Vec<Dot> {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let bright = |x: usize, y: usize| frame.get_pixel(x as u32, y as u32).0[0] >= options.threshold;
+    let mut seen = vec![false; width * height];
+    let mut dots = Vec::new();
+    for start in 0..width * height {
+        if seen[start] || !bright(start % width, start / width) {
+            continue;
+        }
+        seen[start] = true;
+        let mut stack = vec![start];
+        let (mut sum_x, mut sum_y, mut area) = (0, 0, 0);
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % width, i / width);
+            sum_x += x;
+            sum_y += y;
+            area += 1;
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < width).then(|| i + 1),
+                (y > 0).then(|| i - width),
+                (y + 1 < height).then(|| i + width),
+            ];
+            for n in neighbours.into_iter().flatten() {
+                if !seen[n] && bright(n % width, n / width) {
+                    seen[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+        // Pixel centres sit half a pixel in
+        dots.push(Dot {
+            x: sum_x as f64 / area as f64 + 0.5,
+            y: sum_y as f64 / area as f64 + 0.5,
+            area,
+        });
+    }
+    dots
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::FRAC_PI_2;
+
+    use image::{GrayImage, Luma};
+
+    use crate::camera::{detect, MarkerOptions};
+
+    fn square(frame: &mut GrayImage, x: u32, y: u32, size: u32) {
+        for dx in 0..size {
+            for dy in 0..size {
+                frame.put_pixel(x + dx, y + dy, Luma([255]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_marker_offset_and_roll() {
+        let mut frame = GrayImage::new(200, 100);
+        // Three equal dots around (130, 50) and a single noisy pixel
+        square(&mut frame, 118, 48, 4);
+        square(&mut frame, 138, 48, 4);
+        square(&mut frame, 128, 38, 4);
+        frame.put_pixel(10, 10, Luma([255]));
+        assert_eq!(detect(&frame, &MarkerOptions::default()).unwrap().dots, 3);
+
+        // A larger dot to the right marks the top, so the marker is rolled a quarter turn
+        let mut frame = GrayImage::new(200, 100);
+        square(&mut frame, 118, 48, 4);
+        square(&mut frame, 138, 47, 6);
+        let marker = detect(&frame, &MarkerOptions::default()).unwrap();
+        assert_eq!(marker.dots, 2);
+        assert!((marker.roll - FRAC_PI_2).abs() < 1e-9);
+        assert!(marker.offset.0 > 0.0 && marker.offset.1.abs() < 1e-9);
+        assert!(marker.spread > 10.0 && marker.spread < 12.0);
+
+        assert!(detect(&GrayImage::new(20, 20), &MarkerOptions::default()).is_none());
+    }
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, error::Error, str::FromStr};
+
+use krpc_mars::{codec::RPCEncodable, RPCClient, StreamClient};
+
+use crate::{
+    error::{self, Call, Missing},
+    services::space_center::{self, CelestialBody, DockingPort, Orbit, Vessel},
+};
+
+pub const USAGE: &str = "Usage: betterjeb [options] <command> [command options]
+
+Commands:
+  launch        Launch into orbit, into the target's plane when there is one
+                  --profile <path> --apoapsis <m> --periapsis <m> --inclination <deg>
+  circ          Circularize at the next apsis
+  execute-node  Execute the next maneuver node
+  plane         Match the target's plane
+                  --apoapsis <m>
+  transfer      Transfer to the target body
+  rendezvous    Meet the target vessel
+  dock          Dock with the targeted docking port
+  land          Land at the site, or wherever the vessel is now
+                  --latitude <deg> --longitude <deg>
+  reentry       Deorbit to the longitude, or reenter from the current orbit
+                  --longitude <deg>
+  deltav        Print the delta-v of each stage
+  porkchop      Sweep transfers to the target body, saving porkchop.csv and porkchop.svg
+  camera        Save the docking camera's view and look for a marker
+                  --output <path> [default: camera.png]
+  terrain       Scan the terrain with LiDAR, saving terrain.csv and terrain.ply
+  record        Forward a client on the server options to the game's server, logging the traffic
+                  --log <path> --server-rpc <address> [default: 127.0.0.1:50002]
+                  --server-stream <address> [default: 127.0.0.1:50003]
+  replay        Serve a recorded log on the server options
+                  --log <path>
+
+Options:
+  --host <host>         Server address [default: 127.0.0.1]
+  --rpc-port <port>     [default: 50000]
+  --stream-port <port>  [default: 50001]
+  --name <name>         Client name shown by the server [default: betterjeb]
+  --target <name>       Vessel or body to target, the game's target when not set";
+
+/// Command line of the `betterjeb` tool
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub host: String,
+    pub rpc_port: u16,
+    pub stream_port: u16,
+    pub name: String,
+    /// Vessel or body to target by name, the game's target when not set
+    pub target: Option<String>,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    /// Altitudes in m override the profile's, inclination in degrees is flown without a target
+    Launch {
+        profile: Option<String>,
+        apoapsis: Option<f64>,
+        periapsis: Option<f64>,
+        inclination: Option<f64>,
+    },
+    Circ,
+    ExecuteNode,
+    Plane {
+        apoapsis: Option<f64>,
+    },
+    Transfer,
+    Rendezvous,
+    Dock,
+    /// Latitude and longitude in degrees
+    Land {
+        site: Option<(f64, f64)>,
+    },
+    Reentry {
+        longitude: Option<f64>,
+    },
+    Deltav,
+    Porkchop,
+    Camera {
+        output: String,
+    },
+    Terrain,
+    /// Listens on the server options, the game's server has to be moved to other ports
+    Record {
+        log: String,
+        server_rpc: String,
+        server_stream: String,
+    },
+    Replay {
+        log: String,
+    },
+}
+
+/// Parse the arguments after the program name, options may come before or after the command
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, Box<dyn Error>> {
+    let mut command = None;
+    let mut flags = Flags::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            command = Some("help".to_string());
+        } else if let Some(flag) = arg.strip_prefix("--") {
+            let value = args.next().ok_or(format!("Missing value for --{flag}"))?;
+            flags.0.insert(flag.to_string(), value);
+        } else if command.is_none() {
+            command = Some(arg);
+        } else {
+            return Err(format!("Unexpected argument: {arg}").into());
+        }
+    }
+
+    let command = match command.as_deref() {
+        None | Some("help") => Command::Help,
+        Some("launch") => Command::Launch {
+            profile: flags.take("profile")?,
+            apoapsis: flags.take("apoapsis")?,
+            periapsis: flags.take("periapsis")?,
+            inclination: flags.take("inclination")?,
+        },
+        Some("circ") => Command::Circ,
+        Some("execute-node") => Command::ExecuteNode,
+        Some("plane") => Command::Plane {
+            apoapsis: flags.take("apoapsis")?,
+        },
+        Some("transfer") => Command::Transfer,
+        Some("rendezvous") => Command::Rendezvous,
+        Some("dock") => Command::Dock,
+        Some("land") => {
+            let site = match (flags.take("latitude")?, flags.take("longitude")?) {
+                (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+                (None, None) => None,
+                _ => return Err("Landing needs both --latitude and --longitude".into()),
+            };
+            Command::Land { site }
+        }
+        Some("reentry") => Command::Reentry {
+            longitude: flags.take("longitude")?,
+        },
+        Some("deltav") => Command::Deltav,
+        Some("porkchop") => Command::Porkchop,
+        Some("camera") => Command::Camera {
+            output: flags.take("output")?.unwrap_or("camera.png".to_string()),
+        },
+        Some("terrain") => Command::Terrain,
+        Some("record") => Command::Record {
+            log: flags.take("log")?.ok_or("Recording needs --log")?,
+            server_rpc: flags
+                .take("server-rpc")?
+                .unwrap_or("127.0.0.1:50002".to_string()),
+            server_stream: flags
+                .take("server-stream")?
+                .unwrap_or("127.0.0.1:50003".to_string()),
+        },
+        Some("replay") => Command::Replay {
+            log: flags.take("log")?.ok_or("Replaying needs --log")?,
+        },
+        Some(command) => return Err(format!("Unknown command: {command}").into()),
+    };
+    let options = Options {
+        host: flags.take("host")?.unwrap_or("127.0.0.1".to_string()),
+        rpc_port: flags.take("rpc-port")?.unwrap_or(50000),
+        stream_port: flags.take("stream-port")?.unwrap_or(50001),
+        name: flags.take("name")?.unwrap_or("betterjeb".to_string()),
+        target: flags.take("target")?,
+        command,
+    };
+    match flags.0.keys().next() {
+        Some(flag) => Err(format!("Unknown option: --{flag}").into()),
+        None => Ok(options),
+    }
+}
+
+#[derive(Default)]
+struct Flags(HashMap<String, String>);
+
+impl Flags {
+    fn take<T: FromStr>(&mut self, flag: &str) -> Result<Option<T>, Box<dyn Error>> {
+        match self.0.remove(flag) {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(format!("Invalid value for --{flag}: {value}").into()),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Options {
+    pub fn connect(&self) -> Result<(RPCClient, StreamClient), Box<dyn Error>> {
+        let client = RPCClient::connect(&self.name, (self.host.as_str(), self.rpc_port))?;
+        let stream_client = StreamClient::connect(&client, (self.host.as_str(), self.stream_port))?;
+        Ok((client, stream_client))
+    }
+}
+
+/// Vessel or body to fly to
+#[derive(Debug)]
+pub enum Target {
+    Vessel(Vessel),
+    Body(CelestialBody),
+}
+
+impl Target {
+    pub fn orbit(&self, client: &mut RPCClient) -> Result<Orbit, error::Error> {
+        match self {
+            Target::Vessel(vessel) => vessel.get_orbit().call(client),
+            Target::Body(body) => body.get_orbit().call(client),
+        }
+    }
+}
+
+/// The server hands out object id 0 for nothing, like an empty target
+pub fn is_null(object: &impl RPCEncodable) -> bool {
+    object.encode_to_bytes().map_or(true, |bytes| bytes == [0])
+}
+
+pub fn active_vessel(client: &mut RPCClient) -> Result<Vessel, error::Error> {
+    let vessel = space_center::get_active_vessel().call(client)?;
+    if is_null(&vessel) {
+        return Err(error::Error::Missing(Missing::Vessel));
+    }
+    Ok(vessel)
+}
+
+/// Vessel or else body with the given name, the game's target without a name
+pub fn target(client: &mut RPCClient, name: Option<&str>) -> Result<Target, error::Error> {
+    let Some(name) = name else {
+        let vessel = space_center::get_target_vessel().call(client)?;
+        if !is_null(&vessel) {
+            return Ok(Target::Vessel(vessel));
+        }
+        let body = space_center::get_target_body().call(client)?;
+        if !is_null(&body) {
+            return Ok(Target::Body(body));
+        }
+        return Err(error::Error::Missing(Missing::Target));
+    };
+    for vessel in space_center::get_vessels().call(client)? {
+        if vessel.get_name().call(client)? == name {
+            return Ok(Target::Vessel(vessel));
+        }
+    }
+    space_center::get_bodies()
+        .call(client)?
+        .remove(name)
+        .map(Target::Body)
+        .ok_or(error::Error::Missing(Missing::Target))
+}
+
+/// The game's targeted docking port
+pub fn target_docking_port(client: &mut RPCClient) -> Result<DockingPort, error::Error> {
+    let port = space_center::get_target_docking_port().call(client)?;
+    if is_null(&port) {
+        return Err(error::Error::Missing(Missing::Target));
+    }
+    Ok(port)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::{parse, Command, Options};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_options_around_command() {
+        let options = parse(args(
+            "--host 10.0.0.2 launch --apoapsis 80000 --target Mun --inclination 6",
+        ))
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                host: "10.0.0.2".to_string(),
+                rpc_port: 50000,
+                stream_port: 50001,
+                name: "betterjeb".to_string(),
+                target: Some("Mun".to_string()),
+                command: Command::Launch {
+                    profile: None,
+                    apoapsis: Some(80000.0),
+                    periapsis: None,
+                    inclination: Some(6.0),
+                },
+            }
+        );
+        assert_eq!(
+            parse(args("land --latitude -0.1 --longitude 285.4"))
+                .unwrap()
+                .command,
+            Command::Land {
+                site: Some((-0.1, 285.4))
+            }
+        );
+        assert_eq!(parse(args("")).unwrap().command, Command::Help);
+        assert_eq!(
+            parse(args("record --log launch.log --rpc-port 50010"))
+                .unwrap()
+                .command,
+            Command::Record {
+                log: "launch.log".to_string(),
+                server_rpc: "127.0.0.1:50002".to_string(),
+                server_stream: "127.0.0.1:50003".to_string(),
+            }
+        );
+
+        assert!(parse(args("land --latitude 1")).is_err());
+        assert!(parse(args("circ --apoapsis 80000")).is_err());
+        assert!(parse(args("launch --rpc-port high")).is_err());
+        assert!(parse(args("launch circ")).is_err());
+        assert!(parse(args("orbit")).is_err());
+        assert!(parse(args("replay")).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::{collections::HashMap, error::Error};
+
+use krpc_mars::{batch_call_unwrap, codec::RPCEncodable, RPCClient};
+
+use crate::{
+    services::space_center::{Part, ResourceFlowMode, Vessel},
+    stage::G0,
+};
+
+/// Most flow events simulated in a single stage
+const MAX_EVENTS: usize = 1000;
+/// Resource amount, in units, below which a tank counts as empty
+const EMPTY: f64 = 1e-6;
+
+/// How far a resource can flow from the part holding it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Flow {
+    /// Only to the part itself, like solid fuel
+    Part,
+    /// Through parts connected by crossfeed or fuel lines
+    Crossfeed,
+    /// Anywhere on the vessel
+    Vessel,
+}
+
+impl From<ResourceFlowMode> for Flow {
+    fn from(mode: ResourceFlowMode) -> Self {
+        match mode {
+            ResourceFlowMode::None => Flow::Part,
+            ResourceFlowMode::Vessel => Flow::Vessel,
+            ResourceFlowMode::Stage | ResourceFlowMode::Adjacent => Flow::Crossfeed,
+        }
+    }
+}
+
+/// Resource held by a part, amount in units and density in kg per unit
+#[derive(Debug, Clone)]
+pub struct Tank {
+    pub name: String,
+    pub amount: f64,
+    pub density: f64,
+    pub flow: Flow,
+}
+
+#[derive(Debug, Clone)]
+pub struct PartNode {
+    pub decouple_stage: i32,
+    pub dry_mass: f64,
+    /// Whether resources can flow through the part to its neighbours
+    pub crossfeed: bool,
+    pub parent: Option<usize>,
+    pub fuel_lines: Vec<usize>,
+    pub tanks: Vec<Tank>,
+}
+
+impl PartNode {
+    pub fn mass(&self) -> f64 {
+        self.dry_mass + self.tanks.iter().map(|t| t.amount * t.density).sum::<f64>()
+    }
+}
+
+/// Engine with its vacuum thrust in N and the units of each propellant it burns per unit of flow
+#[derive(Debug, Clone)]
+pub struct EngineNode {
+    pub part: usize,
+    pub stage: i32,
+    pub thrust: f64,
+    pub vacuum_isp: f64,
+    pub sea_level_isp: f64,
+    pub propellants: Vec<(String, f64)>,
+}
+
+impl EngineNode {
+    /// Thrust at sea level, fuel flow does not change with pressure
+    pub fn sea_level_thrust(&self) -> f64 {
+        self.thrust * self.sea_level_isp / self.vacuum_isp
+    }
+
+    /// Propellant mass burnt per second
+    pub fn mass_flow(&self) -> f64 {
+        self.thrust / (self.vacuum_isp * G0)
+    }
+}
+
+/// Part tree of a vessel, indexed by position in `parts`
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    pub current_stage: i32,
+    pub parts: Vec<PartNode>,
+    pub engines: Vec<EngineNode>,
+}
+
+/// Delta-v readout for one stage, masses in kg, thrust in N and burn time in s
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StageDeltaV {
+    pub number: i32,
+    pub start_mass: f64,
+    pub end_mass: f64,
+    pub vacuum_thrust: f64,
+    pub sea_level_thrust: f64,
+    pub vacuum_delta_v: f64,
+    pub sea_level_delta_v: f64,
+    pub vacuum_twr: f64,
+    pub sea_level_twr: f64,
+    pub burn_time: f64,
+}
+
+fn key(part: &Part) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(part.encode_to_bytes()?)
+}
+
+impl Vehicle {
+    pub fn snapshot(client: &mut RPCClient, vessel: &Vessel) -> Result<Self, Box<dyn Error>> {
+        let (parts, control) =
+            batch_call_unwrap!(client, (&vessel.get_parts(), &vessel.get_control()))?;
+        let current_stage = control.get_current_stage().mk_call(client)?;
+        let all = parts.get_all().mk_call(client)?;
+        let mut index = HashMap::new();
+        for (i, part) in all.iter().enumerate() {
+            index.insert(key(part)?, i);
+        }
+
+        let mut nodes = Vec::new();
+        for part in &all {
+            let (decouple_stage, dry_mass, crossfeed, parent, fuel_lines, resources) = batch_call_unwrap!(
+                client,
+                (
+                    &part.get_decouple_stage(),
+                    &part.get_dry_mass(),
+                    &part.get_crossfeed(),
+                    &part.get_parent(),
+                    &part.get_fuel_lines_to(),
+                    &part.get_resources(),
+                )
+            )?;
+            let mut tanks = Vec::new();
+            for resource in resources.get_all().mk_call(client)? {
+                let (name, amount, density, flow) = batch_call_unwrap!(
+                    client,
+                    (
+                        &resource.get_name(),
+                        &resource.get_amount(),
+                        &resource.get_density(),
+                        &resource.get_flow_mode(),
+                    )
+                )?;
+                tanks.push(Tank {
+                    name,
+                    amount: amount as f64,
+                    density: density as f64,
+                    flow: flow.into(),
+                });
+            }
+            let mut lines = Vec::new();
+            for line in &fuel_lines {
+                lines.extend(index.get(&key(line)?));
+            }
+            nodes.push(PartNode {
+                decouple_stage,
+                dry_mass,
+                crossfeed,
+                parent: index.get(&key(&parent)?).copied(),
+                fuel_lines: lines,
+                tanks,
+            });
+        }
+
+        let mut engines = Vec::new();
+        for engine in parts.get_engines().mk_call(client)? {
+            let (part, thrust, limit, vacuum_isp, sea_level_isp, ratios) = batch_call_unwrap!(
+                client,
+                (
+                    &engine.get_part(),
+                    &engine.get_max_vacuum_thrust(),
+                    &engine.get_thrust_limit(),
+                    &engine.get_vacuum_specific_impulse(),
+                    &engine.get_kerbin_sea_level_specific_impulse(),
+                    &engine.get_propellant_ratios(),
+                )
+            )?;
+            engines.push(EngineNode {
+                part: *index.get(&key(&part)?).ok_or("Engine part not found")?,
+                stage: part.get_stage().mk_call(client)?,
+                thrust: (thrust * limit) as f64,
+                vacuum_isp: vacuum_isp as f64,
+                sea_level_isp: sea_level_isp as f64,
+                propellants: ratios
+                    .into_iter()
+                    .map(|(name, ratio)| (name, ratio as f64))
+                    .collect(),
+            });
+        }
+
+        Ok(Self {
+            current_stage,
+            parts: nodes,
+            engines,
+        })
+    }
+
+    /// Burn every stage from the current one down to 0, TWR is against `gravity` in m/s²
+    pub fn analyze(&self, gravity: f64) -> Vec<StageDeltaV> {
+        let mut parts = self.parts.clone();
+        (0..=self.current_stage)
+            .rev()
+            .map(|number| burn_stage(&mut parts, &self.engines, number, gravity))
+            .collect()
+    }
+}
+
+/// Simulate one stage until its engines flame out, or until the engines it drops next do
+fn burn_stage(
+    parts: &mut [PartNode],
+    engines: &[EngineNode],
+    number: i32,
+    gravity: f64,
+) -> StageDeltaV {
+    let present: Vec<bool> = parts.iter().map(|p| p.decouple_stage < number).collect();
+    let groups = crossfeed_groups(parts, &present);
+    let active: Vec<&EngineNode> = engines
+        .iter()
+        .filter(|e| present[e.part] && e.stage >= number && e.thrust > 0.0)
+        .collect();
+    let dropped = |e: &EngineNode, parts: &[PartNode]| parts[e.part].decouple_stage == number - 1;
+    let drops_engines = active.iter().any(|e| dropped(e, parts));
+    let mass = |parts: &[PartNode]| -> f64 {
+        parts
+            .iter()
+            .zip(&present)
+            .filter(|(_, &here)| here)
+            .map(|(p, _)| p.mass())
+            .sum()
+    };
+
+    let start_mass = mass(parts);
+    let mut result = StageDeltaV {
+        number,
+        start_mass,
+        end_mass: start_mass,
+        ..Default::default()
+    };
+    for _ in 0..MAX_EVENTS {
+        let burning: Vec<(&EngineNode, Vec<Draw>)> = active
+            .iter()
+            .filter_map(|&e| Some((e, draws(parts, &present, &groups, e)?)))
+            .collect();
+        if burning.is_empty() || (drops_engines && !burning.iter().any(|(e, _)| dropped(e, parts)))
+        {
+            break;
+        }
+
+        let thrust: f64 = burning.iter().map(|(e, _)| e.thrust).sum();
+        let sea_level_thrust: f64 = burning.iter().map(|(e, _)| e.sea_level_thrust()).sum();
+        let flow: f64 = burning.iter().map(|(e, _)| e.mass_flow()).sum();
+        if result.burn_time == 0.0 {
+            result.vacuum_thrust = thrust;
+            result.sea_level_thrust = sea_level_thrust;
+        }
+
+        let mut rates: HashMap<(usize, usize), f64> = HashMap::new();
+        for draw in burning.iter().flat_map(|(_, d)| d) {
+            *rates.entry((draw.part, draw.tank)).or_default() += draw.rate;
+        }
+        let dt = rates
+            .iter()
+            .map(|(&(p, t), rate)| parts[p].tanks[t].amount / rate)
+            .fold(f64::INFINITY, f64::min);
+        let m0 = mass(parts);
+        for ((p, t), rate) in rates {
+            let tank = &mut parts[p].tanks[t];
+            tank.amount -= rate * dt;
+            if tank.amount < EMPTY {
+                tank.amount = 0.0;
+            }
+        }
+        let m1 = mass(parts);
+
+        let ratio = (m0 / m1).ln();
+        result.vacuum_delta_v += thrust / flow * ratio;
+        result.sea_level_delta_v += sea_level_thrust / flow * ratio;
+        result.burn_time += dt;
+        result.end_mass = m1;
+    }
+    result.vacuum_twr = result.vacuum_thrust / (start_mass * gravity);
+    result.sea_level_twr = result.sea_level_thrust / (start_mass * gravity);
+    result
+}
+
+/// Units per second taken from one tank
+struct Draw {
+    part: usize,
+    tank: usize,
+    rate: f64,
+}
+
+/// Tanks the engine feeds from, or None when a propellant has run out
+/// Among reachable tanks, those decoupled soonest are drained first
+fn draws(
+    parts: &[PartNode],
+    present: &[bool],
+    groups: &[usize],
+    engine: &EngineNode,
+) -> Option<Vec<Draw>> {
+    let mut sources = Vec::new();
+    for (name, ratio) in &engine.propellants {
+        let (part, tank) = parts
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| present[i])
+            .flat_map(|(i, p)| {
+                p.tanks
+                    .iter()
+                    .enumerate()
+                    .map(move |(t, tank)| (i, t, tank))
+            })
+            .filter(|(i, _, tank)| {
+                &tank.name == name
+                    && tank.amount > EMPTY
+                    && match tank.flow {
+                        Flow::Part => *i == engine.part,
+                        Flow::Crossfeed => groups[*i] == groups[engine.part],
+                        Flow::Vessel => true,
+                    }
+            })
+            .max_by_key(|(i, _, _)| parts[*i].decouple_stage)
+            .map(|(i, t, _)| (i, t))?;
+        sources.push((part, tank, *ratio));
+    }
+    let flow_density: f64 = sources
+        .iter()
+        .map(|&(p, t, ratio)| ratio * parts[p].tanks[t].density)
+        .sum();
+    if flow_density <= 0.0 {
+        return None;
+    }
+    let units = engine.mass_flow() / flow_density;
+    Some(
+        sources
+            .into_iter()
+            .map(|(part, tank, ratio)| Draw {
+                part,
+                tank,
+                rate: units * ratio,
+            })
+            .collect(),
+    )
+}
+
+/// Label every present part with the root of the crossfeed group it belongs to
+fn crossfeed_groups(parts: &[PartNode], present: &[bool]) -> Vec<usize> {
+    fn find(root: &mut [usize], mut i: usize) -> usize {
+        while root[i] != i {
+            root[i] = root[root[i]];
+            i = root[i];
+        }
+        i
+    }
+
+    let mut root: Vec<usize> = (0..parts.len()).collect();
+    for (i, part) in parts.iter().enumerate().filter(|&(i, _)| present[i]) {
+        let parent = part
+            .parent
+            .filter(|&p| part.crossfeed && parts[p].crossfeed);
+        for j in part.fuel_lines.iter().copied().chain(parent) {
+            if present[j] {
+                let (a, b) = (find(&mut root, i), find(&mut root, j));
+                root[a] = b;
+            }
+        }
+    }
+    (0..parts.len()).map(|i| find(&mut root, i)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        deltav::{EngineNode, Flow, PartNode, Tank, Vehicle},
+        stage::G0,
+    };
+
+    fn part(decouple_stage: i32, parent: Option<usize>, tanks: Vec<Tank>) -> PartNode {
+        PartNode {
+            decouple_stage,
+            dry_mass: 500.0,
+            crossfeed: true,
+            parent,
+            fuel_lines: vec![],
+            tanks,
+        }
+    }
+
+    fn liquid(units: f64) -> Vec<Tank> {
+        vec![
+            Tank {
+                name: "LiquidFuel".into(),
+                amount: 0.9 * units,
+                density: 5.0,
+                flow: Flow::Crossfeed,
+            },
+            Tank {
+                name: "Oxidizer".into(),
+                amount: 1.1 * units,
+                density: 5.0,
+                flow: Flow::Crossfeed,
+            },
+        ]
+    }
+
+    fn engine(part: usize, stage: i32, thrust: f64, propellant: &[(&str, f64)]) -> EngineNode {
+        EngineNode {
+            part,
+            stage,
+            thrust,
+            vacuum_isp: 320.0,
+            sea_level_isp: 280.0,
+            propellants: propellant
+                .iter()
+                .map(|&(name, ratio)| (name.into(), ratio))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_single_stage_matches_rocket_equation() {
+        let vehicle = Vehicle {
+            current_stage: 1,
+            parts: vec![part(-1, None, liquid(400.0)), part(-1, Some(0), vec![])],
+            engines: vec![engine(
+                1,
+                1,
+                50_000.0,
+                &[("LiquidFuel", 0.9), ("Oxidizer", 1.1)],
+            )],
+        };
+        let stages = vehicle.analyze(9.81);
+        assert_eq!(stages.len(), 2);
+        let burn = &stages[0];
+        assert_eq!(burn.start_mass, 5000.0);
+        assert!((burn.end_mass - 1000.0).abs() < 1e-6);
+        assert!((burn.vacuum_delta_v - 320.0 * G0 * 5f64.ln()).abs() < 1e-6);
+        assert!((burn.sea_level_delta_v - 280.0 * G0 * 5f64.ln()).abs() < 1e-6);
+        assert!((burn.vacuum_twr - 50_000.0 / (5000.0 * 9.81)).abs() < 1e-9);
+        assert!((burn.burn_time - 4000.0 / (50_000.0 / (320.0 * G0))).abs() < 1e-6);
+        assert_eq!(stages[1].vacuum_delta_v, 0.0);
+    }
+
+    #[test]
+    fn test_booster_stage_ends_at_burnout() {
+        // Core tank and engine, a radial decoupler and a solid booster dropped by stage 1
+        let mut decoupler = part(1, Some(0), vec![]);
+        decoupler.crossfeed = false;
+        let booster = part(
+            1,
+            Some(2),
+            vec![Tank {
+                name: "SolidFuel".into(),
+                amount: 200.0,
+                density: 7.5,
+                flow: Flow::Part,
+            }],
+        );
+        let vehicle = Vehicle {
+            current_stage: 2,
+            parts: vec![
+                part(-1, None, liquid(400.0)),
+                part(-1, Some(0), vec![]),
+                decoupler,
+                booster,
+            ],
+            engines: vec![
+                engine(1, 2, 50_000.0, &[("LiquidFuel", 0.9), ("Oxidizer", 1.1)]),
+                engine(3, 2, 100_000.0, &[("SolidFuel", 1.0)]),
+            ],
+        };
+        let stages = vehicle.analyze(9.81);
+        let booster_time = 1500.0 / (100_000.0 / (320.0 * G0));
+        assert!((stages[0].burn_time - booster_time).abs() < 1e-6);
+        assert_eq!(stages[0].vacuum_thrust, 150_000.0);
+        // The core keeps what it did not burn alongside the booster
+        assert_eq!(stages[1].start_mass, stages[0].end_mass - 1000.0);
+        assert!(stages[1].end_mass - 1000.0 < 1e-6);
+        assert!(stages[1].vacuum_delta_v > 0.0);
+    }
+}
@@ -0,0 +1,286 @@
+use std::error::Error;
+
+use krpc_mars::{batch_call_unwrap, stream::StreamHandle, RPCClient, StreamClient};
+
+use crate::{
+    cli, error,
+    services::space_center::{Control, DockingPort, DockingPortState, ReferenceFrame, Vessel},
+    vector::{Quat, Quaternion, Vec3D, Vector},
+};
+
+#[derive(Debug, Copy, Clone)]
+pub struct DockingOptions {
+    /// Distance out along the target port's axis to hold while lining up
+    pub safety_distance: f64,
+    /// Offset from the port's axis at which the final approach may start
+    pub lateral_tolerance: f64,
+    /// Fastest translation speed
+    pub max_speed: f64,
+    /// Closing speed at contact
+    pub final_speed: f64,
+    /// Seconds to close the remaining distance, setting the speed between the two limits
+    pub approach_time: f64,
+    /// Translation input per m/s of velocity error
+    pub gain: f64,
+}
+
+impl Default for DockingOptions {
+    fn default() -> Self {
+        Self {
+            safety_distance: 20.0,
+            lateral_tolerance: 0.2,
+            max_speed: 2.0,
+            final_speed: 0.2,
+            approach_time: 10.0,
+            gain: 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Phase {
+    /// Back away along the port's axis to the safety distance before moving sideways
+    BackOff,
+    /// Line up on the port's axis at the safety distance or further
+    Align,
+    /// Close in along the port's axis
+    Approach,
+}
+
+/// Dock the ship, controlled from one of its docking ports, to the targeted docking port
+/// The autopilot points the ship's port at the target port while RCS translation flies the approach
+pub fn dock(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    target: &DockingPort,
+    options: &DockingOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (parts, control, auto_pilot) = batch_call_unwrap!(
+        client,
+        (
+            &ship.get_parts(),
+            &ship.get_control(),
+            &ship.get_auto_pilot(),
+        )
+    )?;
+    let port = parts
+        .get_controlling()
+        .mk_call(client)?
+        .get_docking_port()
+        .mk_call(client)?;
+    if cli::is_null(&port) {
+        return Err("Control the ship from a docking port".into());
+    }
+    let rf = target.get_reference_frame().mk_call(client)?;
+
+    control.set_rcs(true).mk_call(client)?;
+    auto_pilot.set_reference_frame(rf).mk_call(client)?;
+    auto_pilot
+        .set_target_direction((0.0, -1.0, 0.0))
+        .mk_call(client)?;
+    auto_pilot.engage().mk_call(client)?;
+    auto_pilot.wait().mk_call(client)?;
+
+    let streamer = Streamer::init(client, ship, &control, &port, rf)?;
+    let mut telemetry = Telemetry::default();
+    let mut prev_phase = None;
+    loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        if matches!(telemetry.state, Some(DockingPortState::Docked)) {
+            break;
+        }
+        if telemetry.abort {
+            release(client, &control)?;
+            auto_pilot.disengage().mk_call(client)?;
+            streamer.stop(client)?;
+            return Err(error::Error::Aborted.into());
+        }
+        // Magnets have caught the port, stop translating and let them pull it in
+        let input = if matches!(telemetry.state, Some(DockingPortState::Docking)) {
+            (0.0, 0.0, 0.0)
+        } else {
+            let (phase, wanted) = command(telemetry.position, options);
+            if prev_phase != Some(phase) {
+                println!("{phase:?}");
+                prev_phase = Some(phase);
+            }
+            // Velocity error in the ship's own axes: x right, y forward, z down
+            telemetry
+                .rotation
+                .inverse()
+                .rotate(wanted.sub(telemetry.velocity))
+                .scale(options.gain)
+        };
+        let (right, forward, down) = input;
+        batch_call_unwrap!(
+            client,
+            (
+                &control.set_right(right.clamp(-1.0, 1.0) as f32),
+                &control.set_forward(forward.clamp(-1.0, 1.0) as f32),
+                &control.set_up(-down.clamp(-1.0, 1.0) as f32),
+            )
+        )?;
+    }
+    release(client, &control)?;
+    auto_pilot.disengage().mk_call(client)?;
+    streamer.stop(client)?;
+    println!("Docked");
+    Ok(())
+}
+
+/// Stop translating
+fn release(client: &mut RPCClient, control: &Control) -> Result<(), Box<dyn Error>> {
+    batch_call_unwrap!(
+        client,
+        (
+            &control.set_right(0.0),
+            &control.set_forward(0.0),
+            &control.set_up(0.0),
+        )
+    )?;
+    Ok(())
+}
+
+/// Phase and velocity to fly, both in the target port's reference frame with y out of the port
+pub fn command(position: Vec3D, options: &DockingOptions) -> (Phase, Vec3D) {
+    let (x, y, z) = position;
+    let lateral = (x.powi(2) + z.powi(2)).sqrt();
+    let (phase, point) = if lateral <= options.lateral_tolerance && y > 0.0 {
+        (Phase::Approach, (0.0, 0.0, 0.0))
+    } else if y < options.safety_distance {
+        (Phase::BackOff, (x, options.safety_distance, z))
+    } else {
+        (Phase::Align, (0.0, y, 0.0))
+    };
+    let offset = point.sub(position);
+    let distance = offset.mag();
+    if distance == 0.0 {
+        return (phase, (0.0, 0.0, 0.0));
+    }
+    let speed = (distance / options.approach_time).min(options.max_speed);
+    let speed = match phase {
+        Phase::Approach => speed.max(options.final_speed),
+        _ => speed,
+    };
+    (phase, offset.normalize().scale(speed))
+}
+
+pub struct Telemetry {
+    /// Ship port position and ship velocity in the target port's reference frame
+    position: Vec3D,
+    velocity: Vec3D,
+    /// Ship rotation in the target port's reference frame
+    rotation: Quat,
+    state: Option<DockingPortState>,
+    abort: bool,
+}
+
+impl Default for Telemetry {
+    /// Unrotated until the first update, the all-zero quaternion has no inverse
+    fn default() -> Self {
+        Self {
+            position: (0.0, 0.0, 0.0),
+            velocity: (0.0, 0.0, 0.0),
+            rotation: Quat::identity(),
+            state: None,
+            abort: false,
+        }
+    }
+}
+
+pub struct Streamer {
+    position: StreamHandle<Vec3D>,
+    velocity: StreamHandle<Vec3D>,
+    rotation: StreamHandle<Quat>,
+    state: StreamHandle<DockingPortState>,
+    abort: StreamHandle<bool>,
+}
+
+impl Streamer {
+    pub fn init(
+        client: &mut RPCClient,
+        vessel: &Vessel,
+        control: &Control,
+        port: &DockingPort,
+        rf: ReferenceFrame,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            position: port.position(rf).to_stream().mk_call(client)?,
+            velocity: vessel.velocity(rf).to_stream().mk_call(client)?,
+            rotation: vessel.rotation(rf).to_stream().mk_call(client)?,
+            state: port.get_state().to_stream().mk_call(client)?,
+            abort: control.get_abort().to_stream().mk_call(client)?,
+        })
+    }
+
+    pub fn update(
+        &self,
+        stream_client: &mut StreamClient,
+        telemetry: &mut Telemetry,
+    ) -> Result<(), Box<dyn Error>> {
+        let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.position)? {
+            telemetry.position = val;
+        }
+        if let Some(val) = update.get_result(&self.velocity)? {
+            telemetry.velocity = val;
+        }
+        if let Some(val) = update.get_result(&self.rotation)? {
+            telemetry.rotation = val;
+        }
+        if let Some(val) = update.get_result(&self.state)? {
+            telemetry.state = Some(val);
+        }
+        if let Some(val) = update.get_result(&self.abort)? {
+            telemetry.abort = val;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Box<dyn Error>> {
+        batch_call_unwrap!(
+            client,
+            (
+                &self.position.remove(),
+                &self.velocity.remove(),
+                &self.rotation.remove(),
+                &self.state.remove(),
+                &self.abort.remove(),
+            )
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        docking::{command, DockingOptions, Phase, Telemetry},
+        vector::{Quaternion, Vector},
+    };
+
+    #[test]
+    fn test_command_phases() {
+        let options = DockingOptions::default();
+        // Beside the target port, back away before lining up
+        let (phase, velocity) = command((5.0, 2.0, 0.0), &options);
+        assert_eq!(phase, Phase::BackOff);
+        assert!(velocity.0 == 0.0 && velocity.1 > 0.0);
+        let (phase, velocity) = command((5.0, 30.0, 0.0), &options);
+        assert_eq!(phase, Phase::Align);
+        assert!(velocity.0 < 0.0 && velocity.1 == 0.0);
+        // On the axis, close in at no less than the final speed
+        let (phase, velocity) = command((0.0, 0.5, 0.1), &options);
+        assert_eq!(phase, Phase::Approach);
+        assert!((velocity.mag() - options.final_speed).abs() < 1e-12);
+        let (_, velocity) = command((0.0, 100.0, 0.0), &options);
+        assert!((velocity.mag() - options.max_speed).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_telemetry_starts_unrotated() {
+        let rotation = Telemetry::default().rotation;
+        assert_eq!(rotation.inverse().rotate((1.0, 2.0, 3.0)), (1.0, 2.0, 3.0));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{error::Error, f64::consts::TAU};
+
+use krpc_mars::RPCClient;
+
+use crate::{
+    error, lambert,
+    orbit::OrbitalElements,
+    porkchop::Planet,
+    services::space_center::{CelestialBody, Node, Vessel},
+    vector::{Quat, Quaternion, Vec3D, Vector},
+};
+
+const EJECTION_TOLERANCE: f64 = 0.01;
+const EJECTION_MAX_ITER: usize = 20;
+
+/// Burn that leaves the parking orbit on a hyperbola with the requested excess velocity
+#[derive(Debug, Copy, Clone)]
+pub struct Ejection {
+    pub ut: f64,
+    /// Burn as (prograde, normal, radial)
+    pub burn: Vec3D,
+    pub excess_velocity: Vec3D,
+}
+
+/// Plan and add the ejection node for an interplanetary transfer
+/// The ship must orbit a planet of the same star as the destination
+/// Returns the node and the predicted miss distance at the destination on arrival
+pub fn ejection(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    destination: &CelestialBody,
+    departure_ut: f64,
+    arrival_ut: f64,
+) -> Result<(Node, f64), Box<dyn Error>> {
+    let ship_orbit = ship.get_orbit().mk_call(client)?;
+    let origin = ship_orbit.get_body().mk_call(client)?;
+    let parking = OrbitalElements::snapshot(client, &ship_orbit)?;
+    let origin = Planet::snapshot(client, &origin)?;
+    let destination = Planet::snapshot(client, destination)?;
+
+    let transfer = lambert::plan(
+        &origin.orbit,
+        &destination.orbit,
+        departure_ut,
+        arrival_ut,
+        0,
+    )?;
+    let (_, origin_velocity) = origin.orbit.state_at(departure_ut);
+    let excess_velocity = transfer.departure_velocity.sub(origin_velocity);
+    let ejection = plan(
+        &parking,
+        excess_velocity,
+        departure_ut - parking.period() / 2.0,
+    )?;
+    println!("Ejection: {ejection:?}");
+
+    let (prograde, normal, radial) = ejection.burn;
+    let control = ship.get_control().mk_call(client)?;
+    let node = control
+        .add_node(ejection.ut, prograde as f32, normal as f32, radial as f32)
+        .mk_call(client)?;
+
+    let node_orbit = node.get_orbit().mk_call(client)?;
+    if node_orbit
+        .get_time_to_soi_change()
+        .mk_call(client)?
+        .is_nan()
+    {
+        return Err(error::Error::Numerical(
+            "Ejection node does not escape the parking body".to_string(),
+        )
+        .into());
+    }
+    let escape = node_orbit.get_next_orbit().mk_call(client)?;
+    let escape = OrbitalElements::snapshot(client, &escape)?;
+    let (arrival, _) = escape.state_at(arrival_ut);
+    let (target, _) = destination.orbit.state_at(arrival_ut);
+    let miss = arrival.sub(target).mag();
+    println!("Miss distance: {miss}");
+    Ok((node, miss))
+}
+
+/// Find the burn on the parking orbit, at or after `after`, giving the requested excess velocity
+/// The excess velocity is expressed in the same axes as the parking orbit's state vectors
+pub fn plan(
+    parking: &OrbitalElements,
+    excess_velocity: Vec3D,
+    after: f64,
+) -> Result<Ejection, Box<dyn Error>> {
+    if parking.is_hyperbolic() {
+        return Err(error::Error::Numerical("Parking orbit must be closed".to_string()).into());
+    }
+    let mut x = initial_guess(parking, excess_velocity, after);
+    for _ in 0..EJECTION_MAX_ITER {
+        let f = residual(parking, x, excess_velocity)?;
+        if f.mag() < EJECTION_TOLERANCE {
+            return Ok(Ejection {
+                ut: x.0,
+                burn: (x.1, x.2, 0.0),
+                excess_velocity: f.add(excess_velocity),
+            });
+        }
+        // Newton step with a finite difference jacobian over (ut, prograde, normal)
+        let d_ut = residual(parking, (x.0 + 1.0, x.1, x.2), excess_velocity)?.sub(f);
+        let d_pro = residual(parking, (x.0, x.1 + 0.1, x.2), excess_velocity)?
+            .sub(f)
+            .scale(10.0);
+        let d_nor = residual(parking, (x.0, x.1, x.2 + 0.1), excess_velocity)?
+            .sub(f)
+            .scale(10.0);
+        let det = d_ut.dot(d_pro.cross(d_nor));
+        if det.abs() < 1e-12 {
+            break;
+        }
+        let f = f.neg();
+        let step = (
+            f.dot(d_pro.cross(d_nor)) / det,
+            d_ut.dot(f.cross(d_nor)) / det,
+            d_ut.dot(d_pro.cross(f)) / det,
+        );
+        x = x.add(step);
+    }
+    Err(error::Error::Numerical("Ejection burn did not converge".to_string()).into())
+}
+
+/// Tangential burn at the point where a hyperbola leaving along the excess velocity would start
+fn initial_guess(parking: &OrbitalElements, excess_velocity: Vec3D, after: f64) -> Vec3D {
+    let (position, velocity) = parking.state_at(after);
+    let normal = position.cross(velocity).normalize();
+    let in_plane = excess_velocity.reject(normal).normalize();
+
+    let radius = parking.semi_major_axis;
+    let v_inf = excess_velocity.mag();
+    let eccentricity = 1.0 + radius * v_inf.powi(2) / parking.mu;
+    let asymptote = (-1.0 / eccentricity).acos();
+    let periapsis = Quat::from_axis_angle(normal, -asymptote).rotate(in_plane);
+
+    let here = position.normalize();
+    let ahead = here.cross(periapsis).dot(normal).atan2(here.dot(periapsis));
+    let true_anomaly = (parking.true_anomaly_at_ut(after) + ahead).rem_euclid(TAU);
+    let ut = parking.ut_at_true_anomaly(true_anomaly, after);
+
+    let (position, velocity) = parking.state_at(ut);
+    let speed = (v_inf.powi(2) + 2.0 * parking.mu / position.mag()).sqrt();
+    (ut, speed - velocity.mag(), 0.0)
+}
+
+/// Difference between the excess velocity reached by burning (ut, prograde, normal) and the target
+fn residual(
+    parking: &OrbitalElements,
+    (ut, prograde, normal): Vec3D,
+    excess_velocity: Vec3D,
+) -> Result<Vec3D, Box<dyn Error>> {
+    let (position, velocity) = parking.state_at(ut);
+    let burn = velocity
+        .normalize()
+        .scale(prograde)
+        .add(position.cross(velocity).normalize().scale(normal));
+    let hyperbola = OrbitalElements::from_state(parking.mu, position, velocity.add(burn), ut);
+    let reached = hyperbola.excess_velocity().ok_or_else(|| {
+        error::Error::Numerical("Ejection burn does not reach escape velocity".to_string())
+    })?;
+    Ok(reached.sub(excess_velocity))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ejection::plan, orbit::OrbitalElements, vector::Vector};
+
+    #[test]
+    fn test_ejection_matches_excess_velocity() {
+        let parking = OrbitalElements {
+            semi_major_axis: 700_000.0,
+            eccentricity: 0.001,
+            inclination: 0.05,
+            lan: 1.0,
+            aop: 0.5,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        };
+        for excess_velocity in [(900.0, 300.0, 50.0), (-200.0, -1000.0, -150.0)] {
+            let ejection = plan(&parking, excess_velocity, 1000.0).unwrap();
+            assert!(ejection.ut >= 1000.0 - 1.0);
+            assert!(ejection.excess_velocity.sub(excess_velocity).mag() < 0.1);
+            assert!(ejection.burn.0 > 900.0 && ejection.burn.0 < 1300.0);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::{error, fmt};
+
+use krpc_mars::{client::CallHandle, codec::RPCExtractable, krpc, RPCClient};
+
+/// Why a mission step failed, so a supervisor can tell what to do about it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Connection to the server was lost or garbled
+    Connection(String),
+    /// The server raised an exception running a procedure
+    /// Batched calls and streams only name the service
+    Server {
+        procedure: String,
+        description: String,
+    },
+    Missing(Missing),
+    /// A computation gave no usable answer, such as a NaN delta-v or a solver not converging
+    Numerical(String),
+    /// The vessel cannot fly what the step needs, such as running out of thrust or delta-v
+    Performance(String),
+    /// The user triggered the abort action group
+    Aborted,
+    /// Anything else, such as a vessel without a LiDAR
+    Other(String),
+}
+
+/// Object a mission step needs but the game does not have
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Missing {
+    Vessel,
+    Target,
+    Node,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Connection(err) => write!(f, "Connection failed: {err}"),
+            Error::Server {
+                procedure,
+                description,
+            } => write!(f, "{procedure} failed: {description}"),
+            Error::Missing(missing) => write!(f, "No {missing:?} found"),
+            Error::Numerical(err) => write!(f, "Numerical failure: {err}"),
+            Error::Performance(err) => write!(f, "{err}"),
+            Error::Aborted => write!(f, "Aborted"),
+            Error::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl Error {
+    fn server(err: &krpc::Error, procedure: String) -> Self {
+        Error::Server {
+            procedure,
+            description: format!("{}: {}", err.get_name(), err.get_description()),
+        }
+    }
+}
+
+impl From<krpc_mars::error::Error> for Error {
+    fn from(err: krpc_mars::error::Error) -> Self {
+        match err {
+            krpc_mars::error::Error::Procedure(err) => {
+                Error::server(&err, err.get_service().to_string())
+            }
+            err => Error::Connection(err.to_string()),
+        }
+    }
+}
+
+/// Errors of the modules still returning boxed errors keep their kind when they are ours or kRPC's
+impl From<Box<dyn error::Error>> for Error {
+    fn from(err: Box<dyn error::Error>) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        match err.downcast::<krpc_mars::error::Error>() {
+            Ok(err) => (*err).into(),
+            Err(err) => Error::Other(err.to_string()),
+        }
+    }
+}
+
+/// Run a call, naming its procedure when the server raises an exception
+pub trait Call<T> {
+    fn call(&self, client: &mut RPCClient) -> Result<T, Error>;
+}
+
+impl<T: RPCExtractable> Call<T> for CallHandle<T> {
+    fn call(&self, client: &mut RPCClient) -> Result<T, Error> {
+        self.mk_call(client).map_err(|err| match err {
+            krpc_mars::error::Error::Procedure(err) => {
+                let call = self.get_call();
+                Error::server(
+                    &err,
+                    format!("{}.{}", call.get_service(), call.get_procedure()),
+                )
+            }
+            err => err.into(),
+        })
+    }
+}
+
+/// Fail with a numerical error unless the value is finite
+pub fn finite(value: f64, what: &str) -> Result<f64, Error> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(Error::Numerical(format!("{what} is {value}")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error;
+
+    use crate::error::{finite, Error, Missing};
+
+    #[test]
+    fn test_boxed_errors_keep_their_kind() {
+        let boxed: Box<dyn error::Error> = Box::new(Error::Missing(Missing::Node));
+        assert_eq!(Error::from(boxed), Error::Missing(Missing::Node));
+        let boxed: Box<dyn error::Error> = "No LiDAR on the ship".into();
+        assert_eq!(
+            Error::from(boxed),
+            Error::Other("No LiDAR on the ship".to_string())
+        );
+        assert_eq!(finite(1.0, "Delta-v"), Ok(1.0));
+        assert!(matches!(
+            finite(f64::NAN, "Delta-v"),
+            Err(Error::Numerical(_))
+        ));
+    }
+}
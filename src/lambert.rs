@@ -0,0 +1,422 @@
+use std::{error::Error, f64::consts::PI};
+
+use krpc_mars::RPCClient;
+
+use crate::{
+    error,
+    orbit::OrbitalElements,
+    services::space_center::{Node, Orbit, Vessel},
+    vector::{Vec3D, Vector},
+};
+
+const LAMBERT_TOLERANCE: f64 = 1e-11;
+const LAMBERT_MAX_ITER: usize = 35;
+
+/// Transfer orbit between the ship and a target, ready to become a maneuver node
+#[derive(Debug, Copy, Clone)]
+pub struct Transfer {
+    pub departure_ut: f64,
+    pub arrival_ut: f64,
+    pub departure_velocity: Vec3D,
+    pub arrival_velocity: Vec3D,
+    /// Burn at departure as (prograde, normal, radial)
+    pub departure_burn: Vec3D,
+    /// Velocity relative to the target on arrival
+    pub arrival_excess: Vec3D,
+}
+
+impl Transfer {
+    pub fn departure_delta_v(&self) -> f64 {
+        self.departure_burn.mag()
+    }
+
+    pub fn arrival_delta_v(&self) -> f64 {
+        self.arrival_excess.mag()
+    }
+}
+
+/// Plan a transfer from the ship's current orbit to the target orbit
+/// Both orbits must share the same primary body
+/// With revolutions > 0 both multi-revolution branches are tried and the cheaper is kept
+pub fn transfer(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    target: &Orbit,
+    departure_ut: f64,
+    arrival_ut: f64,
+    revolutions: u32,
+) -> Result<Transfer, Box<dyn Error>> {
+    let ship_orbit = ship.get_orbit().mk_call(client)?;
+    let ship_orbit = OrbitalElements::snapshot(client, &ship_orbit)?;
+    let target_orbit = OrbitalElements::snapshot(client, target)?;
+    plan(
+        &ship_orbit,
+        &target_orbit,
+        departure_ut,
+        arrival_ut,
+        revolutions,
+    )
+}
+
+/// Offline part of `transfer`, working only on snapshotted orbits
+pub fn plan(
+    ship_orbit: &OrbitalElements,
+    target_orbit: &OrbitalElements,
+    departure_ut: f64,
+    arrival_ut: f64,
+    revolutions: u32,
+) -> Result<Transfer, Box<dyn Error>> {
+    let (r1, v1) = ship_orbit.state_at(departure_ut);
+    let (r2, v2) = target_orbit.state_at(arrival_ut);
+    let normal = r1.cross(v1);
+    let tof = arrival_ut - departure_ut;
+    let branches: &[bool] = if revolutions == 0 {
+        &[true]
+    } else {
+        &[true, false]
+    };
+    branches
+        .iter()
+        .filter_map(|&low_path| {
+            solve(ship_orbit.mu, r1, r2, tof, revolutions, normal, low_path).ok()
+        })
+        .map(|(departure_velocity, arrival_velocity)| Transfer {
+            departure_ut,
+            arrival_ut,
+            departure_velocity,
+            arrival_velocity,
+            departure_burn: ship_orbit.node_components(departure_ut, departure_velocity.sub(v1)),
+            arrival_excess: arrival_velocity.sub(v2),
+        })
+        .min_by(|a, b| {
+            (a.departure_delta_v() + a.arrival_delta_v())
+                .total_cmp(&(b.departure_delta_v() + b.arrival_delta_v()))
+        })
+        .ok_or_else(|| {
+            error::Error::Numerical("No feasible transfer for the given time of flight".to_string())
+                .into()
+        })
+}
+
+/// Add the departure burn of the transfer as a maneuver node
+pub fn node(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    transfer: &Transfer,
+) -> Result<Node, Box<dyn Error>> {
+    let (prograde, normal, radial) = transfer.departure_burn;
+    let control = ship.get_control().mk_call(client)?;
+    let node = control
+        .add_node(
+            transfer.departure_ut,
+            prograde as f32,
+            normal as f32,
+            radial as f32,
+        )
+        .mk_call(client)?;
+    Ok(node)
+}
+
+/// Solve Lambert's problem with Izzo's method
+/// Returns the velocities at r1 and r2 of the orbit joining them in `tof` seconds
+/// `normal` is the angular momentum of the departure orbit, the transfer circles the same way
+/// around the z axis, and lies in that orbit's plane when r1 and r2 are collinear
+/// `low_path` picks between the two solutions that exist when revolutions > 0
+pub fn solve(
+    mu: f64,
+    r1: Vec3D,
+    r2: Vec3D,
+    tof: f64,
+    revolutions: u32,
+    normal: Vec3D,
+    low_path: bool,
+) -> Result<(Vec3D, Vec3D), Box<dyn Error>> {
+    if tof <= 0.0 {
+        return Err(error::Error::Numerical("Time of flight must be positive".to_string()).into());
+    }
+    let chord = r2.sub(r1).mag();
+    let r1_norm = r1.mag();
+    let r2_norm = r2.mag();
+    let s = (r1_norm + r2_norm + chord) / 2.0;
+    let i_r1 = r1.normalize();
+    let i_r2 = r2.normalize();
+    // A half turn leaves the plane to the departure orbit
+    let i_h = match i_r1.cross(i_r2) {
+        i_h if i_h.mag() >= 1e-12 => i_h.normalize(),
+        _ if normal.mag() > 0.0 => normal.normalize(),
+        _ => {
+            return Err(error::Error::Numerical(
+                "Transfer plane is undefined for collinear positions".to_string(),
+            )
+            .into())
+        }
+    };
+    let prograde = normal.2 >= 0.0;
+
+    let mut ll = (1.0 - (chord / s).min(1.0)).sqrt();
+    let (mut i_t1, mut i_t2) = if i_h.2 < 0.0 {
+        ll = -ll;
+        (i_r1.cross(i_h), i_r2.cross(i_h))
+    } else {
+        (i_h.cross(i_r1), i_h.cross(i_r2))
+    };
+    if !prograde {
+        ll = -ll;
+        i_t1 = i_t1.neg();
+        i_t2 = i_t2.neg();
+    }
+
+    let t = (2.0 * mu / s.powi(3)).sqrt() * tof;
+    let x = find_x(ll, t, revolutions, low_path)?;
+    let y = compute_y(x, ll);
+
+    let gamma = (mu * s / 2.0).sqrt();
+    let rho = (r1_norm - r2_norm) / chord;
+    let sigma = (1.0 - rho.powi(2)).sqrt();
+    let v_r1 = gamma * ((ll * y - x) - rho * (ll * y + x)) / r1_norm;
+    let v_r2 = -gamma * ((ll * y - x) + rho * (ll * y + x)) / r2_norm;
+    let v_t1 = gamma * sigma * (y + ll * x) / r1_norm;
+    let v_t2 = gamma * sigma * (y + ll * x) / r2_norm;
+
+    let v1 = i_r1.scale(v_r1).add(i_t1.scale(v_t1));
+    let v2 = i_r2.scale(v_r2).add(i_t2.scale(v_t2));
+    Ok((v1, v2))
+}
+
+fn find_x(ll: f64, t: f64, revolutions: u32, low_path: bool) -> Result<f64, Box<dyn Error>> {
+    let m = revolutions as f64;
+    let mut m_max = (t / PI).floor();
+    let t_00 = ll.acos() + ll * (1.0 - ll.powi(2)).sqrt();
+    if t < t_00 + m_max * PI && m_max > 0.0 {
+        let t_min = compute_t_min(ll, m_max)?;
+        if t < t_min {
+            m_max -= 1.0;
+        }
+    }
+    if m > m_max {
+        return Err(error::Error::Numerical(format!(
+            "No transfer with {revolutions} revolutions for this time of flight"
+        ))
+        .into());
+    }
+    let x0 = initial_guess(t, ll, m, low_path);
+    householder(x0, t, ll, m)
+}
+
+fn compute_y(x: f64, ll: f64) -> f64 {
+    (1.0 - ll.powi(2) * (1.0 - x.powi(2))).sqrt()
+}
+
+fn compute_psi(x: f64, y: f64, ll: f64) -> f64 {
+    if (-1.0..1.0).contains(&x) {
+        (x * y + ll * (1.0 - x.powi(2))).clamp(-1.0, 1.0).acos()
+    } else if x > 1.0 {
+        ((y - x * ll) * (x.powi(2) - 1.0).sqrt()).asinh()
+    } else {
+        0.0
+    }
+}
+
+/// Non-dimensional time of flight for the given x
+fn tof_equation(x: f64, y: f64, ll: f64, m: f64) -> f64 {
+    if m == 0.0 && 0.6_f64.sqrt() < x && x < 1.4_f64.sqrt() {
+        let eta = y - ll * x;
+        let s1 = (1.0 - ll - x * eta) / 2.0;
+        let q = 4.0 / 3.0 * hyp2f1b(s1);
+        (eta.powi(3) * q + 4.0 * ll * eta) / 2.0
+    } else {
+        let psi = compute_psi(x, y, ll);
+        ((psi + m * PI) / (1.0 - x.powi(2)).abs().sqrt() - x + ll * y) / (1.0 - x.powi(2))
+    }
+}
+
+/// First three derivatives of the time of flight with respect to x
+fn tof_derivatives(x: f64, y: f64, t: f64, ll: f64) -> (f64, f64, f64) {
+    let d1 = (3.0 * t * x - 2.0 + 2.0 * ll.powi(3) * x / y) / (1.0 - x.powi(2));
+    let d2 = (3.0 * t + 5.0 * x * d1 + 2.0 * (1.0 - ll.powi(2)) * ll.powi(3) / y.powi(3))
+        / (1.0 - x.powi(2));
+    let d3 = (7.0 * x * d2 + 8.0 * d1 - 6.0 * (1.0 - ll.powi(2)) * ll.powi(5) * x / y.powi(5))
+        / (1.0 - x.powi(2));
+    (d1, d2, d3)
+}
+
+/// Gauss hypergeometric function 2F1(3, 1, 5/2, x)
+fn hyp2f1b(x: f64) -> f64 {
+    if x >= 1.0 {
+        return f64::INFINITY;
+    }
+    let mut res = 1.0;
+    let mut term = 1.0;
+    let mut i = 0.0;
+    loop {
+        term = term * (3.0 + i) * (1.0 + i) / (2.5 + i) * x / (i + 1.0);
+        let prev = res;
+        res += term;
+        if prev == res {
+            return res;
+        }
+        i += 1.0;
+    }
+}
+
+/// Minimum time of flight achievable with m revolutions, found with Halley's method
+fn compute_t_min(ll: f64, m: f64) -> Result<f64, Box<dyn Error>> {
+    if ll == 1.0 {
+        return Ok(tof_equation(0.0, compute_y(0.0, ll), ll, m));
+    }
+    let mut x = 0.1;
+    let mut t = tof_equation(x, compute_y(x, ll), ll, m);
+    for _ in 0..LAMBERT_MAX_ITER {
+        let (d1, d2, d3) = tof_derivatives(x, compute_y(x, ll), t, ll);
+        if d2 == 0.0 {
+            break;
+        }
+        let next = x - 2.0 * d1 * d2 / (2.0 * d2.powi(2) - d1 * d3);
+        let converged = (next - x).abs() < LAMBERT_TOLERANCE;
+        x = next;
+        t = tof_equation(x, compute_y(x, ll), ll, m);
+        if converged {
+            return Ok(t);
+        }
+    }
+    Err(
+        error::Error::Numerical("Lambert minimum time of flight did not converge".to_string())
+            .into(),
+    )
+}
+
+fn initial_guess(t: f64, ll: f64, m: f64, low_path: bool) -> f64 {
+    if m == 0.0 {
+        let t_0 = ll.acos() + ll * (1.0 - ll.powi(2)).sqrt();
+        let t_1 = 2.0 * (1.0 - ll.powi(3)) / 3.0;
+        if t >= t_0 {
+            (t_0 / t).powf(2.0 / 3.0) - 1.0
+        } else if t < t_1 {
+            5.0 / 2.0 * t_1 / t * (t_1 - t) / (1.0 - ll.powi(5)) + 1.0
+        } else {
+            (t_0 / t).powf((t_1 / t_0).log2()) - 1.0
+        }
+    } else {
+        let left = ((m * PI + PI) / (8.0 * t)).powf(2.0 / 3.0);
+        let right = ((8.0 * t) / (m * PI)).powf(2.0 / 3.0);
+        let x_left = (left - 1.0) / (left + 1.0);
+        let x_right = (right - 1.0) / (right + 1.0);
+        if low_path {
+            x_left.max(x_right)
+        } else {
+            x_left.min(x_right)
+        }
+    }
+}
+
+/// Solve the time of flight equation with Householder's third order method
+fn householder(x0: f64, t0: f64, ll: f64, m: f64) -> Result<f64, Box<dyn Error>> {
+    let mut x = x0;
+    for _ in 0..LAMBERT_MAX_ITER {
+        let y = compute_y(x, ll);
+        let t = tof_equation(x, y, ll, m);
+        let f = t - t0;
+        let (d1, d2, d3) = tof_derivatives(x, y, t, ll);
+        let next = x - f
+            * ((d1.powi(2) - f * d2 / 2.0) / (d1 * (d1.powi(2) - f * d2) + d3 * f.powi(2) / 6.0));
+        if !next.is_finite() {
+            break;
+        }
+        if (next - x).abs() < LAMBERT_TOLERANCE {
+            return Ok(next);
+        }
+        x = next;
+    }
+    Err(error::Error::Numerical("Lambert solver did not converge".to_string()).into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use crate::{
+        lambert::{plan, solve},
+        orbit::OrbitalElements,
+        vector::{Vec3D, Vector},
+    };
+
+    const KERBIN_MU: f64 = 3.5316e12;
+    /// Angular momentum of a counter-clockwise departure orbit
+    const PROGRADE: Vec3D = (0.0, 0.0, 1.0);
+
+    fn circular(radius: f64, mean_anomaly_at_epoch: f64) -> OrbitalElements {
+        OrbitalElements {
+            semi_major_axis: radius,
+            eccentricity: 0.0,
+            inclination: 0.0,
+            lan: 0.0,
+            aop: 0.0,
+            mean_anomaly_at_epoch,
+            epoch: 0.0,
+            mu: KERBIN_MU,
+        }
+    }
+
+    fn reaches(r1: (f64, f64, f64), v1: (f64, f64, f64), r2: (f64, f64, f64), tof: f64) -> bool {
+        let transfer = OrbitalElements::from_state(KERBIN_MU, r1, v1, 0.0);
+        let (arrival, _) = transfer.state_at(tof);
+        arrival.sub(r2).mag() < 1e-6 * r2.mag()
+    }
+
+    #[test]
+    fn test_hohmann() {
+        // Half an ellipse between circular orbits is a Hohmann transfer
+        let (r1, r2): (f64, f64) = (700_000.0, 2_000_000.0);
+        let a = (r1 + r2) / 2.0;
+        let tof = PI * (a.powi(3) / KERBIN_MU).sqrt();
+        let (start, end) = ((r1, 0.0, 0.0), (-r2, 0.0, 0.0));
+        let (v1, _) = solve(KERBIN_MU, start, end, tof, 0, PROGRADE, true).unwrap();
+        let expected = (KERBIN_MU * (2.0 / r1 - 1.0 / a)).sqrt();
+        assert!((v1.1 - expected).abs() < 1e-3);
+        assert!(v1.0.abs() < 1e-3 && v1.2.abs() < 1e-3);
+        // The plane of a half turn comes from the departure orbit alone
+        let (v1, _) = solve(KERBIN_MU, start, end, tof, 0, (0.0, -1.0, 1.0), true).unwrap();
+        assert!((v1.1 - v1.2).abs() < 1e-3 && (v1.1 - expected / 2f64.sqrt()).abs() < 1e-3);
+        assert!(solve(KERBIN_MU, start, end, tof, 0, (0.0, 0.0, 0.0), true).is_err());
+    }
+
+    #[test]
+    fn test_reaches_target() {
+        let r1 = (700_000.0, 100_000.0, 50_000.0);
+        let r2 = (-300_000.0, 1_500_000.0, -200_000.0);
+        for tof in [600.0, 2000.0, 10_000.0] {
+            let (v1, _) = solve(KERBIN_MU, r1, r2, tof, 0, PROGRADE, true).unwrap();
+            assert!(reaches(r1, v1, r2, tof));
+            let (v1, _) = solve(KERBIN_MU, r1, r2, tof, 0, PROGRADE.neg(), true).unwrap();
+            assert!(reaches(r1, v1, r2, tof));
+        }
+    }
+
+    #[test]
+    fn test_multi_revolution() {
+        let r1 = (700_000.0, 0.0, 0.0);
+        let r2 = (0.0, 900_000.0, 10_000.0);
+        let tof = 30_000.0;
+        for low_path in [true, false] {
+            let (v1, _) = solve(KERBIN_MU, r1, r2, tof, 2, PROGRADE, low_path).unwrap();
+            assert!(reaches(r1, v1, r2, tof));
+        }
+        assert!(solve(KERBIN_MU, r1, r2, 3000.0, 2, PROGRADE, true).is_err());
+    }
+
+    #[test]
+    fn test_plan_matches_target() {
+        let ship = circular(700_000.0, 0.0);
+        let target = circular(1_000_000.0, 1.0);
+        let transfer = plan(&ship, &target, 100.0, 2100.0, 0).unwrap();
+        let (position, velocity) = ship.state_at(100.0);
+        let burn = transfer.departure_velocity.sub(velocity);
+        assert!((transfer.departure_delta_v() - burn.mag()).abs() < 1e-6);
+        assert!(reaches(
+            position,
+            transfer.departure_velocity,
+            target.state_at(2100.0).0,
+            2000.0
+        ));
+    }
+}
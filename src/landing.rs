@@ -0,0 +1,533 @@
+use std::{error::Error, f64::consts::PI};
+
+use krpc_mars::{batch_call_unwrap, stream::StreamHandle, RPCClient, StreamClient};
+
+use crate::{
+    apsis::{change_at, Burn},
+    maneuver::{execute, BurnOptions, BurnStatus},
+    minimize::minimize,
+    orbit::OrbitalElements,
+    services::space_center::{self, ReferenceFrame, Vessel, VesselSituation},
+    vector::{Vec3D, Vector},
+    window::Site,
+};
+
+/// Samples per orbit when searching for the deorbit point
+const DEORBIT_STEPS: usize = 360;
+/// Golden section iterations refining the deorbit point
+const DEORBIT_REFINE_ITER: usize = 40;
+
+#[derive(Debug, Copy, Clone)]
+pub struct LandingOptions {
+    /// Periapsis height above the target's terrain after the deorbit burn
+    pub periapsis: f64,
+    /// Orbits searched for the deorbit point
+    pub orbits: u32,
+    /// Seconds before the braking burn at which time warp stops
+    pub warp_lead: f64,
+    /// Horizontal surface speed at which braking ends and the ship falls towards the target
+    pub brake_speed: f64,
+    /// Factor on the stopping height at which the suicide burn starts, keeping some thrust in hand
+    pub margin: f64,
+    /// Height of the centre of mass above the terrain from which the ship descends at the touchdown speed
+    pub final_altitude: f64,
+    pub touchdown_speed: f64,
+    /// Height above the terrain at which the landing legs deploy
+    pub legs_altitude: f64,
+    /// Acceleration per m/s of velocity error while descending to touchdown
+    pub gain: f64,
+}
+
+impl Default for LandingOptions {
+    fn default() -> Self {
+        Self {
+            periapsis: 10000.0,
+            orbits: 5,
+            warp_lead: 60.0,
+            brake_speed: 5.0,
+            margin: 1.15,
+            final_altitude: 20.0,
+            touchdown_speed: 1.5,
+            legs_altitude: 500.0,
+            gain: 1.0,
+        }
+    }
+}
+
+/// Deorbit burn bringing the ship down on a target
+#[derive(Debug, Copy, Clone)]
+pub struct Deorbit {
+    pub burn: Burn,
+    /// Moment the ship reaches the target, for a powered landing as it passes the periapsis
+    pub arrival: f64,
+    /// Angle in radians by which the ship misses the target at arrival, large when the orbit never passes it
+    pub miss: f64,
+}
+
+/// Vertical situation of the descent over the terrain, speeds relative to the surface
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Descent {
+    /// Height of the centre of mass above the terrain
+    pub altitude: f64,
+    /// Positive upwards
+    pub vertical_speed: f64,
+    pub speed: f64,
+    /// Acceleration at full throttle
+    pub max_acceleration: f64,
+    pub gravity: f64,
+}
+
+impl Descent {
+    /// Height lost braking to a stop at full throttle pointed retrograde, None if the ship can't stop falling
+    pub fn stopping_height(&self) -> Option<f64> {
+        if self.speed <= 0.0 {
+            return Some(0.0);
+        }
+        // Sine of the flight path angle below the horizon
+        let sin = (-self.vertical_speed / self.speed).max(0.0);
+        let deceleration = self.max_acceleration - self.gravity * sin;
+        if deceleration <= 0.0 {
+            return None;
+        }
+        Some(self.speed.powi(2) * sin / (2.0 * deceleration))
+    }
+
+    /// Throttle braking to the touchdown speed at the final altitude, then holding that speed to the ground
+    pub fn throttle(&self, options: &LandingOptions) -> f64 {
+        if self.max_acceleration <= 0.0 {
+            return 0.0;
+        }
+        let acceleration = if self.altitude <= options.final_altitude {
+            self.gravity + options.gain * (-options.touchdown_speed - self.vertical_speed)
+        } else {
+            let sin = -self.vertical_speed / self.speed.max(f64::EPSILON);
+            if sin <= 0.0 {
+                return 0.0;
+            }
+            let height = self.altitude - options.final_altitude;
+            (self.speed.powi(2) - options.touchdown_speed.powi(2)).max(0.0) * sin / (2.0 * height)
+                + self.gravity * sin
+        };
+        (acceleration / self.max_acceleration).clamp(0.0, 1.0)
+    }
+}
+
+/// Deorbit to the latitude and longitude in degrees, then brake and land on the terrain there
+/// Only for airless bodies, and the orbit has to pass over the target, see `plane`
+pub fn land(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    latitude: f64,
+    longitude: f64,
+    options: &LandingOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (orbit, ut) = batch_call_unwrap!(client, (&ship.get_orbit(), &space_center::get_ut()))?;
+    let site = Site::surface(client, ship, latitude, longitude)?;
+    let elements = OrbitalElements::snapshot(client, &orbit)?;
+    let plan = deorbit(
+        &elements,
+        &site,
+        site.radius + options.periapsis,
+        ut,
+        options.orbits,
+    )?;
+    println!(
+        "Deorbit at {:.0}, over the target at {:.0}, missing by {:.0} m",
+        plan.burn.ut,
+        plan.arrival,
+        plan.miss * site.radius
+    );
+    plan.burn.add(client, ship)?;
+    let result = execute(client, stream_client, ship, &BurnOptions::default())?;
+    if result.status != BurnStatus::Complete {
+        return Err(format!("Deorbit burn failed: {:?}", result.status).into());
+    }
+    let warp = plan.arrival - options.warp_lead;
+    if warp > space_center::get_ut().mk_call(client)? {
+        space_center::warp_to(warp, 100000.0, 2.0).mk_call(client)?;
+    }
+    descend(client, stream_client, ship, plan.arrival, options)
+}
+
+/// Deorbit burn at which the new periapsis, opposite the burn, passes closest to the site
+pub fn deorbit(
+    orbit: &OrbitalElements,
+    site: &Site,
+    periapsis: f64,
+    after: f64,
+    orbits: u32,
+) -> Result<Deorbit, Box<dyn Error>> {
+    if orbit.is_hyperbolic() {
+        return Err("Orbit must be closed".into());
+    }
+    let arrival = |ut: f64| {
+        let radius = orbit.state_at(ut).0.mag();
+        ut + PI * ((radius + periapsis) / 2.0).powi(3).sqrt() / orbit.mu.sqrt()
+    };
+    let miss = |ut: f64| {
+        let position = orbit.state_at(ut).0;
+        position.neg().vang(site.position_at(arrival(ut)))
+    };
+    let ut = minimize(
+        miss,
+        after,
+        after + orbit.period() * orbits.max(1) as f64,
+        DEORBIT_STEPS * orbits.max(1) as usize,
+        DEORBIT_REFINE_ITER,
+    );
+    let radius = orbit.state_at(ut).0.mag();
+    Ok(Deorbit {
+        burn: change_at(orbit, ut, periapsis, radius)?,
+        arrival: arrival(ut),
+        miss: miss(ut),
+    })
+}
+
+/// Brake centred on `arrival`, fall, then suicide burn down to the terrain
+/// Legs deploy on the way down and the last metres are flown at the touchdown speed
+pub fn descend(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    arrival: f64,
+    options: &LandingOptions,
+) -> Result<(), Box<dyn Error>> {
+    let body = ship
+        .get_orbit()
+        .mk_call(client)?
+        .get_body()
+        .mk_call(client)?;
+    let (has_atmosphere, gravity, rf) = batch_call_unwrap!(
+        client,
+        (
+            &body.get_has_atmosphere(),
+            &body.get_surface_gravity(),
+            &body.get_reference_frame(),
+        )
+    )?;
+    if has_atmosphere {
+        return Err("Powered landing needs an airless body".into());
+    }
+    let (control, auto_pilot, parts) = batch_call_unwrap!(
+        client,
+        (
+            &ship.get_control(),
+            &ship.get_auto_pilot(),
+            &ship.get_parts(),
+        )
+    )?;
+    control.set_throttle(0.0).mk_call(client)?;
+    auto_pilot.set_reference_frame(rf).mk_call(client)?;
+    auto_pilot.engage().mk_call(client)?;
+
+    let streamer = Streamer::init(client, ship, rf)?;
+    let mut telemetry = Telemetry::default();
+    let mut state = State::Coast;
+    let mut prev_state = state;
+    let mut legs_deployed = false;
+    let mut touchdown_speed = 0.0;
+    loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        if state != prev_state {
+            println!("{prev_state:?}->{state:?}");
+            prev_state = state;
+        }
+        let descent = Descent {
+            altitude: telemetry.altitude,
+            vertical_speed: telemetry.vertical_speed,
+            speed: telemetry.speed,
+            max_acceleration: telemetry.thrust as f64 / telemetry.mass as f64,
+            gravity,
+        };
+        let burn_now = descent
+            .stopping_height()
+            .is_none_or(|h| h * options.margin >= descent.altitude - options.final_altitude);
+        let up = telemetry.position.normalize();
+        let retrograde = if telemetry.speed > options.touchdown_speed {
+            telemetry.velocity.neg()
+        } else {
+            up
+        };
+
+        if !legs_deployed && telemetry.altitude < options.legs_altitude {
+            // Fixed legs are always deployed and refuse the call
+            for leg in parts.get_legs().mk_call(client)? {
+                let _ = leg.set_deployed(true).mk_call(client);
+            }
+            legs_deployed = true;
+        }
+
+        let (next, throttle, direction) = match state {
+            State::Coast => {
+                let brake_time = telemetry.horizontal_speed / descent.max_acceleration;
+                let next = if burn_now {
+                    State::Burn
+                } else if telemetry.ut >= arrival - brake_time / 2.0 {
+                    State::Brake
+                } else {
+                    State::Coast
+                };
+                (next, 0.0, retrograde)
+            }
+            State::Brake => {
+                let next = if burn_now {
+                    State::Burn
+                } else if telemetry.horizontal_speed < options.brake_speed {
+                    State::Fall
+                } else {
+                    State::Brake
+                };
+                (next, 1.0, retrograde)
+            }
+            State::Fall => {
+                let next = if burn_now { State::Burn } else { State::Fall };
+                (next, 0.0, retrograde)
+            }
+            State::Burn => {
+                let next = if telemetry.altitude <= options.final_altitude {
+                    State::Touchdown
+                } else {
+                    State::Burn
+                };
+                (next, descent.throttle(options), retrograde)
+            }
+            State::Touchdown => {
+                touchdown_speed = telemetry.speed;
+                let next = if matches!(
+                    telemetry.situation,
+                    Some(VesselSituation::Landed | VesselSituation::Splashed)
+                ) {
+                    State::End
+                } else {
+                    State::Touchdown
+                };
+                // Tilt against the horizontal drift, holding up the ship's weight
+                let drift = telemetry.velocity.reject(up);
+                let direction = up.scale(gravity).sub(drift.scale(options.gain));
+                (next, descent.throttle(options), direction)
+            }
+            State::End => {
+                control.set_throttle(0.0).mk_call(client)?;
+                auto_pilot.disengage().mk_call(client)?;
+                control.set_sas(true).mk_call(client)?;
+                streamer.stop(client)?;
+                println!("Landed at {touchdown_speed:.2} m/s");
+                return Ok(());
+            }
+        };
+        batch_call_unwrap!(
+            client,
+            (
+                &control.set_throttle(throttle as f32),
+                &auto_pilot.set_target_direction(direction),
+            )
+        )?;
+        state = next;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    /// Wait for the braking burn, or for the suicide burn when already falling
+    Coast,
+    /// Cancel the horizontal speed at full throttle
+    Brake,
+    Fall,
+    /// Suicide burn down to the final altitude
+    Burn,
+    Touchdown,
+    End,
+}
+
+#[derive(Default)]
+pub struct Telemetry {
+    ut: f64,
+    altitude: f64,
+    vertical_speed: f64,
+    horizontal_speed: f64,
+    speed: f64,
+    thrust: f32,
+    mass: f32,
+    situation: Option<VesselSituation>,
+    /// Position and surface velocity in the body's reference frame
+    position: Vec3D,
+    velocity: Vec3D,
+}
+
+pub struct Streamer {
+    ut: StreamHandle<f64>,
+    altitude: StreamHandle<f64>,
+    vertical_speed: StreamHandle<f64>,
+    horizontal_speed: StreamHandle<f64>,
+    speed: StreamHandle<f64>,
+    thrust: StreamHandle<f32>,
+    mass: StreamHandle<f32>,
+    situation: StreamHandle<VesselSituation>,
+    position: StreamHandle<Vec3D>,
+    velocity: StreamHandle<Vec3D>,
+}
+
+impl Streamer {
+    pub fn init(
+        client: &mut RPCClient,
+        vessel: &Vessel,
+        rf: ReferenceFrame,
+    ) -> Result<Self, Box<dyn Error>> {
+        let flight = vessel.flight(rf).mk_call(client)?;
+        Ok(Self {
+            ut: space_center::get_ut().to_stream().mk_call(client)?,
+            altitude: flight.get_surface_altitude().to_stream().mk_call(client)?,
+            vertical_speed: flight.get_vertical_speed().to_stream().mk_call(client)?,
+            horizontal_speed: flight.get_horizontal_speed().to_stream().mk_call(client)?,
+            speed: flight.get_speed().to_stream().mk_call(client)?,
+            thrust: vessel.get_available_thrust().to_stream().mk_call(client)?,
+            mass: vessel.get_mass().to_stream().mk_call(client)?,
+            situation: vessel.get_situation().to_stream().mk_call(client)?,
+            position: vessel.position(rf).to_stream().mk_call(client)?,
+            velocity: vessel.velocity(rf).to_stream().mk_call(client)?,
+        })
+    }
+
+    pub fn update(
+        &self,
+        stream_client: &mut StreamClient,
+        telemetry: &mut Telemetry,
+    ) -> Result<(), Box<dyn Error>> {
+        let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.ut)? {
+            telemetry.ut = val;
+        }
+        if let Some(val) = update.get_result(&self.altitude)? {
+            telemetry.altitude = val;
+        }
+        if let Some(val) = update.get_result(&self.vertical_speed)? {
+            telemetry.vertical_speed = val;
+        }
+        if let Some(val) = update.get_result(&self.horizontal_speed)? {
+            telemetry.horizontal_speed = val;
+        }
+        if let Some(val) = update.get_result(&self.speed)? {
+            telemetry.speed = val;
+        }
+        if let Some(val) = update.get_result(&self.thrust)? {
+            telemetry.thrust = val;
+        }
+        if let Some(val) = update.get_result(&self.mass)? {
+            telemetry.mass = val;
+        }
+        if let Some(val) = update.get_result(&self.situation)? {
+            telemetry.situation = Some(val);
+        }
+        if let Some(val) = update.get_result(&self.position)? {
+            telemetry.position = val;
+        }
+        if let Some(val) = update.get_result(&self.velocity)? {
+            telemetry.velocity = val;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Box<dyn Error>> {
+        batch_call_unwrap!(
+            client,
+            (
+                &self.ut.remove(),
+                &self.altitude.remove(),
+                &self.vertical_speed.remove(),
+                &self.horizontal_speed.remove(),
+                &self.speed.remove(),
+            )
+        )?;
+        batch_call_unwrap!(
+            client,
+            (
+                &self.thrust.remove(),
+                &self.mass.remove(),
+                &self.situation.remove(),
+                &self.position.remove(),
+                &self.velocity.remove(),
+            )
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::TAU;
+
+    use crate::{
+        landing::{deorbit, Descent, LandingOptions},
+        orbit::OrbitalElements,
+        vector::Vector,
+        window::Site,
+    };
+
+    #[test]
+    fn test_deorbit_over_site() {
+        let orbit = OrbitalElements {
+            semi_major_axis: 250_000.0,
+            eccentricity: 0.0,
+            inclination: 0.0,
+            lan: 0.0,
+            aop: 0.0,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            mu: 6.5138e10,
+        };
+        let site = Site {
+            latitude: 0.0,
+            longitude: 1.0,
+            ut: 0.0,
+            radius: 201_000.0,
+            rotational_speed: TAU / 138_984.0,
+        };
+        let plan = deorbit(&orbit, &site, 211_000.0, 100.0, 2).unwrap();
+        assert!(plan.burn.ut >= 100.0);
+        assert!(plan.miss < 1e-6);
+        assert!(plan.burn.burn.0 < 0.0);
+
+        // Coast down the new orbit and meet the site at its periapsis
+        let (position, velocity) = orbit.state_at(plan.burn.ut);
+        let velocity = velocity.add(velocity.normalize().scale(plan.burn.burn.0));
+        let descent = OrbitalElements::from_state(orbit.mu, position, velocity, plan.burn.ut);
+        assert!((descent.periapsis() - 211_000.0).abs() < 1e-3);
+        let (position, _) = descent.state_at(plan.arrival);
+        assert!(position.vang(site.position_at(plan.arrival)) < 1e-6);
+    }
+
+    #[test]
+    fn test_suicide_burn_throttle() {
+        let options = LandingOptions::default();
+        let falling = Descent {
+            altitude: 2000.0,
+            vertical_speed: -100.0,
+            speed: 100.0,
+            max_acceleration: 6.0,
+            gravity: 1.63,
+        };
+        let height = falling.stopping_height().unwrap();
+        assert!((height - 100f64.powi(2) / (2.0 * (6.0 - 1.63))).abs() < 1e-9);
+        // Starting the burn right at the stopping height takes all the thrust
+        let start = Descent {
+            altitude: height + options.final_altitude,
+            ..falling
+        };
+        assert!(start.throttle(&options) > 0.99);
+        assert!(falling.throttle(&options) < 1.0);
+        // Near the ground at the touchdown speed the engine holds the weight
+        let hover = Descent {
+            altitude: 5.0,
+            vertical_speed: -options.touchdown_speed,
+            speed: options.touchdown_speed,
+            ..falling
+        };
+        assert!((hover.throttle(&options) - 1.63 / 6.0).abs() < 1e-9);
+        let weak = Descent {
+            max_acceleration: 1.0,
+            ..falling
+        };
+        assert!(weak.stopping_height().is_none());
+    }
+}
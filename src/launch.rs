@@ -2,30 +2,160 @@ use krpc_mars::batch_call_unwrap;
 use krpc_mars::stream::StreamHandle;
 use krpc_mars::RPCClient;
 use krpc_mars::StreamClient;
-use std::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
 
+use crate::azimuth;
+use crate::error::{Call, Error};
 use crate::interpolate::Interpolate;
-use crate::services::space_center::Vessel;
+use crate::peg::{Peg, PegState, PegTarget};
+use crate::services::space_center::{self, Orbit, Vessel};
+use crate::stage::G0;
+use crate::vector::{Vec3D, Vector};
 
+/// Seconds between closed-loop guidance solutions
+const GUIDANCE_PERIOD: f64 = 1.0;
+/// Time to go below which the last guidance solution is flown to cutoff
+const GUIDANCE_TERMINAL: f64 = 10.0;
+
+/// Shape of the gravity turn, altitudes in m above the surface and angles in degrees
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AscentProfile {
+    /// Apoapsis altitude at which the engines cut off
+    pub target_apoapsis: f64,
+    /// Periapsis altitude reached by closed-loop guidance, circular at the apoapsis when not set
+    pub target_periapsis: Option<f64>,
+    /// Altitude and surface speed that must both be reached before pitching over
+    pub vertical_altitude: f64,
+    pub turn_start_speed: f64,
+    /// Altitude at which the pitch curve starts, it may start below `vertical_altitude`
+    pub turn_start_altitude: f64,
+    /// Altitude at which the vessel is pitched flat
+    pub turn_end_altitude: f64,
+    /// Exponent of the pitch curve, 1 is linear and lower values pitch over sooner
+    pub turn_shape: f64,
+    /// Angle of attack allowed at the start and end of `aoa_limit_altitudes`
+    pub aoa_limits: (f32, f32),
+    pub aoa_limit_altitudes: (f64, f64),
+    /// Altitude above which the angle of attack is no longer limited
+    pub aoa_ceiling: f64,
+    /// Coast until this altitude, read from the launch body when not set
+    pub atmosphere_height: Option<f64>,
+    /// Altitude at which closed-loop guidance takes over the pitch curve, never when not set
+    /// Guidance only flies the pitch, the plane comes from the heading as in the gravity turn
+    pub guidance_altitude: Option<f64>,
+}
+
+impl Default for AscentProfile {
+    fn default() -> Self {
+        Self {
+            target_apoapsis: 100000.0,
+            target_periapsis: None,
+            vertical_altitude: 1000.0,
+            turn_start_speed: 0.0,
+            turn_start_altitude: 100.0,
+            turn_end_altitude: 32000.0,
+            turn_shape: 1.0,
+            aoa_limits: (5.0, 25.0),
+            aoa_limit_altitudes: (1000.0, 18000.0),
+            aoa_ceiling: 24000.0,
+            atmosphere_height: None,
+            guidance_altitude: None,
+        }
+    }
+}
+
+impl AscentProfile {
+    /// Read a profile from a `.toml` or `.json` file, missing fields keep their defaults
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(toml::from_str(&text)?),
+            Some("json") => Ok(serde_json::from_str(&text)?),
+            _ => Err(format!("Unknown profile format: {}", path.display()).into()),
+        }
+    }
+
+    /// Target pitch at the given altitude, 90 at the start of the turn down to 0 at its end
+    pub fn pitch(&self, alt: f64) -> f32 {
+        let progress = ((alt - self.turn_start_altitude)
+            / (self.turn_end_altitude - self.turn_start_altitude))
+            .clamp(0.0, 1.0);
+        (90.0 * (1.0 - progress.powf(self.turn_shape))) as f32
+    }
+
+    /// Largest angle of attack allowed at the given altitude, None above the ceiling
+    pub fn aoa_limit(&self, alt: f64) -> Option<f32> {
+        if alt >= self.aoa_ceiling {
+            return None;
+        }
+        let (low, high) = self.aoa_limits;
+        Some(Interpolate::new(self.aoa_limit_altitudes, (low as f64, high as f64)).inter(alt))
+    }
+
+    /// Open-loop pitch, following the curve without exceeding the angle of attack limit
+    fn turn_pitch(&self, attitude: &Attitude) -> f32 {
+        let tgt_pitch = self.pitch(attitude.alt);
+        match self.aoa_limit(attitude.alt) {
+            Some(limit) => tgt_pitch.max(attitude.pitch - attitude.aoa - limit),
+            None => tgt_pitch,
+        }
+    }
+}
+
+/// Fly the ascent, returning true when closed-loop guidance inserted the vessel into orbit
+/// Otherwise the vessel coasts out of the atmosphere and still needs a circularization burn
+/// `azimuth` is the compass heading in degrees to lift off on
+/// With a target orbit the heading is steered into its plane once the gravity turn starts
 pub fn launch(
     client: &mut RPCClient,
     stream_client: &mut StreamClient,
     ship: &Vessel,
-    inclination: f32,
-) -> Result<(), Box<dyn Error>> {
-    let control = ship.get_control().mk_call(client)?;
-    control.set_sas(false).mk_call(client)?;
-    control.set_lights(true).mk_call(client)?;
-    control.set_throttle(1.0).mk_call(client)?;
+    azimuth: f32,
+    target_orbit: Option<&Orbit>,
+    profile: &AscentProfile,
+) -> Result<bool, Error> {
+    let control = ship.get_control().call(client)?;
+    control.set_sas(false).call(client)?;
+    control.set_lights(true).call(client)?;
+    control.set_throttle(1.0).call(client)?;
+
+    let body = ship.get_orbit().call(client)?.get_body().call(client)?;
+    let (mu, body_radius, atmosphere_depth) = batch_call_unwrap!(
+        client,
+        (
+            &body.get_gravitational_parameter(),
+            &body.get_equatorial_radius(),
+            &body.get_atmosphere_depth(),
+        )
+    )?;
+    let atmosphere_height = profile.atmosphere_height.unwrap_or(atmosphere_depth);
+    let target = PegTarget::insertion(
+        mu,
+        body_radius + profile.target_periapsis.unwrap_or(profile.target_apoapsis),
+        body_radius + profile.target_apoapsis,
+    );
+    let plane = match target_orbit {
+        Some(orbit) => {
+            let rf = body.get_non_rotating_reference_frame().call(client)?;
+            Some(azimuth::plane_normal(client, orbit, rf)?)
+        }
+        None => None,
+    };
+    let orbit_speed = (mu / (body_radius + profile.target_apoapsis)).sqrt();
+    let mut guidance: Option<Peg> = None;
+    let mut solved_at = 0.0;
+    let mut inserted = false;
 
     let mut state = State::Launch;
     let mut attitude = Attitude::default();
 
     let streamer = Streamer::init(client, &ship)?;
 
-    let auto_pilot = ship.get_auto_pilot().mk_call(client)?;
-    let pitch_ctrl = Interpolate::new((100.0, 32000.0), (90.0, 0.0));
-    let aoa_ctrl = Interpolate::new((1000.0, 18000.0), (5.0, 25.0));
+    let auto_pilot = ship.get_auto_pilot().call(client)?;
 
     let mut prev_state = state;
 
@@ -34,61 +164,134 @@ pub fn launch(
 
     loop {
         streamer.update(stream_client, &mut attitude)?;
+        if attitude.abort {
+            control.set_throttle(0.0).call(client)?;
+            auto_pilot.disengage().call(client)?;
+            streamer.stop(client)?;
+            return Err(Error::Aborted);
+        }
         if state != prev_state {
             println!("{prev_state:?}->{state:?}");
             prev_state = state;
         }
         if attitude.thrust < prev_thrust && prev_stage != attitude.stage {
-            control.activate_next_stage().mk_call(client)?;
+            control.activate_next_stage().call(client)?;
             prev_stage = attitude.stage;
             prev_thrust = -1.0;
         } else if attitude.thrust > prev_thrust {
             prev_thrust = attitude.thrust;
         }
+        if let (Some(normal), State::Turn | State::Guidance) = (plane, state) {
+            let heading = azimuth::heading(
+                attitude.position.flip_handedness(),
+                attitude.velocity.flip_handedness(),
+                normal,
+                orbit_speed,
+            );
+            auto_pilot
+                .set_target_heading(heading.to_degrees() as f32)
+                .call(client)?;
+        }
         state = match state {
             State::Launch => {
                 auto_pilot
-                    .target_pitch_and_heading(90.0, 90.0 - inclination)
-                    .mk_call(client)?;
-                auto_pilot.engage().mk_call(client)?;
-                control.activate_next_stage().mk_call(client)?;
+                    .target_pitch_and_heading(90.0, azimuth)
+                    .call(client)?;
+                auto_pilot.engage().call(client)?;
+                control.activate_next_stage().call(client)?;
                 State::Ascent
             }
             State::Ascent => {
-                if attitude.alt < 1000.0 {
+                if attitude.alt < profile.vertical_altitude
+                    || attitude.speed < profile.turn_start_speed
+                {
                     State::Ascent
                 } else {
                     State::Turn
                 }
             }
             State::Turn => {
-                let tgt_pitch = pitch_ctrl.inter(attitude.alt);
-                let pitch = if attitude.alt < 24000.0 {
-                    tgt_pitch.max(attitude.pitch - attitude.aoa - aoa_ctrl.inter(attitude.alt))
-                } else {
-                    tgt_pitch
-                };
-                auto_pilot.set_target_pitch(pitch).mk_call(client)?;
+                auto_pilot
+                    .set_target_pitch(profile.turn_pitch(&attitude))
+                    .call(client)?;
 
-                if attitude.apop > 100000.0 {
+                if attitude.apop > profile.target_apoapsis {
                     State::Coast
+                } else if profile
+                    .guidance_altitude
+                    .is_some_and(|alt| attitude.alt > alt)
+                {
+                    State::Guidance
                 } else {
                     State::Turn
                 }
             }
+            State::Guidance => {
+                let current = PegState {
+                    radius: attitude.radius,
+                    vertical_speed: attitude.vertical_speed,
+                    horizontal_speed: attitude.horizontal_speed,
+                    acceleration: attitude.thrust as f64 / attitude.mass as f64,
+                    exhaust_velocity: attitude.isp as f64 * G0,
+                    mu,
+                };
+                let elapsed = attitude.ut - solved_at;
+                match guidance.as_mut() {
+                    // Staging, keep flying the last solution
+                    _ if attitude.thrust <= 0.0 || attitude.isp <= 0.0 => {}
+                    None => {
+                        let mut peg = Peg::new(&current, &target);
+                        if peg.update(&current, &target, 0.0).is_ok() {
+                            guidance = Some(peg);
+                            solved_at = attitude.ut;
+                        }
+                    }
+                    Some(peg)
+                        if elapsed >= GUIDANCE_PERIOD
+                            && peg.time_to_go - elapsed > GUIDANCE_TERMINAL =>
+                    {
+                        // A failed solution keeps flying the previous one
+                        if let Err(err) = peg.update(&current, &target, elapsed) {
+                            println!("{err}");
+                        }
+                        solved_at = attitude.ut;
+                    }
+                    Some(_) => {}
+                }
+
+                match guidance {
+                    Some(peg) if attitude.ut - solved_at >= peg.time_to_go => {
+                        control.set_throttle(0.0).call(client)?;
+                        inserted = true;
+                        State::End
+                    }
+                    Some(peg) => {
+                        let pitch = peg.pitch(attitude.ut - solved_at).to_degrees();
+                        auto_pilot.set_target_pitch(pitch as f32).call(client)?;
+                        State::Guidance
+                    }
+                    None if attitude.apop > profile.target_apoapsis => State::Coast,
+                    None => {
+                        auto_pilot
+                            .set_target_pitch(profile.turn_pitch(&attitude))
+                            .call(client)?;
+                        State::Guidance
+                    }
+                }
+            }
             State::Coast => {
-                control.set_throttle(0.0).mk_call(client)?;
-                if attitude.alt > 70000.0 {
+                control.set_throttle(0.0).call(client)?;
+                if attitude.alt > atmosphere_height {
                     State::End
                 } else {
                     State::Coast
                 }
             }
             State::End => {
-                control.set_throttle(0.0).mk_call(client)?;
-                auto_pilot.disengage().mk_call(client)?;
+                control.set_throttle(0.0).call(client)?;
+                auto_pilot.disengage().call(client)?;
                 streamer.stop(client)?;
-                return Ok(());
+                return Ok(inserted);
             }
         }
     }
@@ -99,13 +302,16 @@ pub enum State {
     Launch,
     Ascent,
     Turn,
+    Guidance,
     Coast,
     End,
 }
 
 #[derive(Default)]
 pub struct Attitude {
+    ut: f64,
     alt: f64,
+    speed: f64,
     aoa: f32,
     pitch: f32,
     apop: f64,
@@ -113,10 +319,20 @@ pub struct Attitude {
     eta_apop: f64,
     thrust: f32,
     stage: i32,
+    radius: f64,
+    vertical_speed: f64,
+    horizontal_speed: f64,
+    mass: f32,
+    isp: f32,
+    position: Vec3D,
+    velocity: Vec3D,
+    abort: bool,
 }
 
 pub struct Streamer {
+    ut: StreamHandle<f64>,
     alt: StreamHandle<f64>,
+    speed: StreamHandle<f64>,
     aoa: StreamHandle<f32>,
     pitch: StreamHandle<f32>,
     apop: StreamHandle<f64>,
@@ -124,24 +340,48 @@ pub struct Streamer {
     eta_apop: StreamHandle<f64>,
     thrust: StreamHandle<f32>,
     stage: StreamHandle<i32>,
+    radius: StreamHandle<f64>,
+    vertical_speed: StreamHandle<f64>,
+    horizontal_speed: StreamHandle<f64>,
+    mass: StreamHandle<f32>,
+    isp: StreamHandle<f32>,
+    position: StreamHandle<Vec3D>,
+    velocity: StreamHandle<Vec3D>,
+    abort: StreamHandle<bool>,
 }
 
 impl Streamer {
-    pub fn init(client: &mut RPCClient, vessel: &Vessel) -> Result<Self, Box<dyn Error>> {
+    pub fn init(client: &mut RPCClient, vessel: &Vessel) -> Result<Self, Error> {
         let flight = vessel
-            .flight(vessel.get_reference_frame().mk_call(client)?)
-            .mk_call(client)?;
-        let orbit = vessel.get_orbit().mk_call(client)?;
-        let control = vessel.get_control().mk_call(client)?;
+            .flight(vessel.get_reference_frame().call(client)?)
+            .call(client)?;
+        let orbit = vessel.get_orbit().call(client)?;
+        let body = orbit.get_body().call(client)?;
+        let surface = vessel
+            .flight(body.get_reference_frame().call(client)?)
+            .call(client)?;
+        let non_rotating = body.get_non_rotating_reference_frame().call(client)?;
+        let inertial = vessel.flight(non_rotating).call(client)?;
+        let control = vessel.get_control().call(client)?;
         Ok(Self {
-            alt: flight.get_surface_altitude().to_stream().mk_call(client)?,
-            aoa: flight.get_angle_of_attack().to_stream().mk_call(client)?,
-            pitch: flight.get_pitch().to_stream().mk_call(client)?,
-            apop: orbit.get_apoapsis_altitude().to_stream().mk_call(client)?,
-            perip: orbit.get_periapsis_altitude().to_stream().mk_call(client)?,
-            eta_apop: orbit.get_time_to_apoapsis().to_stream().mk_call(client)?,
-            thrust: vessel.get_available_thrust().to_stream().mk_call(client)?,
-            stage: control.get_current_stage().to_stream().mk_call(client)?,
+            ut: space_center::get_ut().to_stream().call(client)?,
+            alt: flight.get_surface_altitude().to_stream().call(client)?,
+            speed: surface.get_speed().to_stream().call(client)?,
+            aoa: flight.get_angle_of_attack().to_stream().call(client)?,
+            pitch: flight.get_pitch().to_stream().call(client)?,
+            apop: orbit.get_apoapsis_altitude().to_stream().call(client)?,
+            perip: orbit.get_periapsis_altitude().to_stream().call(client)?,
+            eta_apop: orbit.get_time_to_apoapsis().to_stream().call(client)?,
+            thrust: vessel.get_available_thrust().to_stream().call(client)?,
+            stage: control.get_current_stage().to_stream().call(client)?,
+            radius: orbit.get_radius().to_stream().call(client)?,
+            vertical_speed: inertial.get_vertical_speed().to_stream().call(client)?,
+            horizontal_speed: inertial.get_horizontal_speed().to_stream().call(client)?,
+            mass: vessel.get_mass().to_stream().call(client)?,
+            isp: vessel.get_specific_impulse().to_stream().call(client)?,
+            position: vessel.position(non_rotating).to_stream().call(client)?,
+            velocity: vessel.velocity(non_rotating).to_stream().call(client)?,
+            abort: control.get_abort().to_stream().call(client)?,
         })
     }
 
@@ -149,11 +389,17 @@ impl Streamer {
         &self,
         stream_client: &mut StreamClient,
         attitude: &mut Attitude,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), Error> {
         let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.ut)? {
+            attitude.ut = val;
+        }
         if let Some(val) = update.get_result(&self.alt)? {
             attitude.alt = val;
         }
+        if let Some(val) = update.get_result(&self.speed)? {
+            attitude.speed = val;
+        }
         if let Some(val) = update.get_result(&self.apop)? {
             attitude.apop = val;
         }
@@ -175,23 +421,104 @@ impl Streamer {
         if let Some(val) = update.get_result(&self.stage)? {
             attitude.stage = val;
         }
+        if let Some(val) = update.get_result(&self.radius)? {
+            attitude.radius = val;
+        }
+        if let Some(val) = update.get_result(&self.vertical_speed)? {
+            attitude.vertical_speed = val;
+        }
+        if let Some(val) = update.get_result(&self.horizontal_speed)? {
+            attitude.horizontal_speed = val;
+        }
+        if let Some(val) = update.get_result(&self.mass)? {
+            attitude.mass = val;
+        }
+        if let Some(val) = update.get_result(&self.isp)? {
+            attitude.isp = val;
+        }
+        if let Some(val) = update.get_result(&self.position)? {
+            attitude.position = val;
+        }
+        if let Some(val) = update.get_result(&self.velocity)? {
+            attitude.velocity = val;
+        }
+        if let Some(val) = update.get_result(&self.abort)? {
+            attitude.abort = val;
+        }
         Ok(())
     }
 
-    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Box<dyn Error>> {
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Error> {
         batch_call_unwrap!(
             client,
             (
                 &self.alt.remove(),
+                &self.speed.remove(),
                 &self.aoa.remove(),
                 &self.pitch.remove(),
                 &self.apop.remove(),
                 &self.perip.remove(),
                 &self.eta_apop.remove(),
+            )
+        )?;
+        batch_call_unwrap!(
+            client,
+            (
                 &self.thrust.remove(),
                 &self.stage.remove(),
+                &self.ut.remove(),
+                &self.radius.remove(),
+                &self.vertical_speed.remove(),
+                &self.horizontal_speed.remove(),
+                &self.mass.remove(),
+                &self.isp.remove(),
+            )
+        )?;
+        batch_call_unwrap!(
+            client,
+            (
+                &self.position.remove(),
+                &self.velocity.remove(),
+                &self.abort.remove(),
             )
         )?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::launch::AscentProfile;
+
+    #[test]
+    fn test_profile_defaults_fill_missing_fields() {
+        let profile: AscentProfile =
+            toml::from_str("target_apoapsis = 80000.0\naoa_limits = [3.0, 15.0]").unwrap();
+        assert_eq!(profile.target_apoapsis, 80000.0);
+        assert_eq!(profile.aoa_limits, (3.0, 15.0));
+        assert_eq!(profile.turn_end_altitude, 32000.0);
+
+        let profile: AscentProfile =
+            serde_json::from_str(r#"{"turn_shape": 0.5, "atmosphere_height": 50000.0}"#).unwrap();
+        assert_eq!(profile.turn_shape, 0.5);
+        assert_eq!(profile.atmosphere_height, Some(50000.0));
+        assert_eq!(profile.target_apoapsis, 100000.0);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/kerbin.toml");
+        assert_eq!(AscentProfile::load(path).unwrap(), AscentProfile::default());
+    }
+
+    #[test]
+    fn test_pitch_curve() {
+        let mut profile = AscentProfile::default();
+        assert_eq!(profile.pitch(0.0), 90.0);
+        // The curve is already under way when the vertical climb ends
+        assert!(profile.pitch(profile.vertical_altitude) < 90.0);
+        assert_eq!(profile.pitch(16050.0), 45.0);
+        assert_eq!(profile.pitch(40000.0), 0.0);
+        profile.turn_shape = 0.5;
+        assert!(profile.pitch(16050.0) < 45.0);
+        assert_eq!(profile.aoa_limit(1000.0), Some(5.0));
+        assert_eq!(profile.aoa_limit(30000.0), None);
+    }
+}
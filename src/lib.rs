@@ -1,8 +1,31 @@
+pub mod apsis;
+pub mod azimuth;
+pub mod camera;
 pub mod circ;
+pub mod cli;
+pub mod deltav;
+pub mod docking;
+pub mod ejection;
+pub mod error;
 pub mod intercept;
 pub mod interpolate;
 pub mod intersect;
+pub mod lambert;
+pub mod landing;
 pub mod launch;
 pub mod maneuver;
+pub mod minimize;
+pub mod mock;
+pub mod orbit;
+pub mod peg;
+pub mod plane;
+pub mod porkchop;
+pub mod reentry;
+pub mod rendezvous;
+pub mod replay;
 pub mod services;
+pub mod sim;
+pub mod stage;
+pub mod terrain;
 pub mod vector;
+pub mod window;
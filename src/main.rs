@@ -1,15 +1,379 @@
+use std::{error::Error, net::TcpListener, thread::sleep, time::Duration};
+
 use betterjeb::{
+    camera::{detect, save, MarkerOptions},
+    circ::circ,
+    cli::{self, Command, Options, Target},
+    deltav::{StageDeltaV, Vehicle},
+    docking::{dock, DockingOptions},
+    error::{self, Call, Missing},
+    intercept::intercept,
+    landing::{descend, land, LandingOptions},
+    launch::{launch, AscentProfile},
     maneuver::maneuver,
-    services::space_center::{self},
+    orbit::OrbitalElements,
+    plane::plane,
+    porkchop::{hohmann_time, steps, sweep, synodic_period, Planet},
+    reentry::{reenter, return_to, ReentryOptions},
+    rendezvous::{rendezvous, RendezvousOptions},
+    replay::{load, record, ReplayServer},
+    services::{
+        docking_camera, li_dar,
+        space_center::{self, CelestialBody, Vessel},
+    },
+    terrain::{HeightMap, SiteOptions, Terrain},
+    vector::Vector,
+    window::{self, Site},
 };
 use krpc_mars::{RPCClient, StreamClient};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = RPCClient::connect("kRPC TEST", "127.0.0.1:50000")?;
-    let mut stream_client = StreamClient::connect(&client, "127.0.0.1:50001")?;
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = cli::parse(std::env::args().skip(1))?;
+    match &options.command {
+        Command::Help => println!("{}", cli::USAGE),
+        // The proxies stand in for the game's server instead of connecting to it
+        Command::Record { .. } | Command::Replay { .. } => serve(&options)?,
+        _ => {
+            let (mut client, mut stream_client) = options.connect()?;
+            let ship = cli::active_vessel(&mut client)?;
+            run(&mut client, &mut stream_client, &ship, &options)?;
+        }
+    }
+    Ok(())
+}
+
+fn run(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let target_name = options.target.as_deref();
+    match &options.command {
+        Command::Help | Command::Record { .. } | Command::Replay { .. } => {
+            unreachable!("handled without a vessel")
+        }
+        Command::Launch {
+            profile,
+            apoapsis,
+            periapsis,
+            inclination,
+        } => {
+            let mut profile = match profile {
+                Some(path) => AscentProfile::load(path)?,
+                None => AscentProfile::default(),
+            };
+            if let Some(apoapsis) = apoapsis {
+                profile.target_apoapsis = *apoapsis;
+            }
+            if periapsis.is_some() {
+                profile.target_periapsis = *periapsis;
+            }
+            let inserted = launch_to(
+                client,
+                stream_client,
+                ship,
+                &profile,
+                *inclination,
+                target_name,
+            )?;
+            if !inserted {
+                circ(client, ship)?;
+                maneuver(client, stream_client, ship)?;
+            }
+        }
+        Command::Circ => {
+            circ(client, ship)?;
+            maneuver(client, stream_client, ship)?;
+        }
+        Command::ExecuteNode => {
+            let result = maneuver(client, stream_client, ship)?;
+            println!("Burn: {result:?}");
+        }
+        Command::Plane { apoapsis } => {
+            let target_orbit = cli::target(client, target_name)?.orbit(client)?;
+            plane(client, ship, &target_orbit, *apoapsis)?;
+            maneuver(client, stream_client, ship)?;
+        }
+        Command::Transfer => {
+            let Target::Body(body) = cli::target(client, target_name)? else {
+                return Err("Transfers need a body as the target".into());
+            };
+            intercept(client, ship, &body)?;
+            maneuver(client, stream_client, ship)?;
+        }
+        Command::Rendezvous => {
+            let Target::Vessel(vessel) = cli::target(client, target_name)? else {
+                return Err("Rendezvous needs a vessel as the target".into());
+            };
+            let result = rendezvous(
+                client,
+                stream_client,
+                ship,
+                &vessel,
+                &RendezvousOptions::default(),
+            )?;
+            println!("Rendezvous: {result:?}");
+        }
+        Command::Dock => {
+            let port = cli::target_docking_port(client)?;
+            dock(
+                client,
+                stream_client,
+                ship,
+                &port,
+                &DockingOptions::default(),
+            )?;
+        }
+        Command::Land { site } => {
+            let options = LandingOptions::default();
+            // Without a site, brake and land wherever the ship is now
+            match site {
+                Some((latitude, longitude)) => {
+                    land(client, stream_client, ship, *latitude, *longitude, &options)?
+                }
+                None => {
+                    let ut = space_center::get_ut().call(client)?;
+                    descend(client, stream_client, ship, ut, &options)?
+                }
+            }
+        }
+        Command::Reentry { longitude } => {
+            let options = ReentryOptions::default();
+            // Without a longitude the ship is already on its way down
+            match longitude {
+                Some(longitude) => return_to(client, stream_client, ship, *longitude, &options)?,
+                None => reenter(client, stream_client, ship, &options)?,
+            }
+        }
+        Command::Deltav => {
+            let body = ship.get_orbit().call(client)?.get_body().call(client)?;
+            let gravity = body.get_surface_gravity().call(client)?;
+            print_stages(&Vehicle::snapshot(client, ship)?.analyze(gravity));
+        }
+        Command::Porkchop => {
+            let Target::Body(body) = cli::target(client, target_name)? else {
+                return Err("Porkchops need a body as the target".into());
+            };
+            sweep_transfers(client, ship, &body)?;
+        }
+        Command::Camera { output } => save_camera(client, ship, output)?,
+        Command::Terrain => scan_terrain(client, ship)?,
+    }
+    Ok(())
+}
+
+/// Launch in the target's plane at its next window, on the inclination's heading right away,
+/// or due east without either
+fn launch_to(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    profile: &AscentProfile,
+    inclination: Option<f64>,
+    target_name: Option<&str>,
+) -> Result<bool, Box<dyn Error>> {
+    let target = match cli::target(client, target_name) {
+        Ok(target) => Some(target),
+        // Only a named target has to exist
+        Err(error::Error::Missing(Missing::Target)) if target_name.is_none() => None,
+        Err(err) => return Err(err.into()),
+    };
+    let target_orbit = match &target {
+        Some(target) => Some(target.orbit(client)?),
+        None => None,
+    };
+    let site = Site::snapshot(client, ship)?;
+    let body = ship.get_orbit().call(client)?.get_body().call(client)?;
+    let mu = body.get_gravitational_parameter().call(client)?;
+    let orbit_speed = (mu / (site.radius + profile.target_apoapsis)).sqrt();
+
+    let mut azimuth = 90.0;
+    if let Some(target_orbit) = &target_orbit {
+        let target = OrbitalElements::snapshot(client, target_orbit)?;
+        let window = window::next(&site, &target, orbit_speed);
+        println!(
+            "Launch window: {} at {:.1} deg",
+            if window.northbound { "north" } else { "south" },
+            window.azimuth.to_degrees()
+        );
+        azimuth = window.azimuth.to_degrees();
+        space_center::warp_to(window.ut, 100000.0, 2.0).call(client)?;
+    } else if let Some(inclination) = inclination {
+        azimuth = window::azimuth(&site, inclination.to_radians(), orbit_speed).to_degrees();
+        println!("Launch heading: {azimuth:.1} deg");
+    }
+
+    Ok(launch(
+        client,
+        stream_client,
+        ship,
+        azimuth as f32,
+        target_orbit.as_ref(),
+        profile,
+    )?)
+}
+
+/// Departures over one synodic period against flight times around the Hohmann transfer's
+fn sweep_transfers(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    destination: &CelestialBody,
+) -> Result<(), Box<dyn Error>> {
+    let ship_orbit = ship.get_orbit().call(client)?;
+    let origin = ship_orbit.get_body().call(client)?;
+    let parking = OrbitalElements::snapshot(client, &ship_orbit)?.semi_major_axis;
+
+    let origin = Planet::snapshot(client, &origin)?;
+    let destination = Planet::snapshot(client, destination)?;
+    let ut = space_center::get_ut().call(client)?;
+
+    let window = synodic_period(&origin.orbit, &destination.orbit);
+    let hohmann = hohmann_time(&origin.orbit, &destination.orbit);
+    let porkchop = sweep(
+        &origin,
+        &destination,
+        parking,
+        destination.low_orbit(),
+        steps(ut, ut + window, 120),
+        steps(0.5 * hohmann, 1.5 * hohmann, 80),
+    );
+
+    porkchop.write_csv("porkchop.csv")?;
+    porkchop.write_svg("porkchop.svg")?;
+    if let Some((departure, flight_time, point)) = porkchop.best() {
+        println!("Departure: {departure}");
+        println!("Time of flight: {flight_time}");
+        println!("Ejection: {}", point.ejection_dv);
+        println!("Capture: {}", point.capture_dv);
+    }
+    Ok(())
+}
+
+/// Save the view of the camera on the part the ship is controlled from
+fn save_camera(client: &mut RPCClient, ship: &Vessel, path: &str) -> Result<(), Box<dyn Error>> {
+    let part = ship
+        .get_parts()
+        .call(client)?
+        .get_controlling()
+        .call(client)?;
+    let camera = docking_camera::camera(part).call(client)?;
+
+    let frame = save(client, &camera, path)?;
+    println!("Saved {path}");
+    match detect(&frame.to_luma8(), &MarkerOptions::default()) {
+        Some(marker) => println!("Marker: {marker:?}"),
+        None => println!("No marker in view"),
+    }
+    Ok(())
+}
+
+fn scan_terrain(client: &mut RPCClient, ship: &Vessel) -> Result<(), Box<dyn Error>> {
+    let body = ship.get_orbit().call(client)?.get_body().call(client)?;
+    let parts = ship.get_parts().call(client)?.get_all().call(client)?;
+    let laser = parts
+        .into_iter()
+        .find_map(|part| li_dar::laser(part).call(client).ok())
+        .ok_or("No LiDAR on the ship")?;
 
-    let ship = space_center::get_active_vessel().mk_call(&mut client)?;
+    let mut terrain = Terrain::default();
+    for _ in 0..10 {
+        let count = terrain.scan(client, &laser, &body)?;
+        println!("Scanned {count} points");
+        sleep(Duration::from_secs(1));
+    }
+    if terrain.points.is_empty() {
+        return Err("LiDAR saw no terrain".into());
+    }
 
-    maneuver(&mut client, &mut stream_client, &ship)?;
+    // Centre the map under the ship at the mean radius of the points
+    let rf = body.get_reference_frame().call(client)?;
+    let position = ship.position(rf).call(client)?.flip_handedness();
+    let radius = terrain.points.iter().map(|p| p.mag()).sum::<f64>() / terrain.points.len() as f64;
+    let map = HeightMap::build(
+        &terrain.points,
+        position.normalize().scale(radius),
+        2.0,
+        100,
+    );
+    map.write_csv("terrain.csv")?;
+    map.write_ply("terrain.ply")?;
+    println!("Saved terrain.csv and terrain.ply");
+
+    match map.sites(&SiteOptions::default()).first() {
+        Some(site) => println!("Best site: {site:?}"),
+        None => println!("No safe site in view"),
+    }
     Ok(())
 }
+
+/// Record or replay on the server options
+fn serve(options: &Options) -> Result<(), Box<dyn Error>> {
+    let host = options.host.as_str();
+    match &options.command {
+        Command::Record {
+            log,
+            server_rpc,
+            server_stream,
+        } => {
+            let rpc = TcpListener::bind((host, options.rpc_port))?;
+            let stream = TcpListener::bind((host, options.stream_port))?;
+            println!("Forwarding to {server_rpc} and {server_stream}");
+            record(
+                &rpc,
+                &stream,
+                server_rpc.as_str(),
+                server_stream.clone(),
+                log,
+            )?;
+            println!("Saved {log}");
+        }
+        Command::Replay { log } => {
+            let entries = load(log)?;
+            println!("Replaying {} entries", entries.len());
+
+            let server = ReplayServer::bind(
+                entries,
+                (host, options.rpc_port),
+                (host, options.stream_port),
+            )?;
+            while !server.disconnected() {
+                sleep(Duration::from_millis(100));
+            }
+            match server.remaining() {
+                0 => println!("Replayed to the end"),
+                remaining => println!("Client left with {remaining} entries to go"),
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn print_stages(stages: &[StageDeltaV]) {
+    println!(
+        "{:>5} {:>10} {:>10} {:>9} {:>9} {:>7} {:>7} {:>8}",
+        "stage", "mass", "end mass", "dv vac", "dv asl", "twr vac", "twr asl", "time"
+    );
+    for stage in stages {
+        println!(
+            "{:>5} {:>10.0} {:>10.0} {:>9.0} {:>9.0} {:>7.2} {:>7.2} {:>8.1}",
+            stage.number,
+            stage.start_mass,
+            stage.end_mass,
+            stage.vacuum_delta_v,
+            stage.sea_level_delta_v,
+            stage.vacuum_twr,
+            stage.sea_level_twr,
+            stage.burn_time
+        );
+    }
+    println!(
+        "{:>5} {:>10} {:>10} {:>9.0} {:>9.0}",
+        "total",
+        "",
+        "",
+        stages.iter().map(|s| s.vacuum_delta_v).sum::<f64>(),
+        stages.iter().map(|s| s.sea_level_delta_v).sum::<f64>()
+    );
+}
@@ -1,88 +1,268 @@
-use std::error::Error;
+use std::f64::consts::FRAC_PI_2;
 
-use krpc_mars::{krpc::Event, RPCClient, StreamClient};
+use krpc_mars::{batch_call_unwrap, krpc::Event, stream::StreamHandle, RPCClient, StreamClient};
 
-use crate::services::{
-    krpc::{add_event, Expression},
-    space_center::{self, Vessel},
+use crate::{
+    error::{Call, Error, Missing},
+    services::{
+        krpc::{add_event, Expression},
+        space_center::{self, AutoPilot, Control, Node, ReferenceFrame, Vessel},
+    },
+    stage,
+    vector::{Vec3D, Vector},
 };
 
+/// Remaining delta-v below which drift is no longer checked and the autopilot stops re-pointing
+const SETTLE_DV: f64 = 1.0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct BurnOptions {
+    /// Largest angle in radians between the remaining and the initial burn vector before aborting
+    pub tolerance: f64,
+    /// Seconds of burn left at full thrust when throttling down starts
+    pub throttle_down_time: f64,
+    pub min_throttle: f32,
+    /// Remaining delta-v at which the burn is complete
+    pub cutoff: f64,
+}
+
+impl Default for BurnOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 15f64.to_radians(),
+            throttle_down_time: 2.0,
+            min_throttle: 0.05,
+            cutoff: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BurnStatus {
+    Complete,
+    /// Burn vector drifted past the tolerance
+    Drifted,
+    /// No thrust left and no stage to activate
+    OutOfThrust,
+}
+
+/// Outcome of executing a maneuver node
+#[derive(Debug, Copy, Clone)]
+pub struct BurnResult {
+    pub status: BurnStatus,
+    /// Remaining burn vector in the node's orbital reference frame
+    pub residual: Vec3D,
+    pub residual_dv: f64,
+    /// Seconds spent with the throttle open
+    pub duration: f64,
+}
+
 pub fn maneuver(
     client: &mut RPCClient,
     stream_client: &mut StreamClient,
     ship: &Vessel,
-) -> Result<(), Box<dyn Error>> {
-    let control = ship.get_control().mk_call(client)?;
-    control.set_throttle(0.0).mk_call(client)?;
-    let auto_pilot = ship.get_auto_pilot().mk_call(client)?;
+) -> Result<BurnResult, Error> {
+    execute(client, stream_client, ship, &BurnOptions::default())
+}
+
+/// Execute the next maneuver node, steering and throttling on the remaining burn vector
+pub fn execute(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    options: &BurnOptions,
+) -> Result<BurnResult, Error> {
+    let control = ship.get_control().call(client)?;
+    control.set_throttle(0.0).call(client)?;
+    let auto_pilot = ship.get_auto_pilot().call(client)?;
     let node = control
         .get_nodes()
-        .mk_call(client)?
+        .call(client)?
         .into_iter()
         .next()
-        .ok_or("No node found!")?;
-    let rf = node.get_orbital_reference_frame().mk_call(client)?;
-    auto_pilot.set_reference_frame(rf.clone()).mk_call(client)?;
-    let ut_node = node.get_ut().mk_call(client)?;
-    let deltav = node.get_delta_v().mk_call(client)?;
+        .ok_or(Error::Missing(Missing::Node))?;
+    let rf = node.get_orbital_reference_frame().call(client)?;
+    auto_pilot.set_reference_frame(rf).call(client)?;
+    let ut_node = node.get_ut().call(client)?;
+    let deltav = node.get_delta_v().call(client)?;
     let (burn_time_before, burn_time_after) = burn_time(client, ship, deltav)?;
     println!("Burn Time: {}", burn_time_before + burn_time_after);
     let burn_start_time = ut_node - burn_time_before;
-    space_center::warp_to(burn_start_time - 60.0, 100000.0, 2.0).mk_call(client)?;
-    auto_pilot
-        .set_target_direction(node.burn_vector(rf).mk_call(client)?)
-        .mk_call(client)?;
-    auto_pilot.engage().mk_call(client)?;
-    auto_pilot.wait().mk_call(client)?;
-    let ut_time = space_center::get_ut().to_stream().mk_call(client)?;
+    space_center::warp_to(burn_start_time - 60.0, 100000.0, 2.0).call(client)?;
+    let initial = node.burn_vector(rf).call(client)?;
+    auto_pilot.set_target_direction(initial).call(client)?;
+    auto_pilot.engage().call(client)?;
+    auto_pilot.wait().call(client)?;
+
+    let streamer = Streamer::init(client, ship, &node, rf)?;
+    let mut telemetry = Telemetry::default();
     loop {
-        let update = stream_client.recv_update()?;
-        if let Some(val) = update.get_result(&ut_time)? {
-            if val >= burn_start_time {
-                break;
-            }
+        streamer.update(stream_client, &mut telemetry)?;
+        if telemetry.abort {
+            return abort(client, &control, &auto_pilot, &streamer);
+        }
+        if telemetry.ut >= burn_start_time {
+            break;
         }
     }
-    control.set_throttle(1.0).mk_call(client)?;
-    let burn_stop_time = ut_node + burn_time_after;
-    loop {
-        let update = stream_client.recv_update()?;
-        if let Some(val) = update.get_result(&ut_time)? {
-            if val >= burn_stop_time {
-                break;
+
+    let mut staged_at = -1;
+    let status = loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        if telemetry.abort {
+            return abort(client, &control, &auto_pilot, &streamer);
+        }
+        let remaining = telemetry.remaining.mag();
+        let drift = telemetry.remaining.vang(initial);
+        if remaining < options.cutoff || drift > FRAC_PI_2 {
+            break BurnStatus::Complete;
+        }
+        if remaining > SETTLE_DV && drift > options.tolerance {
+            break BurnStatus::Drifted;
+        }
+        if telemetry.thrust <= 0.0 {
+            if telemetry.stage <= 0 {
+                break BurnStatus::OutOfThrust;
+            }
+            if staged_at != telemetry.stage {
+                control.activate_next_stage().call(client)?;
+                staged_at = telemetry.stage;
             }
+            continue;
         }
-    }
-    control.set_throttle(0.0).mk_call(client)?;
-    auto_pilot.disengage().mk_call(client)?;
-    ut_time.remove().mk_call(client)?;
-    Ok(())
+        let acceleration = telemetry.thrust as f64 / telemetry.mass as f64;
+        let throttle = (remaining / (acceleration * options.throttle_down_time))
+            .clamp(options.min_throttle as f64, 1.0);
+        control.set_throttle(throttle as f32).call(client)?;
+        if remaining > SETTLE_DV {
+            auto_pilot
+                .set_target_direction(telemetry.remaining)
+                .call(client)?;
+        }
+    };
+    control.set_throttle(0.0).call(client)?;
+    auto_pilot.disengage().call(client)?;
+    let (residual, end) = batch_call_unwrap!(
+        client,
+        (&node.remaining_burn_vector(rf), &space_center::get_ut())
+    )?;
+    streamer.stop(client)?;
+    Ok(BurnResult {
+        status,
+        residual,
+        residual_dv: residual.mag(),
+        duration: end - burn_start_time,
+    })
 }
 
-pub fn burn_time(
+/// Cut the throttle and release the controls after the user aborted
+fn abort(
     client: &mut RPCClient,
-    ship: &Vessel,
-    deltav: f64,
-) -> Result<(f64, f64), Box<dyn Error>> {
-    let half_dv = deltav / 2.0;
-    let thrust = ship.get_available_thrust().mk_call(client)? as f64;
-    let isp = ship.get_specific_impulse().mk_call(client)? as f64 * 9.82;
-    let m0 = ship.get_mass().mk_call(client)? as f64;
-    let m1 = m0 / (half_dv / isp).exp();
-    let m2 = m1 / (half_dv / isp).exp();
-    let flow_rate = thrust / isp;
-    let burn_time_before = (m0 - m1) / flow_rate;
-    let burn_time_after = (m1 - m2) / flow_rate;
+    control: &Control,
+    auto_pilot: &AutoPilot,
+    streamer: &Streamer,
+) -> Result<BurnResult, Error> {
+    control.set_throttle(0.0).call(client)?;
+    auto_pilot.disengage().call(client)?;
+    streamer.stop(client)?;
+    Err(Error::Aborted)
+}
+
+/// Burn time before and after the node, staging as the stages run dry
+pub fn burn_time(client: &mut RPCClient, ship: &Vessel, deltav: f64) -> Result<(f64, f64), Error> {
+    let stages = stage::stages(client, ship)?;
+    let burn_time_before = stage::burn_time(&stages, deltav / 2.0)?;
+    let burn_time_after = stage::burn_time(&stages, deltav)? - burn_time_before;
     Ok((burn_time_before, burn_time_after))
 }
 
-pub fn alarm(client: &mut RPCClient, ut_time: f64) -> Result<Event, Box<dyn Error>> {
-    let call = Expression::call(space_center::get_ut().get_call().clone()).mk_call(client)?;
+pub fn alarm(client: &mut RPCClient, ut_time: f64) -> Result<Event, Error> {
+    let call = Expression::call(space_center::get_ut().get_call().clone()).call(client)?;
     let exp = Expression::greater_than_or_equal(
         call,
-        Expression::constant_float(ut_time as f32).mk_call(client)?,
+        Expression::constant_float(ut_time as f32).call(client)?,
     )
-    .mk_call(client)?;
-    let event = add_event(exp).mk_call(client)?;
+    .call(client)?;
+    let event = add_event(exp).call(client)?;
     Ok(event)
 }
+
+#[derive(Default)]
+pub struct Telemetry {
+    ut: f64,
+    remaining: Vec3D,
+    thrust: f32,
+    mass: f32,
+    stage: i32,
+    abort: bool,
+}
+
+pub struct Streamer {
+    ut: StreamHandle<f64>,
+    remaining: StreamHandle<Vec3D>,
+    thrust: StreamHandle<f32>,
+    mass: StreamHandle<f32>,
+    stage: StreamHandle<i32>,
+    abort: StreamHandle<bool>,
+}
+
+impl Streamer {
+    pub fn init(
+        client: &mut RPCClient,
+        vessel: &Vessel,
+        node: &Node,
+        rf: ReferenceFrame,
+    ) -> Result<Self, Error> {
+        let control = vessel.get_control().call(client)?;
+        Ok(Self {
+            ut: space_center::get_ut().to_stream().call(client)?,
+            remaining: node.remaining_burn_vector(rf).to_stream().call(client)?,
+            thrust: vessel.get_available_thrust().to_stream().call(client)?,
+            mass: vessel.get_mass().to_stream().call(client)?,
+            stage: control.get_current_stage().to_stream().call(client)?,
+            abort: control.get_abort().to_stream().call(client)?,
+        })
+    }
+
+    pub fn update(
+        &self,
+        stream_client: &mut StreamClient,
+        telemetry: &mut Telemetry,
+    ) -> Result<(), Error> {
+        let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.ut)? {
+            telemetry.ut = val;
+        }
+        if let Some(val) = update.get_result(&self.remaining)? {
+            telemetry.remaining = val;
+        }
+        if let Some(val) = update.get_result(&self.thrust)? {
+            telemetry.thrust = val;
+        }
+        if let Some(val) = update.get_result(&self.mass)? {
+            telemetry.mass = val;
+        }
+        if let Some(val) = update.get_result(&self.stage)? {
+            telemetry.stage = val;
+        }
+        if let Some(val) = update.get_result(&self.abort)? {
+            telemetry.abort = val;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Error> {
+        batch_call_unwrap!(
+            client,
+            (
+                &self.ut.remove(),
+                &self.remaining.remove(),
+                &self.thrust.remove(),
+                &self.mass.remove(),
+                &self.stage.remove(),
+                &self.abort.remove(),
+            )
+        )?;
+        Ok(())
+    }
+}
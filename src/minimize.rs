@@ -0,0 +1,61 @@
+/// Value between start and end minimizing `f`, taking the best of `samples` even steps
+/// and refining it by golden section search between its neighbours
+pub fn minimize(
+    f: impl Fn(f64) -> f64,
+    start: f64,
+    end: f64,
+    samples: usize,
+    iterations: usize,
+) -> f64 {
+    let samples = samples.max(1);
+    let step = (end - start) / samples as f64;
+    let best = (0..=samples)
+        .map(|i| start + step * i as f64)
+        .map(|t| (t, f(t)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(start, |(t, _)| t);
+
+    // Each iteration keeps one interior point and only evaluates the other
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = ((best - step).max(start), (best + step).min(end));
+    let mut a = high - ratio * (high - low);
+    let mut b = low + ratio * (high - low);
+    let (mut fa, mut fb) = (f(a), f(b));
+    for _ in 0..iterations {
+        if fa < fb {
+            (high, b, fb) = (b, a, fa);
+            a = high - ratio * (high - low);
+            fa = f(a);
+        } else {
+            (low, a, fa) = (a, b, fb);
+            b = low + ratio * (high - low);
+            fb = f(b);
+        }
+    }
+    (low + high) / 2.0
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use crate::minimize::minimize;
+
+    #[test]
+    fn test_minimize_refines_between_samples() {
+        let calls = Cell::new(0);
+        let f = |x: f64| {
+            calls.set(calls.get() + 1);
+            (x - 1.234).powi(2)
+        };
+        let x = minimize(f, 0.0, 10.0, 10, 60);
+        assert!((x - 1.234).abs() < 1e-6);
+        // Every sample and every iteration evaluates once, plus the first two interior points
+        assert_eq!(calls.get(), 11 + 60 + 2);
+        // The best sample is at the start, the search does not leave the range
+        let x = minimize(|x| x, 2.0, 3.0, 4, 60);
+        assert!((x - 2.0).abs() < 1e-6);
+        let x = minimize(|x| -x, 2.0, 3.0, 4, 60);
+        assert!((x - 3.0).abs() < 1e-6);
+    }
+}
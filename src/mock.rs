@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use krpc_mars::{
+    codec::{RPCEncodable, RPCExtractable},
+    krpc::{
+        self, ConnectionRequest, ConnectionRequest_Type, ConnectionResponse,
+        ConnectionResponse_Status, ProcedureCall, ProcedureResult, Request, Response, StreamResult,
+        StreamUpdate,
+    },
+    protobuf::{CodedInputStream, Message},
+    RPCClient, StreamClient,
+};
+
+use crate::sim::Simulation;
+
+/// Time without requests after which the client is taken to be waiting for the next update
+const QUIET: Duration = Duration::from_millis(1);
+
+/// Stand-in for the kRPC server on localhost, speaking its RPC and stream protocols
+/// Procedures are named `Service.Procedure`, as in `SpaceCenter.Vessel_get_Orbit`
+pub struct MockServer {
+    rpc_address: SocketAddr,
+    stream_address: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// Values for a single stream update, sent to every stream of each procedure
+#[derive(Debug, Clone, Default)]
+pub struct Update {
+    values: Vec<(String, Vec<u8>)>,
+}
+
+impl Update {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<T: RPCEncodable>(mut self, procedure: &str, value: T) -> Self {
+        self.values.push((procedure.to_string(), encode(value)));
+        self
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Queued answers by procedure, the last one answering every later call
+    responses: HashMap<String, VecDeque<Result<Vec<u8>, String>>>,
+    calls: Vec<ProcedureCall>,
+    /// Call streamed under each stream id
+    streams: HashMap<u64, ProcedureCall>,
+    next_stream: u64,
+    /// Updates waiting for the client to stream all of their procedures
+    updates: VecDeque<Update>,
+    stream_socket: Option<TcpStream>,
+    simulation: Option<Simulation>,
+    last_request: Option<Instant>,
+}
+
+impl MockServer {
+    /// Listen for RPC and stream connections on free ports
+    pub fn start() -> io::Result<Self> {
+        let rpc = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpListener::bind("127.0.0.1:0")?;
+        let server = Self {
+            rpc_address: rpc.local_addr()?,
+            stream_address: stream.local_addr()?,
+            shared: Arc::new(Mutex::new(Shared {
+                next_stream: 1,
+                ..Default::default()
+            })),
+        };
+        let shared = server.shared.clone();
+        thread::spawn(move || {
+            for socket in rpc.incoming().flatten() {
+                let shared = shared.clone();
+                thread::spawn(move || serve_rpc(socket, &shared));
+            }
+        });
+        let shared = server.shared.clone();
+        thread::spawn(move || {
+            for socket in stream.incoming().flatten() {
+                if accept_stream(&socket).is_ok() {
+                    let mut shared = lock(&shared);
+                    shared.stream_socket = Some(socket);
+                    shared.flush();
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn rpc_address(&self) -> SocketAddr {
+        self.rpc_address
+    }
+
+    pub fn stream_address(&self) -> SocketAddr {
+        self.stream_address
+    }
+
+    /// Connect both clients, as the CLI does to a running game
+    pub fn connect(&self) -> Result<(RPCClient, StreamClient), Box<dyn Error>> {
+        let client = RPCClient::connect("mock", self.rpc_address)?;
+        let stream_client = StreamClient::connect(&client, self.stream_address)?;
+        Ok((client, stream_client))
+    }
+
+    /// Queue an answer to the procedure, the last one queued answers every later call
+    pub fn respond<T: RPCEncodable>(&self, procedure: &str, value: T) {
+        self.queue(procedure, Ok(encode(value)));
+    }
+
+    /// Queue an error as the answer to the procedure
+    pub fn fail(&self, procedure: &str, description: &str) {
+        self.queue(procedure, Err(description.to_string()));
+    }
+
+    fn queue(&self, procedure: &str, answer: Result<Vec<u8>, String>) {
+        lock(&self.shared)
+            .responses
+            .entry(procedure.to_string())
+            .or_default()
+            .push_back(answer);
+    }
+
+    /// Send the update once the client streams all of its procedures
+    /// Updates go out in the order they were pushed
+    pub fn push(&self, update: Update) {
+        let mut shared = lock(&self.shared);
+        shared.updates.push_back(update);
+        shared.flush();
+    }
+
+    /// Answer the procedures it simulates from the simulation, falling back to queued answers
+    /// While anything is streamed it steps each time the client goes quiet and sends every stream's value
+    pub fn simulate(&self, simulation: Simulation) {
+        lock(&self.shared).simulation = Some(simulation);
+        let shared = self.shared.clone();
+        thread::spawn(move || loop {
+            thread::sleep(QUIET);
+            let mut shared = lock(&shared);
+            if shared.last_request.is_some_and(|at| at.elapsed() < QUIET) {
+                continue;
+            }
+            if shared.tick().is_err() {
+                return;
+            }
+        });
+    }
+
+    /// Current state of the simulation
+    pub fn simulation(&self) -> Option<Simulation> {
+        lock(&self.shared).simulation.clone()
+    }
+
+    /// Every call received so far, stream requests included
+    pub fn calls(&self) -> Vec<ProcedureCall> {
+        lock(&self.shared).calls.clone()
+    }
+
+    /// Calls received to the procedure, in order
+    pub fn calls_to(&self, procedure: &str) -> Vec<ProcedureCall> {
+        self.calls()
+            .into_iter()
+            .filter(|call| name(call) == procedure)
+            .collect()
+    }
+}
+
+/// Decode an argument of a recorded call, position 0 being the object for class members
+pub fn argument<T: RPCExtractable>(
+    call: &ProcedureCall,
+    position: u32,
+) -> Result<T, Box<dyn Error>> {
+    let argument = call
+        .get_arguments()
+        .iter()
+        .find(|arg| arg.get_position() == position)
+        .ok_or_else(|| format!("{} has no argument {position}", name(call)))?;
+    decode(argument.get_value())
+}
+
+impl Shared {
+    fn answer(&mut self, call: &ProcedureCall) -> Result<Vec<u8>, String> {
+        self.calls.push(call.clone());
+        match name(call).as_str() {
+            "KRPC.AddStream" => {
+                let streamed = call
+                    .get_arguments()
+                    .first()
+                    .and_then(|arg| ProcedureCall::parse_from_bytes(arg.get_value()).ok())
+                    .ok_or("AddStream without a call")?;
+                let id = self.next_stream;
+                self.next_stream += 1;
+                self.streams.insert(id, streamed);
+                let mut stream = krpc::Stream::new();
+                stream.set_id(id);
+                stream.write_to_bytes().map_err(|err| err.to_string())
+            }
+            "KRPC.RemoveStream" => {
+                let id: u64 = argument(call, 0).map_err(|err| err.to_string())?;
+                self.streams.remove(&id);
+                Ok(Vec::new())
+            }
+            procedure => {
+                if let Some(simulation) = self.simulation.as_mut() {
+                    if let Some(value) = simulation.answer(call).map_err(|err| err.to_string())? {
+                        return Ok(value);
+                    }
+                }
+                let answers = self
+                    .responses
+                    .get_mut(procedure)
+                    .ok_or_else(|| format!("No response for {procedure}"))?;
+                if answers.len() > 1 {
+                    answers.pop_front().unwrap_or(Ok(Vec::new()))
+                } else {
+                    answers.front().cloned().unwrap_or(Ok(Vec::new()))
+                }
+            }
+        }
+    }
+
+    /// Send the waiting updates whose procedures are all streamed
+    fn flush(&mut self) {
+        let Some(socket) = self.stream_socket.as_mut() else {
+            return;
+        };
+        while let Some(update) = self.updates.front() {
+            let streamed =
+                |procedure: &String| self.streams.values().any(|call| name(call) == *procedure);
+            if !update
+                .values
+                .iter()
+                .all(|(procedure, _)| streamed(procedure))
+            {
+                return;
+            }
+            let mut message = StreamUpdate::new();
+            for (procedure, value) in &update.values {
+                for (&id, _) in self
+                    .streams
+                    .iter()
+                    .filter(|(_, call)| name(call) == *procedure)
+                {
+                    let mut result = ProcedureResult::new();
+                    result.set_value(value.clone());
+                    let mut stream_result = StreamResult::new();
+                    stream_result.set_id(id);
+                    stream_result.set_result(result);
+                    message.mut_results().push(stream_result);
+                }
+            }
+            if message.write_length_delimited_to_writer(socket).is_err() {
+                return;
+            }
+            self.updates.pop_front();
+        }
+    }
+
+    /// Step the simulation and send the simulated streams, an error once the client is gone
+    fn tick(&mut self) -> io::Result<()> {
+        let (Some(simulation), Some(socket)) =
+            (self.simulation.as_mut(), self.stream_socket.as_mut())
+        else {
+            return Ok(());
+        };
+        if self.streams.is_empty() {
+            return Ok(());
+        }
+        simulation.step();
+        let mut message = StreamUpdate::new();
+        for (&id, call) in &self.streams {
+            let mut result = ProcedureResult::new();
+            match simulation.answer(call) {
+                Ok(Some(value)) => result.set_value(value),
+                Ok(None) => continue,
+                Err(err) => result.set_error(error(call, err.to_string())),
+            }
+            let mut stream_result = StreamResult::new();
+            stream_result.set_id(id);
+            stream_result.set_result(result);
+            message.mut_results().push(stream_result);
+        }
+        message
+            .write_length_delimited_to_writer(socket)
+            .map_err(io::Error::other)
+    }
+}
+
+/// Answer the client's requests until it disconnects
+fn serve_rpc(mut socket: TcpStream, shared: &Mutex<Shared>) {
+    if handshake(&mut socket, ConnectionRequest_Type::RPC).is_err() {
+        return;
+    }
+    loop {
+        let request: Request = match CodedInputStream::new(&mut socket).read_message() {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let mut response = Response::new();
+        {
+            let mut shared = lock(shared);
+            for call in request.get_calls() {
+                let mut result = ProcedureResult::new();
+                match shared.answer(call) {
+                    Ok(value) => result.set_value(value),
+                    Err(description) => result.set_error(error(call, description)),
+                }
+                response.mut_results().push(result);
+            }
+            shared.flush();
+            shared.last_request = Some(Instant::now());
+        }
+        if response
+            .write_length_delimited_to_writer(&mut socket)
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+pub(crate) fn accept_stream(socket: &TcpStream) -> Result<(), Box<dyn Error>> {
+    let mut socket = socket.try_clone()?;
+    handshake(&mut socket, ConnectionRequest_Type::STREAM)
+}
+
+/// Read the connection request and accept it if it is of the expected type
+pub(crate) fn handshake(
+    socket: &mut TcpStream,
+    kind: ConnectionRequest_Type,
+) -> Result<(), Box<dyn Error>> {
+    let request: ConnectionRequest = CodedInputStream::new(socket).read_message()?;
+    let mut response = ConnectionResponse::new();
+    let accepted = request.get_field_type() == kind;
+    if accepted {
+        response.set_status(ConnectionResponse_Status::OK);
+        response.set_client_identifier(vec![0; 16]);
+    } else {
+        response.set_status(ConnectionResponse_Status::WRONG_TYPE);
+        response.set_message(format!("Expected a {kind:?} connection"));
+    }
+    response.write_length_delimited_to_writer(socket)?;
+    if accepted {
+        Ok(())
+    } else {
+        Err("Wrong connection type".into())
+    }
+}
+
+pub(crate) fn error(call: &ProcedureCall, description: String) -> krpc::Error {
+    let mut error = krpc::Error::new();
+    error.set_service(call.get_service().to_string());
+    error.set_name("MockError".to_string());
+    error.set_description(description);
+    error
+}
+
+pub(crate) fn name(call: &ProcedureCall) -> String {
+    format!("{}.{}", call.get_service(), call.get_procedure())
+}
+
+pub(crate) fn encode<T: RPCEncodable>(value: T) -> Vec<u8> {
+    value
+        .encode_to_bytes()
+        .expect("Could not encode the mock's value")
+}
+
+fn decode<T: RPCExtractable>(bytes: &[u8]) -> Result<T, Box<dyn Error>> {
+    Ok(T::extract_value(&mut CodedInputStream::from_bytes(bytes))?)
+}
+
+pub(crate) fn lock<T>(shared: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panicking test thread leaves the state as usable as it was
+    shared.lock().unwrap_or_else(|err| err.into_inner())
+}
@@ -0,0 +1,440 @@
+use std::{
+    error::Error,
+    f64::consts::{PI, TAU},
+};
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    services::space_center::Orbit,
+    vector::{Vec3D, Vector},
+};
+
+const KEPLER_TOLERANCE: f64 = 1e-12;
+const KEPLER_MAX_ITER: usize = 64;
+
+/// Keplerian elements of an orbit around a single primary body
+/// Angles are in radians, distances in metres and times in seconds of UT
+/// Hyperbolic orbits have a negative semi-major axis, as reported by kRPC
+/// State vectors are right-handed: x towards the reference direction, z towards the primary's north pole
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OrbitalElements {
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub lan: f64,
+    pub aop: f64,
+    pub mean_anomaly_at_epoch: f64,
+    pub epoch: f64,
+    pub mu: f64,
+}
+
+impl OrbitalElements {
+    /// Read every element of the orbit in a single batch
+    pub fn snapshot(client: &mut RPCClient, orbit: &Orbit) -> Result<Self, Box<dyn Error>> {
+        let (
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            lan,
+            aop,
+            mean_anomaly_at_epoch,
+            epoch,
+            body,
+        ) = batch_call_unwrap!(
+            client,
+            (
+                &orbit.get_semi_major_axis(),
+                &orbit.get_eccentricity(),
+                &orbit.get_inclination(),
+                &orbit.get_longitude_of_ascending_node(),
+                &orbit.get_argument_of_periapsis(),
+                &orbit.get_mean_anomaly_at_epoch(),
+                &orbit.get_epoch(),
+                &orbit.get_body(),
+            )
+        )?;
+        let mu = body.get_gravitational_parameter().mk_call(client)?;
+        Ok(Self {
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            lan,
+            aop,
+            mean_anomaly_at_epoch,
+            epoch,
+            mu,
+        })
+    }
+
+    /// Elements of the orbit passing through the given position and velocity at `ut`
+    pub fn from_state(mu: f64, position: Vec3D, velocity: Vec3D, ut: f64) -> Self {
+        let r = position.mag();
+        let h = position.cross(velocity);
+        let ecc_vec = position
+            .scale(velocity.dot(velocity) - mu / r)
+            .sub(velocity.scale(position.dot(velocity)))
+            .scale(1.0 / mu);
+        let eccentricity = ecc_vec.mag();
+        let semi_major_axis = 1.0 / (2.0 / r - velocity.dot(velocity) / mu);
+        let inclination = (h.2 / h.mag()).clamp(-1.0, 1.0).acos();
+
+        // Angles in the orbital plane are measured from the ascending node in the direction of motion
+        let node = (-h.1, h.0, 0.0);
+        let (lan, node) = if node.mag() < 1e-9 * h.mag() {
+            (0.0, (1.0, 0.0, 0.0))
+        } else {
+            (node.1.atan2(node.0).rem_euclid(TAU), node.normalize())
+        };
+        let normal = h.normalize().cross(node);
+        let aop = if eccentricity < 1e-12 {
+            0.0
+        } else {
+            ecc_vec.dot(normal).atan2(ecc_vec.dot(node)).rem_euclid(TAU)
+        };
+        let latitude_arg = position.dot(normal).atan2(position.dot(node));
+        let true_anomaly = (latitude_arg - aop + PI).rem_euclid(TAU) - PI;
+
+        let mut elements = Self {
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            lan,
+            aop,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: ut,
+            mu,
+        };
+        elements.mean_anomaly_at_epoch = elements
+            .mean_anomaly_from_eccentric(elements.eccentric_anomaly_from_true(true_anomaly));
+        elements
+    }
+
+    pub fn is_hyperbolic(&self) -> bool {
+        self.eccentricity >= 1.0
+    }
+
+    /// Mean angular motion in radians per second
+    pub fn mean_motion(&self) -> f64 {
+        (self.mu / self.semi_major_axis.abs().powi(3)).sqrt()
+    }
+
+    /// Orbital period, infinite for hyperbolic orbits
+    pub fn period(&self) -> f64 {
+        if self.is_hyperbolic() {
+            f64::INFINITY
+        } else {
+            TAU / self.mean_motion()
+        }
+    }
+
+    pub fn periapsis(&self) -> f64 {
+        self.semi_major_axis * (1.0 - self.eccentricity)
+    }
+
+    /// Apoapsis radius, negative for hyperbolic orbits
+    pub fn apoapsis(&self) -> f64 {
+        self.semi_major_axis * (1.0 + self.eccentricity)
+    }
+
+    pub fn semi_latus_rectum(&self) -> f64 {
+        self.semi_major_axis * (1.0 - self.eccentricity.powi(2))
+    }
+
+    /// Mean anomaly at the given UT, wrapped to [0, 2pi) for closed orbits
+    pub fn mean_anomaly_at_ut(&self, ut: f64) -> f64 {
+        let mean = self.mean_anomaly_at_epoch + self.mean_motion() * (ut - self.epoch);
+        if self.is_hyperbolic() {
+            mean
+        } else {
+            mean.rem_euclid(TAU)
+        }
+    }
+
+    /// Solve Kepler's equation for the eccentric (or hyperbolic) anomaly
+    pub fn eccentric_anomaly_from_mean(&self, mean: f64) -> f64 {
+        let e = self.eccentricity;
+        if self.is_hyperbolic() {
+            let mut h = (mean / e).asinh();
+            for _ in 0..KEPLER_MAX_ITER {
+                let step = (e * h.sinh() - h - mean) / (e * h.cosh() - 1.0);
+                h -= step;
+                if step.abs() < KEPLER_TOLERANCE {
+                    break;
+                }
+            }
+            h
+        } else {
+            let mut ecc = if e > 0.8 { PI } else { mean };
+            for _ in 0..KEPLER_MAX_ITER {
+                let step = (ecc - e * ecc.sin() - mean) / (1.0 - e * ecc.cos());
+                ecc -= step;
+                if step.abs() < KEPLER_TOLERANCE {
+                    break;
+                }
+            }
+            ecc
+        }
+    }
+
+    pub fn mean_anomaly_from_eccentric(&self, ecc: f64) -> f64 {
+        let e = self.eccentricity;
+        if self.is_hyperbolic() {
+            e * ecc.sinh() - ecc
+        } else {
+            ecc - e * ecc.sin()
+        }
+    }
+
+    pub fn true_anomaly_from_eccentric(&self, ecc: f64) -> f64 {
+        let e = self.eccentricity;
+        if self.is_hyperbolic() {
+            2.0 * (((e + 1.0) / (e - 1.0)).sqrt() * (ecc / 2.0).tanh()).atan()
+        } else {
+            let true_anomaly = 2.0
+                * ((1.0 + e).sqrt() * (ecc / 2.0).sin())
+                    .atan2((1.0 - e).sqrt() * (ecc / 2.0).cos());
+            true_anomaly.rem_euclid(TAU)
+        }
+    }
+
+    pub fn eccentric_anomaly_from_true(&self, true_anomaly: f64) -> f64 {
+        let e = self.eccentricity;
+        if self.is_hyperbolic() {
+            2.0 * (((e - 1.0) / (e + 1.0)).sqrt() * (true_anomaly / 2.0).tan()).atanh()
+        } else {
+            let ecc = 2.0
+                * ((1.0 - e).sqrt() * (true_anomaly / 2.0).sin())
+                    .atan2((1.0 + e).sqrt() * (true_anomaly / 2.0).cos());
+            ecc.rem_euclid(TAU)
+        }
+    }
+
+    pub fn true_anomaly_at_ut(&self, ut: f64) -> f64 {
+        let mean = self.mean_anomaly_at_ut(ut);
+        self.true_anomaly_from_eccentric(self.eccentric_anomaly_from_mean(mean))
+    }
+
+    /// First UT at or after `after` at which the orbit reaches the true anomaly
+    /// Hyperbolic orbits pass each anomaly once, so the result may precede `after`
+    pub fn ut_at_true_anomaly(&self, true_anomaly: f64, after: f64) -> f64 {
+        let mean = self.mean_anomaly_from_eccentric(self.eccentric_anomaly_from_true(true_anomaly));
+        let ut = self.epoch + (mean - self.mean_anomaly_at_epoch) / self.mean_motion();
+        if self.is_hyperbolic() {
+            ut
+        } else {
+            let period = self.period();
+            ut + ((after - ut) / period).ceil() * period
+        }
+    }
+
+    pub fn time_to_periapsis(&self, ut: f64) -> f64 {
+        self.ut_at_true_anomaly(0.0, ut) - ut
+    }
+
+    /// Time until apoapsis, infinite for hyperbolic orbits
+    pub fn time_to_apoapsis(&self, ut: f64) -> f64 {
+        if self.is_hyperbolic() {
+            f64::INFINITY
+        } else {
+            self.ut_at_true_anomaly(PI, ut) - ut
+        }
+    }
+
+    pub fn radius_at_true_anomaly(&self, true_anomaly: f64) -> f64 {
+        self.semi_latus_rectum() / (1.0 + self.eccentricity * true_anomaly.cos())
+    }
+
+    pub fn radius_at(&self, ut: f64) -> f64 {
+        self.radius_at_true_anomaly(self.true_anomaly_at_ut(ut))
+    }
+
+    /// Orbital speed at the given radius from the vis-viva equation
+    pub fn speed_at_radius(&self, radius: f64) -> f64 {
+        (self.mu * ((2.0 / radius) - (1.0 / self.semi_major_axis))).sqrt()
+    }
+
+    /// Position and velocity relative to the primary at the given UT
+    pub fn state_at(&self, ut: f64) -> (Vec3D, Vec3D) {
+        let true_anomaly = self.true_anomaly_at_ut(ut);
+        let (sin_nu, cos_nu) = true_anomaly.sin_cos();
+        let r = self.radius_at_true_anomaly(true_anomaly);
+        let v = (self.mu / self.semi_latus_rectum()).sqrt();
+        let position = self.perifocal_to_inertial(r * cos_nu, r * sin_nu);
+        let velocity = self.perifocal_to_inertial(-v * sin_nu, v * (self.eccentricity + cos_nu));
+        (position, velocity)
+    }
+
+    /// Velocity along the outgoing asymptote, only defined for hyperbolic orbits
+    pub fn excess_velocity(&self) -> Option<Vec3D> {
+        if !self.is_hyperbolic() {
+            return None;
+        }
+        let (sin, cos) = (-1.0 / self.eccentricity).acos().sin_cos();
+        let speed = (-self.mu / self.semi_major_axis).sqrt();
+        Some(self.perifocal_to_inertial(cos, sin).scale(speed))
+    }
+
+    /// Unit vector along the angular momentum
+    pub fn normal(&self) -> Vec3D {
+        self.perifocal_to_inertial(1.0, 0.0)
+            .cross(self.perifocal_to_inertial(0.0, 1.0))
+    }
+
+    /// True anomaly at which the orbit passes over the direction, projected into the orbital plane
+    pub fn true_anomaly_of(&self, direction: Vec3D) -> f64 {
+        let along = direction.dot(self.perifocal_to_inertial(0.0, 1.0));
+        along
+            .atan2(direction.dot(self.perifocal_to_inertial(1.0, 0.0)))
+            .rem_euclid(TAU)
+    }
+
+    /// Split a burn at the given UT into the prograde, normal and radial components used by maneuver nodes
+    pub fn node_components(&self, ut: f64, delta_v: Vec3D) -> Vec3D {
+        let (position, velocity) = self.state_at(ut);
+        let prograde = velocity.normalize();
+        let normal = position.cross(velocity).normalize();
+        let radial = prograde.cross(normal);
+        (
+            delta_v.dot(prograde),
+            delta_v.dot(normal),
+            delta_v.dot(radial),
+        )
+    }
+
+    /// Rotate a vector from the orbital plane (x towards periapsis) into the reference frame
+    fn perifocal_to_inertial(&self, x: f64, y: f64) -> Vec3D {
+        let (sin_lan, cos_lan) = self.lan.sin_cos();
+        let (sin_aop, cos_aop) = self.aop.sin_cos();
+        let (sin_inc, cos_inc) = self.inclination.sin_cos();
+        let p = (
+            cos_lan * cos_aop - sin_lan * sin_aop * cos_inc,
+            sin_lan * cos_aop + cos_lan * sin_aop * cos_inc,
+            sin_aop * sin_inc,
+        );
+        let q = (
+            -cos_lan * sin_aop - sin_lan * cos_aop * cos_inc,
+            -sin_lan * sin_aop + cos_lan * cos_aop * cos_inc,
+            cos_aop * sin_inc,
+        );
+        (x * p.0 + y * q.0, x * p.1 + y * q.1, x * p.2 + y * q.2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::{FRAC_PI_2, PI, TAU};
+
+    use crate::{
+        orbit::OrbitalElements,
+        vector::{Vec3D, Vector},
+    };
+
+    const KERBIN_MU: f64 = 3.5316e12;
+
+    fn elements(semi_major_axis: f64, eccentricity: f64, inclination: f64) -> OrbitalElements {
+        OrbitalElements {
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            lan: 0.6,
+            aop: 1.1,
+            mean_anomaly_at_epoch: 0.3,
+            epoch: 1000.0,
+            mu: KERBIN_MU,
+        }
+    }
+
+    fn energy(orbit: &OrbitalElements, (r, v): (Vec3D, Vec3D)) -> f64 {
+        v.dot(v) / 2.0 - orbit.mu / r.mag()
+    }
+
+    #[test]
+    fn test_circular_state() {
+        let orbit = OrbitalElements {
+            lan: 0.0,
+            aop: 0.0,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            ..elements(700_000.0, 0.0, 0.0)
+        };
+        let (r, v) = orbit.state_at(orbit.period() / 4.0);
+        assert!(r.0.abs() < 1e-6 && (r.1 - 700_000.0).abs() < 1e-6 && r.2 == 0.0);
+        assert!((v.0 + (KERBIN_MU / 700_000.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_anomaly_round_trip() {
+        for e in [0.0, 0.1, 0.5, 0.95, 1.2, 3.0] {
+            let orbit = elements(if e < 1.0 { 1.0e6 } else { -1.0e6 }, e, 0.4);
+            for mean in [-2.0_f64, -0.5, 0.0, 0.7, 2.5, 3.1] {
+                let mean = if orbit.is_hyperbolic() {
+                    mean
+                } else {
+                    mean.rem_euclid(TAU)
+                };
+                let ecc = orbit.eccentric_anomaly_from_mean(mean);
+                assert!((orbit.mean_anomaly_from_eccentric(ecc) - mean).abs() < 1e-9);
+                let true_anomaly = orbit.true_anomaly_from_eccentric(ecc);
+                assert!((orbit.eccentric_anomaly_from_true(true_anomaly) - ecc).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_energy_and_momentum_conserved() {
+        for orbit in [elements(1.2e6, 0.3, 0.5), elements(-2.0e6, 1.5, 2.0)] {
+            let expected = -orbit.mu / (2.0 * orbit.semi_major_axis);
+            let h = (orbit.mu * orbit.semi_latus_rectum()).sqrt();
+            for ut in [0.0, 1000.0, 1234.5, 5000.0] {
+                let state = orbit.state_at(ut);
+                assert!((energy(&orbit, state) / expected - 1.0).abs() < 1e-9);
+                let (r, v) = state;
+                let momentum = r.cross(v);
+                assert!((momentum.mag() / h - 1.0).abs() < 1e-9);
+                assert!((momentum.2 / h - orbit.inclination.cos()).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_state_round_trip() {
+        for orbit in [
+            elements(1.2e6, 0.3, 0.5),
+            elements(-2.0e6, 1.5, 2.0),
+            elements(9.0e5, 0.01, 3.0),
+        ] {
+            let (r, v) = orbit.state_at(4321.0);
+            let copy = OrbitalElements::from_state(orbit.mu, r, v, 4321.0);
+            assert!((copy.semi_major_axis / orbit.semi_major_axis - 1.0).abs() < 1e-9);
+            assert!((copy.eccentricity - orbit.eccentricity).abs() < 1e-9);
+            assert!((copy.inclination - orbit.inclination).abs() < 1e-9);
+            for ut in [0.0, 4321.0, 9000.0] {
+                let (expected, _) = orbit.state_at(ut);
+                let (actual, _) = copy.state_at(ut);
+                assert!(actual.sub(expected).mag() < 1e-6 * expected.mag());
+            }
+        }
+    }
+
+    #[test]
+    fn test_node_components() {
+        let orbit = elements(1.0e6, 0.0, 0.7);
+        let (r, v) = orbit.state_at(0.0);
+        let (prograde, normal, radial) = orbit.node_components(0.0, v.normalize().scale(10.0));
+        assert!((prograde - 10.0).abs() < 1e-9 && normal.abs() < 1e-9 && radial.abs() < 1e-9);
+        let (prograde, normal, radial) = orbit.node_components(0.0, r.normalize());
+        assert!(prograde.abs() < 1e-9 && normal.abs() < 1e-9 && (radial - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ut_at_true_anomaly() {
+        let orbit = elements(1.0e6, 0.2, 0.1);
+        let ut = orbit.ut_at_true_anomaly(FRAC_PI_2, 50_000.0);
+        assert!(ut >= 50_000.0 && ut < 50_000.0 + orbit.period());
+        assert!((orbit.true_anomaly_at_ut(ut) - FRAC_PI_2).abs() < 1e-9);
+        let apo = orbit.time_to_apoapsis(0.0);
+        assert!((orbit.true_anomaly_at_ut(apo) - PI).abs() < 1e-9);
+        assert!((orbit.radius_at(apo) - orbit.apoapsis()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,226 @@
+use std::error::Error;
+
+use crate::{
+    error,
+    vector::{Vec3D, Vector},
+};
+
+/// Largest burnout miss accepted, in units of `MISS_SCALE`
+const PEG_TOLERANCE: f64 = 1e-3;
+const PEG_MAX_ITER: usize = 20;
+/// Integration steps used to predict burnout
+const PEG_STEPS: usize = 100;
+/// Burnout miss that counts as one unit: 1 km of radius and 10 m/s of speed
+const MISS_SCALE: Vec3D = (1000.0, 10.0, 10.0);
+
+/// Vehicle state in the orbital plane, velocities relative to the non-rotating body frame
+#[derive(Debug, Copy, Clone)]
+pub struct PegState {
+    pub radius: f64,
+    pub vertical_speed: f64,
+    pub horizontal_speed: f64,
+    /// Thrust over mass
+    pub acceleration: f64,
+    pub exhaust_velocity: f64,
+    pub mu: f64,
+}
+
+impl PegState {
+    /// Time to burn the whole vehicle mass at the current flow rate
+    fn tau(&self) -> f64 {
+        self.exhaust_velocity / self.acceleration
+    }
+
+    /// Burnout (radius, vertical speed, horizontal speed) after burning `time` seconds at full thrust
+    fn fly(&self, a: f64, b: f64, time: f64) -> Vec3D {
+        let tau = self.tau();
+        let derivative = |t: f64, (radius, vertical, horizontal): Vec3D| {
+            let acceleration = self.acceleration / (1.0 - t / tau);
+            let sin = (a + b * t).clamp(-1.0, 1.0);
+            let cos = (1.0 - sin.powi(2)).sqrt();
+            (
+                vertical,
+                -self.mu / radius.powi(2) + horizontal.powi(2) / radius + acceleration * sin,
+                -vertical * horizontal / radius + acceleration * cos,
+            )
+        };
+        let h = time / PEG_STEPS as f64;
+        let mut y = (self.radius, self.vertical_speed, self.horizontal_speed);
+        for i in 0..PEG_STEPS {
+            let t = i as f64 * h;
+            let k1 = derivative(t, y);
+            let k2 = derivative(t + h / 2.0, y.add(k1.scale(h / 2.0)));
+            let k3 = derivative(t + h / 2.0, y.add(k2.scale(h / 2.0)));
+            let k4 = derivative(t + h, y.add(k3.scale(h)));
+            y = y.add(
+                k1.add(k2.scale(2.0))
+                    .add(k3.scale(2.0))
+                    .add(k4)
+                    .scale(h / 6.0),
+            );
+        }
+        y
+    }
+}
+
+/// Burnout conditions to reach
+#[derive(Debug, Copy, Clone)]
+pub struct PegTarget {
+    pub radius: f64,
+    pub vertical_speed: f64,
+    pub horizontal_speed: f64,
+}
+
+impl PegTarget {
+    /// Burnout at the periapsis of the orbit between the two radii
+    pub fn insertion(mu: f64, periapsis: f64, apoapsis: f64) -> Self {
+        let semi_major_axis = (periapsis + apoapsis) / 2.0;
+        Self {
+            radius: periapsis,
+            vertical_speed: 0.0,
+            horizontal_speed: (mu * (2.0 / periapsis - 1.0 / semi_major_axis)).sqrt(),
+        }
+    }
+
+    fn miss(&self, burnout: Vec3D) -> Vec3D {
+        let (radius, vertical, horizontal) =
+            burnout.sub((self.radius, self.vertical_speed, self.horizontal_speed));
+        (
+            radius / MISS_SCALE.0,
+            vertical / MISS_SCALE.1,
+            horizontal / MISS_SCALE.2,
+        )
+    }
+}
+
+/// Closed-loop guidance with PEG's linear steering law on the vertical thrust component
+/// The sine of the pitch above the horizon is `a + b * t`, burning at full thrust for `time_to_go`
+/// The constants are found by shooting on the predicted burnout instead of PEG's series expansion
+/// Only the in-plane motion is guided, the heading has to be steered into the target plane separately
+#[derive(Debug, Copy, Clone)]
+pub struct Peg {
+    pub a: f64,
+    pub b: f64,
+    pub time_to_go: f64,
+}
+
+impl Peg {
+    /// First guess burning for the horizontal speed change while pitching down from 17 degrees
+    pub fn new(state: &PegState, target: &PegTarget) -> Self {
+        let dv = (target.horizontal_speed - state.horizontal_speed).abs();
+        let time_to_go = 1.1 * state.tau() * (1.0 - (-dv / state.exhaust_velocity).exp());
+        Self {
+            a: 0.3,
+            b: -0.3 / time_to_go,
+            time_to_go,
+        }
+    }
+
+    /// Re-solve the steering constants, `elapsed` seconds after the last solution
+    /// On error the last solution is kept, shifted by `elapsed`
+    pub fn update(
+        &mut self,
+        state: &PegState,
+        target: &PegTarget,
+        elapsed: f64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.a += self.b * elapsed;
+        self.time_to_go -= elapsed;
+        let tau = state.tau();
+        let mut x = (self.a, self.b, self.time_to_go);
+        for _ in 0..PEG_MAX_ITER {
+            if !(x.2 > 0.0 && x.2 < tau) {
+                break;
+            }
+            let miss = target.miss(state.fly(x.0, x.1, x.2));
+            if miss.0.abs().max(miss.1.abs()).max(miss.2.abs()) < PEG_TOLERANCE {
+                (self.a, self.b, self.time_to_go) = x;
+                return Ok(());
+            }
+            // Newton step with a finite difference jacobian over (a, b, time to go)
+            let column = |dx: Vec3D, size: f64| {
+                let y = x.add(dx);
+                target
+                    .miss(state.fly(y.0, y.1, y.2))
+                    .sub(miss)
+                    .scale(1.0 / size)
+            };
+            let d_a = column((1e-4, 0.0, 0.0), 1e-4);
+            let d_b = column((0.0, 1e-6, 0.0), 1e-6);
+            let d_t = column((0.0, 0.0, 1e-2), 1e-2);
+            let det = d_a.dot(d_b.cross(d_t));
+            if det.abs() < 1e-12 {
+                break;
+            }
+            let f = miss.neg();
+            let step = (
+                f.dot(d_b.cross(d_t)) / det,
+                d_a.dot(f.cross(d_t)) / det,
+                d_a.dot(d_b.cross(f)) / det,
+            );
+            // Keep each change of time to go within a fifth of it
+            let damping = (step.2.abs() / (0.2 * x.2)).max(1.0);
+            x = x.add(step.scale(1.0 / damping));
+        }
+        Err(error::Error::Numerical("Guidance did not converge".to_string()).into())
+    }
+
+    /// Pitch above the horizon in radians, `elapsed` seconds after the last solution
+    pub fn pitch(&self, elapsed: f64) -> f64 {
+        (self.a + self.b * elapsed).clamp(-1.0, 1.0).asin()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peg::{Peg, PegState, PegTarget};
+
+    #[test]
+    fn test_peg_reaches_circular_orbit() {
+        let mu = 3.5316e12;
+        let target = PegTarget::insertion(mu, 680_000.0, 680_000.0);
+        let (thrust, ve) = (40_000.0, 340.0 * 9.80665);
+        let (mut radius, mut vertical_speed, mut horizontal_speed, mut mass) =
+            (650_000.0, 200.0, 1800.0, 10_000.0);
+        let state = |radius, vertical_speed, horizontal_speed, mass| PegState {
+            radius,
+            vertical_speed,
+            horizontal_speed,
+            acceleration: thrust / mass,
+            exhaust_velocity: ve,
+            mu,
+        };
+        let start = state(radius, vertical_speed, horizontal_speed, mass);
+        let mut peg = Peg::new(&start, &target);
+        peg.update(&start, &target, 0.0).unwrap();
+
+        // Re-solve every second until the last ten seconds
+        let dt = 0.02;
+        let mut since_update = 0.0;
+        while since_update < peg.time_to_go {
+            if since_update >= 1.0 && peg.time_to_go - since_update > 10.0 {
+                let now = state(radius, vertical_speed, horizontal_speed, mass);
+                peg.update(&now, &target, since_update).unwrap();
+                since_update = 0.0;
+            }
+            let pitch = peg.pitch(since_update);
+            let acceleration = thrust / mass;
+            let vertical = -mu / radius.powi(2)
+                + horizontal_speed.powi(2) / radius
+                + acceleration * pitch.sin();
+            let horizontal =
+                -vertical_speed * horizontal_speed / radius + acceleration * pitch.cos();
+            radius += vertical_speed * dt;
+            vertical_speed += vertical * dt;
+            horizontal_speed += horizontal * dt;
+            mass -= thrust / ve * dt;
+            since_update += dt;
+        }
+        assert!((radius - target.radius).abs() < 500.0, "{radius}");
+        assert!(vertical_speed.abs() < 2.0, "{vertical_speed}");
+        assert!(
+            (horizontal_speed - target.horizontal_speed).abs() < 2.0,
+            "{horizontal_speed}"
+        );
+    }
+}
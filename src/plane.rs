@@ -0,0 +1,175 @@
+use std::error::Error;
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    orbit::OrbitalElements,
+    services::space_center::{self, Node, Orbit, Vessel},
+    vector::{Vec3D, Vector},
+};
+
+/// Burn at one of the nodes between the ship's and the target's planes
+#[derive(Debug, Copy, Clone)]
+pub struct PlaneChange {
+    pub ut: f64,
+    /// Burn as (prograde, normal, radial)
+    pub burn: Vec3D,
+    /// Burn in the same axes as the orbits' state vectors
+    pub delta_v: Vec3D,
+    /// Angle between the two planes in radians
+    pub relative_inclination: f64,
+    /// Whether the burn is at the ascending node, where the ship crosses to the north of the target's plane
+    pub ascending: bool,
+}
+
+/// Plan and add the node matching the ship's plane to the target orbit's, at the cheaper node
+/// With an apoapsis altitude, the same burn also raises or lowers the apoapsis to it
+pub fn plane(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    target: &Orbit,
+    apoapsis: Option<f64>,
+) -> Result<Node, Box<dyn Error>> {
+    let ship_orbit = ship.get_orbit().mk_call(client)?;
+    let (ship_body, target_body, ut) = batch_call_unwrap!(
+        client,
+        (
+            &ship_orbit.get_body(),
+            &target.get_body(),
+            &space_center::get_ut(),
+        )
+    )?;
+    let (ship_body, target_body, radius) = batch_call_unwrap!(
+        client,
+        (
+            &ship_body.get_name(),
+            &target_body.get_name(),
+            &ship_body.get_equatorial_radius(),
+        )
+    )?;
+    if ship_body != target_body {
+        return Err(format!("Target orbits {target_body}, not {ship_body}").into());
+    }
+    let ship_orbit = OrbitalElements::snapshot(client, &ship_orbit)?;
+    let target = OrbitalElements::snapshot(client, target)?;
+
+    let change = plan(&ship_orbit, &target, ut, apoapsis.map(|alt| radius + alt))?;
+    println!("Plane change: {change:?}");
+    let (prograde, normal, radial) = change.burn;
+    let control = ship.get_control().mk_call(client)?;
+    let node = control
+        .add_node(change.ut, prograde as f32, normal as f32, radial as f32)
+        .mk_call(client)?;
+    Ok(node)
+}
+
+/// Cheaper of the burns at the ascending and descending nodes after `after`
+/// The burn turns the horizontal velocity into the target's plane and direction of motion
+/// With an apoapsis radius it also sets the horizontal speed to reach it, keeping the radial speed
+pub fn plan(
+    ship: &OrbitalElements,
+    target: &OrbitalElements,
+    after: f64,
+    apoapsis: Option<f64>,
+) -> Result<PlaneChange, Box<dyn Error>> {
+    if ship.is_hyperbolic() {
+        return Err("Ship orbit must be closed".into());
+    }
+    let relative_inclination = ship.normal().vang(target.normal());
+    let line = target.normal().cross(ship.normal());
+    if line.mag() < 1e-9 {
+        return Err("Orbits are already coplanar".into());
+    }
+
+    let burn_at = |direction: Vec3D, ascending: bool| -> Result<PlaneChange, Box<dyn Error>> {
+        let ut = ship.ut_at_true_anomaly(ship.true_anomaly_of(direction), after);
+        let (position, velocity) = ship.state_at(ut);
+        let up = position.normalize();
+        let radial_speed = velocity.dot(up);
+        let horizontal_speed = match apoapsis {
+            Some(apoapsis) => raise_speed(ship.mu, position.mag(), radial_speed, apoapsis)?,
+            None => velocity.reject(up).mag(),
+        };
+        let along = target.normal().cross(up).normalize();
+        let delta_v = up
+            .scale(radial_speed)
+            .add(along.scale(horizontal_speed))
+            .sub(velocity);
+        Ok(PlaneChange {
+            ut,
+            burn: ship.node_components(ut, delta_v),
+            delta_v,
+            relative_inclination,
+            ascending,
+        })
+    };
+    let ascending = burn_at(line, true)?;
+    let descending = burn_at(line.neg(), false)?;
+    if descending.delta_v.mag() < ascending.delta_v.mag() {
+        Ok(descending)
+    } else {
+        Ok(ascending)
+    }
+}
+
+/// Horizontal speed at `radius` that reaches the apoapsis radius, keeping the radial speed
+fn raise_speed(
+    mu: f64,
+    radius: f64,
+    radial_speed: f64,
+    apoapsis: f64,
+) -> Result<f64, Box<dyn Error>> {
+    if apoapsis <= radius {
+        return Err("Apoapsis must be above the node".into());
+    }
+    // Energy and angular momentum conserved between the node and the apoapsis
+    let squared = (2.0 * mu * (1.0 / radius - 1.0 / apoapsis) - radial_speed.powi(2))
+        / (1.0 - (radius / apoapsis).powi(2));
+    if squared <= 0.0 {
+        return Err("Radial speed alone climbs past the apoapsis".into());
+    }
+    Ok(squared.sqrt())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{orbit::OrbitalElements, plane::plan, vector::Vector};
+
+    fn orbit(eccentricity: f64, inclination: f64, lan: f64) -> OrbitalElements {
+        OrbitalElements {
+            semi_major_axis: 750_000.0,
+            eccentricity,
+            inclination,
+            lan,
+            aop: 0.4,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        }
+    }
+
+    #[test]
+    fn test_plane_change_matches_target_plane() {
+        let ship = orbit(0.05, 0.1, 0.3);
+        let target = orbit(0.0, 0.5, 2.0);
+        let change = plan(&ship, &target, 100.0, None).unwrap();
+        assert!(change.ut >= 100.0);
+        assert!((change.relative_inclination - ship.normal().vang(target.normal())).abs() < 1e-12);
+
+        let (position, velocity) = ship.state_at(change.ut);
+        let after = velocity.add(change.delta_v);
+        assert!(position.dot(target.normal()).abs() < 1e-3);
+        assert!(after.dot(target.normal()).abs() < 1e-9);
+        assert!((after.mag() - velocity.mag()).abs() < 1e-9);
+        assert!((change.burn.mag() - change.delta_v.mag()).abs() < 1e-9);
+
+        // Raising the apoapsis in the same burn
+        let ship_apoapsis = ship.apoapsis() + 50_000.0;
+        let raised = plan(&ship, &target, 100.0, Some(ship_apoapsis)).unwrap();
+        let (position, velocity) = ship.state_at(raised.ut);
+        let after = velocity.add(raised.delta_v);
+        let result = OrbitalElements::from_state(ship.mu, position, after, raised.ut);
+        assert!((result.apoapsis() - ship_apoapsis).abs() < 1e-3);
+        assert!(result.normal().vang(target.normal()) < 1e-9);
+    }
+}
@@ -0,0 +1,405 @@
+use std::{error::Error, fs::File, io::Write, path::Path};
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    lambert::{self, Transfer},
+    orbit::OrbitalElements,
+    services::space_center::CelestialBody,
+    vector::Vector,
+};
+
+/// Planet taking part in an interplanetary transfer
+#[derive(Debug, Copy, Clone)]
+pub struct Planet {
+    pub orbit: OrbitalElements,
+    pub mu: f64,
+    pub radius: f64,
+    pub atmosphere_depth: f64,
+}
+
+impl Planet {
+    pub fn snapshot(client: &mut RPCClient, body: &CelestialBody) -> Result<Self, Box<dyn Error>> {
+        let (mu, radius, atmosphere_depth, orbit) = batch_call_unwrap!(
+            client,
+            (
+                &body.get_gravitational_parameter(),
+                &body.get_equatorial_radius(),
+                &body.get_atmosphere_depth(),
+                &body.get_orbit(),
+            )
+        )?;
+        let orbit = OrbitalElements::snapshot(client, &orbit)?;
+        Ok(Self {
+            orbit,
+            mu,
+            radius,
+            atmosphere_depth,
+        })
+    }
+
+    /// Radius of the lowest circular orbit clear of the atmosphere
+    pub fn low_orbit(&self) -> f64 {
+        self.radius + self.atmosphere_depth + 10000.0
+    }
+}
+
+/// Burn from a circular orbit of the given radius onto a hyperbola with excess speed v_inf
+/// The same burn captures an arriving hyperbola into that circular orbit
+pub fn excess_velocity_burn(mu: f64, radius: f64, v_inf: f64) -> f64 {
+    (v_inf.powi(2) + 2.0 * mu / radius).sqrt() - (mu / radius).sqrt()
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Point {
+    pub ejection_dv: f64,
+    pub capture_dv: f64,
+}
+
+impl Point {
+    pub fn total(&self) -> f64 {
+        self.ejection_dv + self.capture_dv
+    }
+}
+
+/// Grid of transfer costs, indexed by departure and then by time of flight
+pub struct Porkchop {
+    pub departures: Vec<f64>,
+    pub flight_times: Vec<f64>,
+    pub grid: Vec<Vec<Option<Point>>>,
+}
+
+/// Evenly spaced values from start to end inclusive
+pub fn steps(start: f64, end: f64, count: usize) -> Vec<f64> {
+    if count < 2 {
+        return vec![start];
+    }
+    let step = (end - start) / (count - 1) as f64;
+    (0..count).map(|i| start + step * i as f64).collect()
+}
+
+/// Synodic period of two orbits around the same primary
+pub fn synodic_period(a: &OrbitalElements, b: &OrbitalElements) -> f64 {
+    1.0 / (1.0 / a.period() - 1.0 / b.period()).abs()
+}
+
+/// Time of flight of a Hohmann transfer between the two orbits
+pub fn hohmann_time(a: &OrbitalElements, b: &OrbitalElements) -> f64 {
+    let semi = (a.semi_major_axis + b.semi_major_axis) / 2.0;
+    std::f64::consts::PI * (semi.powi(3) / a.mu).sqrt()
+}
+
+/// Compute ejection and capture delta-v for every departure and time of flight
+/// Ejection starts from a circular orbit of radius `parking`, capture ends in one of radius `capture`
+pub fn sweep(
+    origin: &Planet,
+    destination: &Planet,
+    parking: f64,
+    capture: f64,
+    departures: Vec<f64>,
+    flight_times: Vec<f64>,
+) -> Porkchop {
+    let grid = departures
+        .iter()
+        .map(|&departure| {
+            flight_times
+                .iter()
+                .map(|&flight_time| {
+                    let transfer = lambert::plan(
+                        &origin.orbit,
+                        &destination.orbit,
+                        departure,
+                        departure + flight_time,
+                        0,
+                    )
+                    .ok()?;
+                    Some(point(origin, destination, parking, capture, &transfer))
+                })
+                .collect()
+        })
+        .collect();
+    Porkchop {
+        departures,
+        flight_times,
+        grid,
+    }
+}
+
+fn point(
+    origin: &Planet,
+    destination: &Planet,
+    parking: f64,
+    capture: f64,
+    transfer: &Transfer,
+) -> Point {
+    let (_, origin_velocity) = origin.orbit.state_at(transfer.departure_ut);
+    let departure_excess = transfer.departure_velocity.sub(origin_velocity).mag();
+    let arrival_excess = transfer.arrival_excess.mag();
+    Point {
+        ejection_dv: excess_velocity_burn(origin.mu, parking, departure_excess),
+        capture_dv: excess_velocity_burn(destination.mu, capture, arrival_excess),
+    }
+}
+
+impl Porkchop {
+    /// Cheapest transfer as (departure UT, time of flight, cost)
+    pub fn best(&self) -> Option<(f64, f64, Point)> {
+        self.cells()
+            .min_by(|a, b| a.2.total().total_cmp(&b.2.total()))
+    }
+
+    fn cells(&self) -> impl Iterator<Item = (f64, f64, Point)> + '_ {
+        self.grid.iter().enumerate().flat_map(move |(i, row)| {
+            row.iter().enumerate().filter_map(move |(j, point)| {
+                point
+                    .filter(|p| p.total().is_finite())
+                    .map(|p| (self.departures[i], self.flight_times[j], p))
+            })
+        })
+    }
+
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(path)?;
+        writeln!(
+            file,
+            "departure_ut,time_of_flight,ejection_dv,capture_dv,total_dv"
+        )?;
+        for (departure, flight_time, point) in self.cells() {
+            writeln!(
+                file,
+                "{departure},{flight_time},{},{},{}",
+                point.ejection_dv,
+                point.capture_dv,
+                point.total()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Heatmap of total delta-v, departure on the x axis and time of flight on the y axis
+    /// Colours run from green at the cheapest transfer to red at three times its cost
+    pub fn write_svg(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        const CELL: usize = 6;
+        const MARGIN: usize = 60;
+        let min = self
+            .best()
+            .ok_or("No feasible transfer in the sweep")?
+            .2
+            .total();
+        let max = 3.0 * min;
+        let width = self.departures.len() * CELL;
+        let height = self.flight_times.len() * CELL;
+
+        let mut file = File::create(path)?;
+        writeln!(
+            file,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="10">"#,
+            width + 2 * MARGIN,
+            height + 2 * MARGIN
+        )?;
+        writeln!(
+            file,
+            r#"<rect x="{MARGIN}" y="{MARGIN}" width="{width}" height="{height}" fill="black"/>"#
+        )?;
+        for (i, row) in self.grid.iter().enumerate() {
+            for (j, point) in row.iter().enumerate() {
+                let Some(point) = point.filter(|p| p.total().is_finite()) else {
+                    continue;
+                };
+                // Free transfers leave no range to spread the colours over
+                let fraction = match max - min {
+                    range if range > 0.0 => ((point.total() - min) / range).clamp(0.0, 1.0),
+                    _ => 0.0,
+                };
+                writeln!(
+                    file,
+                    r#"<rect x="{}" y="{}" width="{CELL}" height="{CELL}" fill="hsl({:.0},90%,50%)"><title>{:.0} m/s</title></rect>"#,
+                    MARGIN + i * CELL,
+                    MARGIN + height - (j + 1) * CELL,
+                    120.0 * (1.0 - fraction),
+                    point.total()
+                )?;
+            }
+        }
+        if let (Some(first), Some(last)) = (self.departures.first(), self.departures.last()) {
+            writeln!(
+                file,
+                r#"<text x="{MARGIN}" y="{}">{first:.0}</text><text x="{}" y="{}" text-anchor="end">{last:.0}</text><text x="{}" y="{}" text-anchor="middle">departure UT (s)</text>"#,
+                MARGIN + height + 15,
+                MARGIN + width,
+                MARGIN + height + 15,
+                MARGIN + width / 2,
+                MARGIN + height + 35
+            )?;
+        }
+        if let (Some(first), Some(last)) = (self.flight_times.first(), self.flight_times.last()) {
+            writeln!(
+                file,
+                r#"<text x="{}" y="{}" text-anchor="end">{first:.0}</text><text x="{}" y="{MARGIN}" text-anchor="end">{last:.0}</text><text x="15" y="{}" transform="rotate(-90 15 {})" text-anchor="middle">time of flight (s)</text>"#,
+                MARGIN - 5,
+                MARGIN + height,
+                MARGIN - 5,
+                MARGIN + height / 2,
+                MARGIN + height / 2
+            )?;
+        }
+        writeln!(
+            file,
+            r#"<text x="{MARGIN}" y="{}">{min:.0} m/s (green) to {max:.0} m/s (red)</text>"#,
+            MARGIN - 20
+        )?;
+        writeln!(file, "</svg>")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, f64::consts::PI, fs};
+
+    use crate::{
+        orbit::OrbitalElements,
+        porkchop::{
+            excess_velocity_burn, hohmann_time, steps, sweep, synodic_period, Planet, Point,
+            Porkchop,
+        },
+    };
+
+    const KERBOL_MU: f64 = 1.1723328e18;
+
+    fn planet(semi_major_axis: f64, mean_anomaly_at_epoch: f64, mu: f64, radius: f64) -> Planet {
+        Planet {
+            orbit: OrbitalElements {
+                semi_major_axis,
+                eccentricity: 0.0,
+                inclination: 0.0,
+                lan: 0.0,
+                aop: 0.0,
+                mean_anomaly_at_epoch,
+                epoch: 0.0,
+                mu: KERBOL_MU,
+            },
+            mu,
+            radius,
+            atmosphere_depth: 50000.0,
+        }
+    }
+
+    fn kerbin() -> Planet {
+        planet(13_599_840_256.0, 0.0, 3.5316e12, 600_000.0)
+    }
+
+    fn duna() -> Planet {
+        planet(20_726_155_264.0, 1.0, 3.0136321e11, 320_000.0)
+    }
+
+    #[test]
+    fn test_steps() {
+        assert_eq!(steps(0.0, 10.0, 5), vec![0.0, 2.5, 5.0, 7.5, 10.0]);
+        assert_eq!(steps(3.0, 10.0, 1), vec![3.0]);
+    }
+
+    #[test]
+    fn test_transfer_periods() {
+        let (kerbin, duna) = (kerbin().orbit, duna().orbit);
+        // The inner planet laps the outer one once per synodic period
+        let synodic = synodic_period(&kerbin, &duna);
+        assert!((synodic / kerbin.period() - synodic / duna.period() - 1.0).abs() < 1e-9);
+        assert_eq!(synodic, synodic_period(&duna, &kerbin));
+
+        let transfer = OrbitalElements {
+            semi_major_axis: (kerbin.semi_major_axis + duna.semi_major_axis) / 2.0,
+            ..kerbin
+        };
+        let time = hohmann_time(&kerbin, &duna);
+        assert!((time - transfer.period() / 2.0).abs() < 1e-6 * time);
+    }
+
+    #[test]
+    fn test_excess_velocity_burn() {
+        let (mu, radius): (f64, f64) = (3.5316e12, 700_000.0);
+        let circular = (mu / radius).sqrt();
+        // Without excess speed the burn only reaches escape speed
+        let escape = excess_velocity_burn(mu, radius, 0.0);
+        assert!((escape - (2f64.sqrt() - 1.0) * circular).abs() < 1e-9);
+        let speed = circular + excess_velocity_burn(mu, radius, 1000.0);
+        assert!((speed.powi(2) - 2.0 * mu / radius - 1000f64.powi(2)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_best_transfer_is_near_hohmann() {
+        let (kerbin, duna) = (kerbin(), duna());
+        let (r1, r2) = (kerbin.orbit.semi_major_axis, duna.orbit.semi_major_axis);
+        let (parking, capture) = (kerbin.low_orbit(), duna.low_orbit());
+        let hohmann = hohmann_time(&kerbin.orbit, &duna.orbit);
+        let porkchop = sweep(
+            &kerbin,
+            &duna,
+            parking,
+            capture,
+            steps(0.0, synodic_period(&kerbin.orbit, &duna.orbit), 90),
+            steps(0.5 * hohmann, 1.5 * hohmann, 41),
+        );
+        let (departure, flight_time, point) = porkchop.best().unwrap();
+
+        let departure_excess = (KERBOL_MU / r1).sqrt() * ((2.0 * r2 / (r1 + r2)).sqrt() - 1.0);
+        let arrival_excess = (KERBOL_MU / r2).sqrt() * (1.0 - (2.0 * r1 / (r1 + r2)).sqrt());
+        let expected = excess_velocity_burn(kerbin.mu, parking, departure_excess)
+            + excess_velocity_burn(duna.mu, capture, arrival_excess);
+        assert!(
+            (point.total() - expected).abs() < 0.05 * expected,
+            "{} {expected}",
+            point.total()
+        );
+        assert!(
+            (flight_time - hohmann).abs() < 0.1 * hohmann,
+            "{flight_time}"
+        );
+        // Duna leads Kerbin by the angle it covers during the transfer at departure
+        let lead = |ut: f64| {
+            let angle = |planet: &Planet| {
+                let (position, _) = planet.orbit.state_at(ut);
+                position.1.atan2(position.0)
+            };
+            (angle(&duna) - angle(&kerbin)).rem_euclid(2.0 * PI)
+        };
+        let expected_lead = PI - 2.0 * PI * hohmann / duna.orbit.period();
+        assert!(
+            (lead(departure) - expected_lead).abs() < 0.1,
+            "{}",
+            lead(departure)
+        );
+    }
+
+    #[test]
+    fn test_write_files() {
+        let point = Point {
+            ejection_dv: 0.0,
+            capture_dv: 0.0,
+        };
+        let porkchop = Porkchop {
+            departures: vec![0.0, 10.0],
+            flight_times: vec![100.0],
+            grid: vec![vec![Some(point)], vec![None]],
+        };
+        let path = env::temp_dir().join(format!("betterjeb-porkchop-{}", std::process::id()));
+
+        porkchop.write_csv(path.with_extension("csv")).unwrap();
+        let csv = fs::read_to_string(path.with_extension("csv")).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "departure_ut,time_of_flight,ejection_dv,capture_dv,total_dv",
+                "0,100,0,0,0"
+            ]
+        );
+
+        // Every transfer costs the same, which must not colour the cells with NaN
+        porkchop.write_svg(path.with_extension("svg")).unwrap();
+        let svg = fs::read_to_string(path.with_extension("svg")).unwrap();
+        assert!(svg.contains("hsl(120,90%,50%)") && !svg.contains("NaN"));
+
+        fs::remove_file(path.with_extension("csv")).unwrap();
+        fs::remove_file(path.with_extension("svg")).unwrap();
+    }
+}
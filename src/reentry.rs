@@ -0,0 +1,410 @@
+use std::{
+    error::Error,
+    f64::consts::{PI, TAU},
+};
+
+use krpc_mars::{batch_call_unwrap, stream::StreamHandle, RPCClient, StreamClient};
+
+use crate::{
+    apsis::{change_at, ut_at_radius},
+    landing::Deorbit,
+    maneuver::{execute, BurnOptions, BurnStatus},
+    minimize::minimize,
+    orbit::OrbitalElements,
+    services::space_center::{self, Parachute, ParachuteState, Vessel, VesselSituation},
+    vector::{Vec3D, Vector},
+    window::Site,
+};
+
+/// Samples per orbit when searching for the deorbit point
+const DEORBIT_STEPS: usize = 360;
+/// Golden section iterations refining the deorbit point
+const DEORBIT_REFINE_ITER: usize = 40;
+/// Pascals in an atmosphere, the unit of the parachutes' deploy pressure
+const ATMOSPHERE: f64 = 101325.0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReentryOptions {
+    /// Periapsis altitude after the deorbit burn
+    pub periapsis: f64,
+    /// Angle around the orbit from the deorbit burn to splashdown
+    /// Less than half an orbit, as drag brings the ship down before the periapsis
+    pub range: f64,
+    /// Orbits searched for the deorbit point
+    pub orbits: u32,
+    /// Seconds before the atmosphere's edge at which time warp stops
+    pub warp_lead: f64,
+    /// Dynamic pressure in Pa above which the ship holds retrograde against the heating
+    pub hold_pressure: f64,
+    /// Fastest surface speed and highest altitude above the terrain for opening the parachutes
+    pub chute_speed: f64,
+    pub chute_altitude: f64,
+    /// Static pressure in atm from which armed parachutes open
+    pub min_pressure: f32,
+}
+
+impl Default for ReentryOptions {
+    fn default() -> Self {
+        Self {
+            periapsis: 30000.0,
+            range: 160f64.to_radians(),
+            orbits: 5,
+            warp_lead: 30.0,
+            hold_pressure: 1000.0,
+            chute_speed: 250.0,
+            chute_altitude: 5000.0,
+            min_pressure: 0.04,
+        }
+    }
+}
+
+/// Deorbit to splash down at the longitude in degrees, then reenter and land under parachutes
+/// The latitude of the splashdown is left to the orbit
+pub fn return_to(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    longitude: f64,
+    options: &ReentryOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (orbit, ut) = batch_call_unwrap!(client, (&ship.get_orbit(), &space_center::get_ut()))?;
+    let body = orbit.get_body().mk_call(client)?;
+    let (body_radius, atmosphere_depth) = batch_call_unwrap!(
+        client,
+        (&body.get_equatorial_radius(), &body.get_atmosphere_depth())
+    )?;
+    // Only the longitude is matched, so any latitude will do
+    let site = Site::surface(client, ship, 0.0, longitude)?;
+    let elements = OrbitalElements::snapshot(client, &orbit)?;
+    let plan = deorbit(
+        &elements,
+        &site,
+        body_radius + options.periapsis,
+        options.range,
+        ut,
+        options.orbits,
+    )?;
+    println!(
+        "Deorbit at {:.0}, splashdown at {:.0}, missing by {:.2} deg",
+        plan.burn.ut,
+        plan.arrival,
+        plan.miss.to_degrees()
+    );
+    plan.burn.add(client, ship)?;
+    let result = execute(client, stream_client, ship, &BurnOptions::default())?;
+    if result.status != BurnStatus::Complete {
+        return Err(format!("Deorbit burn failed: {:?}", result.status).into());
+    }
+
+    let ut = space_center::get_ut().mk_call(client)?;
+    let elements = OrbitalElements::snapshot(client, &orbit)?;
+    let interface = ut_at_radius(&elements, body_radius + atmosphere_depth, ut)?;
+    if interface - options.warp_lead > ut {
+        space_center::warp_to(interface - options.warp_lead, 100000.0, 2.0).mk_call(client)?;
+    }
+    reenter(client, stream_client, ship, options)
+}
+
+/// Retrograde burn lowering the periapsis, timed so the ship comes down at the site's longitude
+/// The ship is taken to come down `range` around the new orbit from the burn, which starts at its apoapsis
+pub fn deorbit(
+    orbit: &OrbitalElements,
+    site: &Site,
+    periapsis: f64,
+    range: f64,
+    after: f64,
+    orbits: u32,
+) -> Result<Deorbit, Box<dyn Error>> {
+    if orbit.is_hyperbolic() {
+        return Err("Orbit must be closed".into());
+    }
+    let splashdown = |ut: f64| {
+        let (position, velocity) = orbit.state_at(ut);
+        let radius = position.mag();
+        let speed = (orbit.mu * (2.0 / radius - 2.0 / (radius + periapsis))).sqrt();
+        let along = position.cross(velocity).cross(position).normalize();
+        let descent = OrbitalElements::from_state(orbit.mu, position, along.scale(speed), ut);
+        let arrival = descent.ut_at_true_anomaly(PI + range, ut);
+        (arrival, descent.state_at(arrival).0)
+    };
+    let longitude = |position: Vec3D| position.1.atan2(position.0);
+    let miss = |ut: f64| {
+        let (arrival, position) = splashdown(ut);
+        let difference = longitude(position) - longitude(site.position_at(arrival));
+        ((difference + PI).rem_euclid(TAU) - PI).abs()
+    };
+    let ut = minimize(
+        miss,
+        after,
+        after + orbit.period() * orbits.max(1) as f64,
+        DEORBIT_STEPS * orbits.max(1) as usize,
+        DEORBIT_REFINE_ITER,
+    );
+    let radius = orbit.state_at(ut).0.mag();
+    Ok(Deorbit {
+        burn: change_at(orbit, ut, periapsis, radius)?,
+        arrival: splashdown(ut).0,
+        miss: miss(ut),
+    })
+}
+
+/// Stage off everything below the parachutes, then hold retrograde through the heating
+/// and open the parachutes once slow and low enough
+pub fn reenter(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    options: &ReentryOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (control, auto_pilot, parts, rf) = batch_call_unwrap!(
+        client,
+        (
+            &ship.get_control(),
+            &ship.get_auto_pilot(),
+            &ship.get_parts(),
+            &ship.get_surface_velocity_reference_frame(),
+        )
+    )?;
+    let chutes = parts.get_parachutes().mk_call(client)?;
+    if chutes.is_empty() {
+        return Err("Ship has no parachutes".into());
+    }
+    control.set_throttle(0.0).mk_call(client)?;
+    auto_pilot.set_reference_frame(rf).mk_call(client)?;
+    auto_pilot
+        .set_target_direction((0.0, -1.0, 0.0))
+        .mk_call(client)?;
+    auto_pilot.engage().mk_call(client)?;
+
+    let streamer = Streamer::init(client, ship)?;
+    let mut telemetry = Telemetry::default();
+    let mut state = State::Separate;
+    let mut armed = false;
+    loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        let heating = telemetry.dynamic_pressure as f64 > options.hold_pressure;
+        let safe = telemetry.speed < options.chute_speed
+            && telemetry.altitude < options.chute_altitude
+            && !heating;
+        state = match state {
+            State::Separate => {
+                stage_off(client, ship, &chutes)?;
+                State::Coast
+            }
+            State::Coast => {
+                if heating {
+                    State::Entry
+                } else if safe {
+                    State::Chutes
+                } else {
+                    State::Coast
+                }
+            }
+            State::Entry => {
+                if safe {
+                    State::Chutes
+                } else {
+                    State::Entry
+                }
+            }
+            State::Chutes => {
+                if !armed {
+                    auto_pilot.disengage().mk_call(client)?;
+                    for chute in &chutes {
+                        chute
+                            .set_deploy_min_pressure(options.min_pressure)
+                            .mk_call(client)?;
+                        chute.arm().mk_call(client)?;
+                    }
+                    armed = true;
+                }
+                // Armed parachutes wait for their pressure, open any still waiting once it is reached
+                let pressure = telemetry.static_pressure as f64 / ATMOSPHERE;
+                for chute in &chutes {
+                    let (chute_state, min_pressure) = batch_call_unwrap!(
+                        client,
+                        (&chute.get_state(), &chute.get_deploy_min_pressure())
+                    )?;
+                    if matches!(chute_state, ParachuteState::Armed)
+                        && pressure >= min_pressure as f64
+                    {
+                        chute.deploy().mk_call(client)?;
+                    }
+                }
+                if matches!(
+                    telemetry.situation,
+                    Some(VesselSituation::Landed | VesselSituation::Splashed)
+                ) {
+                    State::End
+                } else {
+                    State::Chutes
+                }
+            }
+            State::End => {
+                streamer.stop(client)?;
+                println!("Down at {:.1} m/s", telemetry.speed);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Activate stages until the next one would release the parachutes
+fn stage_off(
+    client: &mut RPCClient,
+    ship: &Vessel,
+    chutes: &[Parachute],
+) -> Result<(), Box<dyn Error>> {
+    let control = ship.get_control().mk_call(client)?;
+    let mut chute_stage = 0;
+    for chute in chutes {
+        let part = chute.get_part().mk_call(client)?;
+        chute_stage = chute_stage.max(part.get_stage().mk_call(client)?);
+    }
+    let mut stage = control.get_current_stage().mk_call(client)?;
+    while stage - 1 > chute_stage {
+        control.activate_next_stage().mk_call(client)?;
+        let next = control.get_current_stage().mk_call(client)?;
+        // Staging is locked or there is nothing left to stage
+        if next >= stage {
+            return Err(format!("Stage {stage} did not activate").into());
+        }
+        stage = next;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    /// Shed the service module
+    Separate,
+    Coast,
+    /// Hold retrograde through the heating
+    Entry,
+    Chutes,
+    End,
+}
+
+#[derive(Default)]
+pub struct Telemetry {
+    altitude: f64,
+    speed: f64,
+    dynamic_pressure: f32,
+    static_pressure: f32,
+    situation: Option<VesselSituation>,
+}
+
+pub struct Streamer {
+    altitude: StreamHandle<f64>,
+    speed: StreamHandle<f64>,
+    dynamic_pressure: StreamHandle<f32>,
+    static_pressure: StreamHandle<f32>,
+    situation: StreamHandle<VesselSituation>,
+}
+
+impl Streamer {
+    pub fn init(client: &mut RPCClient, vessel: &Vessel) -> Result<Self, Box<dyn Error>> {
+        let body = vessel
+            .get_orbit()
+            .mk_call(client)?
+            .get_body()
+            .mk_call(client)?;
+        let flight = vessel
+            .flight(body.get_reference_frame().mk_call(client)?)
+            .mk_call(client)?;
+        Ok(Self {
+            altitude: flight.get_surface_altitude().to_stream().mk_call(client)?,
+            speed: flight.get_speed().to_stream().mk_call(client)?,
+            dynamic_pressure: flight.get_dynamic_pressure().to_stream().mk_call(client)?,
+            static_pressure: flight.get_static_pressure().to_stream().mk_call(client)?,
+            situation: vessel.get_situation().to_stream().mk_call(client)?,
+        })
+    }
+
+    pub fn update(
+        &self,
+        stream_client: &mut StreamClient,
+        telemetry: &mut Telemetry,
+    ) -> Result<(), Box<dyn Error>> {
+        let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.altitude)? {
+            telemetry.altitude = val;
+        }
+        if let Some(val) = update.get_result(&self.speed)? {
+            telemetry.speed = val;
+        }
+        if let Some(val) = update.get_result(&self.dynamic_pressure)? {
+            telemetry.dynamic_pressure = val;
+        }
+        if let Some(val) = update.get_result(&self.static_pressure)? {
+            telemetry.static_pressure = val;
+        }
+        if let Some(val) = update.get_result(&self.situation)? {
+            telemetry.situation = Some(val);
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Box<dyn Error>> {
+        batch_call_unwrap!(
+            client,
+            (
+                &self.altitude.remove(),
+                &self.speed.remove(),
+                &self.dynamic_pressure.remove(),
+                &self.static_pressure.remove(),
+                &self.situation.remove(),
+            )
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::{PI, TAU};
+
+    use crate::{
+        orbit::OrbitalElements,
+        reentry::{deorbit, ReentryOptions},
+        vector::Vector,
+        window::Site,
+    };
+
+    #[test]
+    fn test_deorbit_to_longitude() {
+        let orbit = OrbitalElements {
+            semi_major_axis: 680_000.0,
+            eccentricity: 0.0,
+            inclination: 0.4,
+            lan: 0.3,
+            aop: 0.0,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        };
+        let site = Site {
+            latitude: 0.0,
+            longitude: 2.0,
+            ut: 0.0,
+            radius: 600_000.0,
+            rotational_speed: TAU / 21_549.4,
+        };
+        let options = ReentryOptions::default();
+        let plan = deorbit(&orbit, &site, 630_000.0, options.range, 100.0, 2).unwrap();
+        assert!(plan.burn.ut >= 100.0);
+        assert!(plan.miss < 1e-6);
+        assert!(plan.burn.burn.0 < 0.0);
+
+        // Follow the new orbit round to the splashdown, short of the periapsis
+        let (position, velocity) = orbit.state_at(plan.burn.ut);
+        let velocity = velocity.add(velocity.normalize().scale(plan.burn.burn.0));
+        let descent = OrbitalElements::from_state(orbit.mu, position, velocity, plan.burn.ut);
+        assert!((descent.periapsis() - 630_000.0).abs() < 1e-3);
+        let (landing, _) = descent.state_at(plan.arrival);
+        assert!((landing.vang(position) - options.range).abs() < 1e-6);
+        let target = site.position_at(plan.arrival);
+        let difference = landing.1.atan2(landing.0) - target.1.atan2(target.0);
+        assert!(((difference + PI).rem_euclid(TAU) - PI).abs() < 1e-6);
+    }
+}
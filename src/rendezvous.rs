@@ -0,0 +1,449 @@
+use std::error::Error;
+
+use krpc_mars::{batch_call_unwrap, stream::StreamHandle, RPCClient, StreamClient};
+
+use crate::{
+    error,
+    lambert::{self, Transfer},
+    maneuver::{self, BurnOptions, BurnStatus},
+    minimize::minimize,
+    orbit::OrbitalElements,
+    porkchop::steps,
+    services::space_center::{self, Vessel},
+    vector::{Vec3D, Vector},
+};
+
+/// Samples per orbit when searching for the closest approach
+const APPROACH_STEPS: usize = 360;
+const APPROACH_REFINE_ITER: usize = 60;
+/// Departures tried over the first orbit when planning the correction burn
+const CORRECTION_DEPARTURES: usize = 12;
+/// Arrivals tried per orbit when planning the correction burn
+const CORRECTION_ARRIVALS: usize = 36;
+/// Seconds left before the correction burn to point and warp
+const CORRECTION_LEAD: f64 = 300.0;
+/// Seconds of warp margin before the kill burn starts
+const KILL_MARGIN: f64 = 30.0;
+/// Largest pointing error in degrees with the throttle open
+const POINTING_TOLERANCE: f32 = 5.0;
+/// Seconds to close the remaining distance when approaching below the fastest closing speed
+const CLOSING_TIME: f64 = 60.0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct RendezvousOptions {
+    /// Orbits of the ship searched for the closest approach
+    pub orbits: u32,
+    /// Distance from the target to end within
+    pub distance: f64,
+    /// Relative speed to end below
+    pub speed: f64,
+    /// Fastest closing speed when approaching the target after the kill burn
+    pub approach_speed: f64,
+    /// Seconds of burn left at full thrust when throttling down starts
+    pub throttle_down_time: f64,
+}
+
+impl Default for RendezvousOptions {
+    fn default() -> Self {
+        Self {
+            orbits: 3,
+            distance: 100.0,
+            speed: 0.2,
+            approach_speed: 10.0,
+            throttle_down_time: 2.0,
+        }
+    }
+}
+
+/// Closest approach between two orbits of the same primary
+#[derive(Debug, Copy, Clone)]
+pub struct Approach {
+    pub ut: f64,
+    pub distance: f64,
+    /// Ship velocity minus target velocity at the approach
+    pub relative_velocity: Vec3D,
+}
+
+/// Distance and relative speed left when the rendezvous ends
+#[derive(Debug, Copy, Clone)]
+pub struct RendezvousResult {
+    pub distance: f64,
+    pub speed: f64,
+}
+
+/// Meet the target vessel: correct the orbit if the closest approach misses,
+/// then kill the relative velocity there and close in until within the options' distance and speed
+pub fn rendezvous(
+    client: &mut RPCClient,
+    stream_client: &mut StreamClient,
+    ship: &Vessel,
+    target: &Vessel,
+    options: &RendezvousOptions,
+) -> Result<RendezvousResult, Box<dyn Error>> {
+    let (ship_orbit, target_orbit) =
+        batch_call_unwrap!(client, (&ship.get_orbit(), &target.get_orbit()))?;
+    let (ship_body, target_body) =
+        batch_call_unwrap!(client, (&ship_orbit.get_body(), &target_orbit.get_body()))?;
+    let (ship_body, target_body) =
+        batch_call_unwrap!(client, (&ship_body.get_name(), &target_body.get_name()))?;
+    if ship_body != target_body {
+        return Err(format!("Target orbits {target_body}, not {ship_body}").into());
+    }
+
+    let ut = space_center::get_ut().mk_call(client)?;
+    let ship_elements = OrbitalElements::snapshot(client, &ship_orbit)?;
+    let target_elements = OrbitalElements::snapshot(client, &target_orbit)?;
+    let mut approach = closest_approach(&ship_elements, &target_elements, ut, options.orbits)?;
+    println!("Closest approach: {approach:?}");
+
+    if approach.distance > options.distance {
+        let transfer = correction(
+            &ship_elements,
+            &target_elements,
+            ut + CORRECTION_LEAD,
+            options.orbits,
+        )?;
+        println!("Correction: {transfer:?}");
+        lambert::node(client, ship, &transfer)?;
+        let result = maneuver::execute(client, stream_client, ship, &BurnOptions::default())?;
+        if result.status != BurnStatus::Complete {
+            return Err(format!("Correction burn failed: {:?}", result.status).into());
+        }
+        let ship_orbit = ship.get_orbit().mk_call(client)?;
+        let ship_elements = OrbitalElements::snapshot(client, &ship_orbit)?;
+        let ut = space_center::get_ut().mk_call(client)?;
+        // The transfer may arrive several revolutions out, search until past its arrival
+        let orbits = ((transfer.arrival_ut - ut) / ship_elements.period()).ceil() as u32 + 1;
+        approach = closest_approach(&ship_elements, &target_elements, ut, orbits)?;
+        println!("Closest approach: {approach:?}");
+    }
+
+    let (kill_before, _) = maneuver::burn_time(client, ship, approach.relative_velocity.mag())?;
+    let kill_start = approach.ut - kill_before;
+    space_center::warp_to(kill_start - KILL_MARGIN, 100000.0, 2.0).mk_call(client)?;
+
+    let control = ship.get_control().mk_call(client)?;
+    let auto_pilot = ship.get_auto_pilot().mk_call(client)?;
+    // The approach is predicted right-handed in the body's inertial frame, kRPC's frames are left-handed
+    let inertial = ship
+        .get_orbit()
+        .mk_call(client)?
+        .get_body()
+        .mk_call(client)?
+        .get_non_rotating_reference_frame()
+        .mk_call(client)?;
+    auto_pilot.set_reference_frame(inertial).mk_call(client)?;
+    auto_pilot
+        .set_target_direction(approach.relative_velocity.neg().flip_handedness())
+        .mk_call(client)?;
+    auto_pilot.engage().mk_call(client)?;
+
+    let streamer = Streamer::init(client, ship, target)?;
+    let mut telemetry = Telemetry::default();
+    loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        if telemetry.ut >= kill_start {
+            break;
+        }
+    }
+    // Telemetry is relative to the target from here on
+    let rf = target.get_reference_frame().mk_call(client)?;
+    auto_pilot.set_reference_frame(rf).mk_call(client)?;
+
+    let mut state = State::Kill;
+    let mut prev_state = state;
+    loop {
+        streamer.update(stream_client, &mut telemetry)?;
+        if state != prev_state {
+            println!("{prev_state:?}->{state:?}");
+            prev_state = state;
+        }
+        let distance = telemetry.position.mag();
+        let speed = telemetry.velocity.mag();
+        if telemetry.thrust <= 0.0 {
+            control.set_throttle(0.0).mk_call(client)?;
+            auto_pilot.disengage().mk_call(client)?;
+            streamer.stop(client)?;
+            return Err(error::Error::Performance("Out of thrust".to_string()).into());
+        }
+        let acceleration = telemetry.thrust as f64 / telemetry.mass as f64;
+        // Next state and the velocity change to burn for, if any
+        let (next, change) = match state {
+            State::Kill if speed < options.speed => {
+                if distance < options.distance {
+                    control.set_throttle(0.0).mk_call(client)?;
+                    break;
+                }
+                (State::Close, None)
+            }
+            State::Kill => (State::Kill, Some(telemetry.velocity.neg())),
+            State::Close => {
+                let closing = (distance / CLOSING_TIME)
+                    .min(options.approach_speed)
+                    .max(2.0 * options.speed);
+                let change = telemetry
+                    .position
+                    .neg()
+                    .normalize()
+                    .scale(closing)
+                    .sub(telemetry.velocity);
+                if change.mag() < options.speed {
+                    (State::Coast, None)
+                } else {
+                    (State::Close, Some(change))
+                }
+            }
+            State::Coast => {
+                let stopping = speed.powi(2) / (2.0 * acceleration);
+                let receding = telemetry.velocity.dot(telemetry.position) > 0.0;
+                if receding || distance - stopping < options.distance / 2.0 {
+                    (State::Kill, None)
+                } else {
+                    (State::Coast, None)
+                }
+            }
+        };
+        let throttle = match change {
+            Some(change) => {
+                auto_pilot.set_target_direction(change).mk_call(client)?;
+                if telemetry.error < POINTING_TOLERANCE {
+                    (change.mag() / (acceleration * options.throttle_down_time)).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        control.set_throttle(throttle as f32).mk_call(client)?;
+        state = next;
+    }
+    auto_pilot.disengage().mk_call(client)?;
+    streamer.stop(client)?;
+    Ok(RendezvousResult {
+        distance: telemetry.position.mag(),
+        speed: telemetry.velocity.mag(),
+    })
+}
+
+/// Closest approach over the ship's next `orbits` orbits from `start`
+pub fn closest_approach(
+    ship: &OrbitalElements,
+    target: &OrbitalElements,
+    start: f64,
+    orbits: u32,
+) -> Result<Approach, Box<dyn Error>> {
+    if ship.is_hyperbolic() {
+        return Err(error::Error::Numerical("Ship orbit must be closed".to_string()).into());
+    }
+    let distance = |ut: f64| ship.state_at(ut).0.sub(target.state_at(ut).0).mag();
+    let ut = minimize(
+        distance,
+        start,
+        start + ship.period() * orbits.max(1) as f64,
+        APPROACH_STEPS * orbits.max(1) as usize,
+        APPROACH_REFINE_ITER,
+    );
+    let (ship_position, ship_velocity) = ship.state_at(ut);
+    let (target_position, target_velocity) = target.state_at(ut);
+    Ok(Approach {
+        ut,
+        distance: ship_position.sub(target_position).mag(),
+        relative_velocity: ship_velocity.sub(target_velocity),
+    })
+}
+
+/// Cheapest transfer meeting the target, counting both the correction and the kill burn
+/// Departures span one orbit from `after`, arrivals up to `orbits` orbits later
+pub fn correction(
+    ship: &OrbitalElements,
+    target: &OrbitalElements,
+    after: f64,
+    orbits: u32,
+) -> Result<Transfer, Box<dyn Error>> {
+    if ship.is_hyperbolic() {
+        return Err(error::Error::Numerical("Ship orbit must be closed".to_string()).into());
+    }
+    let period = ship.period();
+    let orbits = orbits.max(1);
+    let flight_times = steps(
+        period / CORRECTION_ARRIVALS as f64,
+        period * orbits as f64,
+        CORRECTION_ARRIVALS * orbits as usize,
+    );
+    let mut best: Option<Transfer> = None;
+    for departure in steps(after, after + period, CORRECTION_DEPARTURES) {
+        for &tof in &flight_times {
+            let full = (tof / period) as u32;
+            let revolutions = if full == 0 { vec![0] } else { vec![0, full] };
+            for revolutions in revolutions {
+                let Ok(transfer) =
+                    lambert::plan(ship, target, departure, departure + tof, revolutions)
+                else {
+                    continue;
+                };
+                let cost = transfer.departure_delta_v() + transfer.arrival_delta_v();
+                if best.is_none_or(|b| cost < b.departure_delta_v() + b.arrival_delta_v()) {
+                    best = Some(transfer);
+                }
+            }
+        }
+    }
+    best.ok_or_else(|| {
+        error::Error::Numerical("No correction burn meets the target".to_string()).into()
+    })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Kill,
+    Close,
+    Coast,
+}
+
+#[derive(Default)]
+pub struct Telemetry {
+    ut: f64,
+    /// Ship position and velocity in the target's reference frame
+    position: Vec3D,
+    velocity: Vec3D,
+    thrust: f32,
+    mass: f32,
+    /// Autopilot pointing error in degrees
+    error: f32,
+}
+
+pub struct Streamer {
+    ut: StreamHandle<f64>,
+    position: StreamHandle<Vec3D>,
+    velocity: StreamHandle<Vec3D>,
+    thrust: StreamHandle<f32>,
+    mass: StreamHandle<f32>,
+    error: StreamHandle<f32>,
+}
+
+impl Streamer {
+    pub fn init(
+        client: &mut RPCClient,
+        vessel: &Vessel,
+        target: &Vessel,
+    ) -> Result<Self, Box<dyn Error>> {
+        let rf = target.get_reference_frame().mk_call(client)?;
+        let auto_pilot = vessel.get_auto_pilot().mk_call(client)?;
+        Ok(Self {
+            ut: space_center::get_ut().to_stream().mk_call(client)?,
+            position: vessel.position(rf).to_stream().mk_call(client)?,
+            velocity: vessel.velocity(rf).to_stream().mk_call(client)?,
+            thrust: vessel.get_available_thrust().to_stream().mk_call(client)?,
+            mass: vessel.get_mass().to_stream().mk_call(client)?,
+            error: auto_pilot.get_error().to_stream().mk_call(client)?,
+        })
+    }
+
+    pub fn update(
+        &self,
+        stream_client: &mut StreamClient,
+        telemetry: &mut Telemetry,
+    ) -> Result<(), Box<dyn Error>> {
+        let update = stream_client.recv_update()?;
+        if let Some(val) = update.get_result(&self.ut)? {
+            telemetry.ut = val;
+        }
+        if let Some(val) = update.get_result(&self.position)? {
+            telemetry.position = val;
+        }
+        if let Some(val) = update.get_result(&self.velocity)? {
+            telemetry.velocity = val;
+        }
+        if let Some(val) = update.get_result(&self.thrust)? {
+            telemetry.thrust = val;
+        }
+        if let Some(val) = update.get_result(&self.mass)? {
+            telemetry.mass = val;
+        }
+        if let Some(val) = update.get_result(&self.error)? {
+            telemetry.error = val;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, client: &mut RPCClient) -> Result<(), Box<dyn Error>> {
+        batch_call_unwrap!(
+            client,
+            (
+                &self.ut.remove(),
+                &self.position.remove(),
+                &self.velocity.remove(),
+                &self.thrust.remove(),
+                &self.mass.remove(),
+                &self.error.remove(),
+            )
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        orbit::OrbitalElements,
+        rendezvous::{closest_approach, correction},
+        vector::Vector,
+    };
+
+    fn circular(semi_major_axis: f64, mean_anomaly_at_epoch: f64) -> OrbitalElements {
+        OrbitalElements {
+            semi_major_axis,
+            eccentricity: 0.0,
+            inclination: 0.1,
+            lan: 0.5,
+            aop: 0.0,
+            mean_anomaly_at_epoch,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        }
+    }
+
+    #[test]
+    fn test_closest_approach_finds_crossing() {
+        let target = circular(700_000.0, 0.0);
+        // Ship on a faster orbit through the target's position at t = 1500
+        let (position, velocity) = target.state_at(1500.0);
+        let ship = OrbitalElements::from_state(
+            target.mu,
+            position,
+            velocity.scale(1.05).add(position.normalize().scale(20.0)),
+            1500.0,
+        );
+        let approach = closest_approach(&ship, &target, 1000.0, 1).unwrap();
+        assert!((approach.ut - 1500.0).abs() < 0.1, "{approach:?}");
+        assert!(approach.distance < 1.0);
+        assert!(
+            (approach.relative_velocity.mag()
+                - velocity
+                    .scale(0.05)
+                    .add(position.normalize().scale(20.0))
+                    .mag())
+            .abs()
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn test_correction_meets_target() {
+        let ship = circular(680_000.0, 0.0);
+        let target = circular(700_000.0, 0.8);
+        let transfer = correction(&ship, &target, 100.0, 2).unwrap();
+        let (position, _) = ship.state_at(transfer.departure_ut);
+        let after = OrbitalElements::from_state(
+            ship.mu,
+            position,
+            transfer.departure_velocity,
+            transfer.departure_ut,
+        );
+        let (arrival, _) = after.state_at(transfer.arrival_ut);
+        let (expected, _) = target.state_at(transfer.arrival_ut);
+        assert!(arrival.sub(expected).mag() < 1.0);
+        let cost = transfer.departure_delta_v() + transfer.arrival_delta_v();
+        assert!(cost < 100.0, "{cost}");
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::{self, File},
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use krpc_mars::{
+    krpc::{
+        ConnectionRequest, ConnectionRequest_Type, ConnectionResponse, ProcedureCall,
+        ProcedureResult, Request, Response, StreamUpdate,
+    },
+    protobuf::{CodedInputStream, Message},
+    RPCClient, StreamClient,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::mock::{accept_stream, error, handshake, lock, name};
+
+/// Event of a recorded session, one per line of the log as JSON
+/// Times are in seconds since the recording started and messages are protobuf-encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Entry {
+    /// Calls of a single request and their results
+    Request {
+        time: f64,
+        calls: Vec<Call>,
+    },
+    Update {
+        time: f64,
+        update: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Call {
+    /// `Service.Procedure`, for reading the log
+    pub procedure: String,
+    pub call: Vec<u8>,
+    pub result: Vec<u8>,
+}
+
+/// Read every entry of a log
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Entry>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let mut entries = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        entries.push(serde_json::from_str(line)?);
+    }
+    Ok(entries)
+}
+
+/// Forward a single client session to the server, appending each request and stream update to the log
+/// Returns once the client disconnects
+pub fn record(
+    rpc: &TcpListener,
+    stream: &TcpListener,
+    server_rpc: impl ToSocketAddrs,
+    server_stream: impl ToSocketAddrs + Send + 'static,
+    path: impl AsRef<Path>,
+) -> Result<(), Box<dyn Error>> {
+    let log = Arc::new(Mutex::new(File::create(path)?));
+    let start = Instant::now();
+
+    let (mut client, _) = rpc.accept()?;
+    let mut server = TcpStream::connect(server_rpc)?;
+    forward_handshake(&mut client, &mut server)?;
+    println!("Recording {}", client.peer_addr()?);
+
+    let stream = stream.try_clone()?;
+    let stream_log = log.clone();
+    // Updates stop when either side hangs up
+    thread::spawn(move || forward_updates(&stream, server_stream, &stream_log, start).ok());
+
+    loop {
+        let request: Request = match CodedInputStream::new(&mut client).read_message() {
+            Ok(request) => request,
+            // The client hung up
+            Err(_) => return Ok(()),
+        };
+        request.write_length_delimited_to_writer(&mut server)?;
+        let response: Response = CodedInputStream::new(&mut server).read_message()?;
+        response.write_length_delimited_to_writer(&mut client)?;
+        let mut calls = Vec::new();
+        for (call, result) in request.get_calls().iter().zip(response.get_results()) {
+            calls.push(Call {
+                procedure: name(call),
+                call: call.write_to_bytes()?,
+                result: result.write_to_bytes()?,
+            });
+        }
+        let entry = Entry::Request {
+            time: start.elapsed().as_secs_f64(),
+            calls,
+        };
+        append(&log, &entry)?;
+    }
+}
+
+fn forward_updates(
+    stream: &TcpListener,
+    server_stream: impl ToSocketAddrs,
+    log: &Mutex<File>,
+    start: Instant,
+) -> Result<(), Box<dyn Error>> {
+    let (mut client, _) = stream.accept()?;
+    let mut server = TcpStream::connect(server_stream)?;
+    forward_handshake(&mut client, &mut server)?;
+    loop {
+        let update: StreamUpdate = CodedInputStream::new(&mut server).read_message()?;
+        update.write_length_delimited_to_writer(&mut client)?;
+        let entry = Entry::Update {
+            time: start.elapsed().as_secs_f64(),
+            update: update.write_to_bytes()?,
+        };
+        append(log, &entry)?;
+    }
+}
+
+fn forward_handshake(client: &mut TcpStream, server: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    let request: ConnectionRequest = CodedInputStream::new(client).read_message()?;
+    request.write_length_delimited_to_writer(server)?;
+    let response: ConnectionResponse = CodedInputStream::new(server).read_message()?;
+    response.write_length_delimited_to_writer(client)?;
+    Ok(())
+}
+
+fn append(log: &Mutex<File>, entry: &Entry) -> Result<(), Box<dyn Error>> {
+    let mut log = lock(log);
+    serde_json::to_writer(&mut *log, entry)?;
+    writeln!(log)?;
+    Ok(())
+}
+
+/// Serves a recorded session back, ignoring its timing
+/// Each request must match the next one recorded and is answered with the recorded results,
+/// the stream updates recorded after it are sent as soon as it is answered
+/// Once the client strays from the log every call fails, naming the call expected
+pub struct ReplayServer {
+    rpc_address: SocketAddr,
+    stream_address: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+}
+
+struct Shared {
+    entries: VecDeque<Entry>,
+    stream_socket: Option<TcpStream>,
+    disconnected: bool,
+}
+
+impl ReplayServer {
+    /// Serve the entries on free ports
+    pub fn start(entries: Vec<Entry>) -> io::Result<Self> {
+        Self::bind(entries, "127.0.0.1:0", "127.0.0.1:0")
+    }
+
+    pub fn bind(
+        entries: Vec<Entry>,
+        rpc: impl ToSocketAddrs,
+        stream: impl ToSocketAddrs,
+    ) -> io::Result<Self> {
+        let rpc = TcpListener::bind(rpc)?;
+        let stream = TcpListener::bind(stream)?;
+        let server = Self {
+            rpc_address: rpc.local_addr()?,
+            stream_address: stream.local_addr()?,
+            shared: Arc::new(Mutex::new(Shared {
+                entries: entries.into(),
+                stream_socket: None,
+                disconnected: false,
+            })),
+        };
+        let shared = server.shared.clone();
+        thread::spawn(move || {
+            for socket in rpc.incoming().flatten() {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    serve_rpc(socket, &shared);
+                    lock(&shared).disconnected = true;
+                });
+            }
+        });
+        let shared = server.shared.clone();
+        thread::spawn(move || {
+            for socket in stream.incoming().flatten() {
+                if accept_stream(&socket).is_ok() {
+                    let mut shared = lock(&shared);
+                    shared.stream_socket = Some(socket);
+                    shared.flush();
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn rpc_address(&self) -> SocketAddr {
+        self.rpc_address
+    }
+
+    pub fn stream_address(&self) -> SocketAddr {
+        self.stream_address
+    }
+
+    pub fn connect(&self) -> Result<(RPCClient, StreamClient), Box<dyn Error>> {
+        let client = RPCClient::connect("replay", self.rpc_address)?;
+        let stream_client = StreamClient::connect(&client, self.stream_address)?;
+        Ok((client, stream_client))
+    }
+
+    /// Entries not served yet, none once the session was replayed to the end
+    pub fn remaining(&self) -> usize {
+        lock(&self.shared).entries.len()
+    }
+
+    /// Whether a client has connected and hung up again
+    pub fn disconnected(&self) -> bool {
+        lock(&self.shared).disconnected
+    }
+}
+
+impl Shared {
+    /// Answer the request from the next entry, or fail every call if it does not match
+    fn answer(&mut self, request: &Request) -> Response {
+        let mut response = Response::new();
+        let calls = request.get_calls();
+        let expected = match self.entries.front() {
+            Some(Entry::Request { calls, .. }) => calls.clone(),
+            _ => Vec::new(),
+        };
+        let matches = expected.len() == calls.len()
+            && expected.iter().zip(calls).all(|(recorded, call)| {
+                ProcedureCall::parse_from_bytes(&recorded.call).is_ok_and(|rec| rec == *call)
+            });
+        if !matches {
+            let next = expected
+                .first()
+                .map_or("the end of the log", |call| &call.procedure);
+            for call in calls {
+                let mut result = ProcedureResult::new();
+                result.set_error(error(
+                    call,
+                    format!("Replay diverged: expected {next}, got {}", name(call)),
+                ));
+                response.mut_results().push(result);
+            }
+            return response;
+        }
+        for recorded in &expected {
+            // An unreadable result reaches the client as an empty value it fails to decode
+            response
+                .mut_results()
+                .push(ProcedureResult::parse_from_bytes(&recorded.result).unwrap_or_default());
+        }
+        self.entries.pop_front();
+        response
+    }
+
+    /// Send the updates up to the next request
+    fn flush(&mut self) {
+        let Some(socket) = self.stream_socket.as_mut() else {
+            return;
+        };
+        while let Some(Entry::Update { update, .. }) = self.entries.front() {
+            let sent = StreamUpdate::parse_from_bytes(update)
+                .map_err(Box::<dyn Error>::from)
+                .and_then(|update| Ok(update.write_length_delimited_to_writer(socket)?));
+            if sent.is_err() {
+                return;
+            }
+            self.entries.pop_front();
+        }
+    }
+}
+
+fn serve_rpc(mut socket: TcpStream, shared: &Mutex<Shared>) {
+    if handshake(&mut socket, ConnectionRequest_Type::RPC).is_err() {
+        return;
+    }
+    loop {
+        let request: Request = match CodedInputStream::new(&mut socket).read_message() {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let response = {
+            let mut shared = lock(shared);
+            let response = shared.answer(&request);
+            shared.flush();
+            response
+        };
+        if response
+            .write_length_delimited_to_writer(&mut socket)
+            .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,763 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    f64::consts::{FRAC_PI_2, TAU},
+};
+
+use krpc_mars::krpc::ProcedureCall;
+
+use crate::{
+    mock::{argument, encode, name},
+    orbit::OrbitalElements,
+    services::space_center::ResourceFlowMode,
+    stage::G0,
+    vector::{Vec3D, Vector},
+};
+
+/// Ids the simulation hands out for the game's objects
+pub const VESSEL: u32 = 1;
+pub const ORBIT: u32 = 2;
+pub const BODY: u32 = 3;
+pub const CONTROL: u32 = 4;
+pub const AUTO_PILOT: u32 = 5;
+pub const PARTS: u32 = 6;
+/// Frame centred on the vessel, only flights are measured in it
+pub const VESSEL_FRAME: u32 = 10;
+/// Frames centred on the body, rotating with it and not
+pub const SURFACE_FRAME: u32 = 11;
+pub const INERTIAL_FRAME: u32 = 12;
+/// Flights in a frame are numbered after it
+const FLIGHT: u32 = 100;
+/// Nodes and their orbital frames are numbered in the order they are added
+const NODE: u32 = 1000;
+const NODE_FRAME: u32 = 2000;
+/// Each stage is a single part carrying its engine and propellant, numbered in firing order
+const PART: u32 = 3000;
+const ENGINE: u32 = 4000;
+const RESOURCES: u32 = 5000;
+const RESOURCE: u32 = 6000;
+/// Propellant is liquid fuel only reaching the engine of its own part, in kg per unit
+const PROPELLANT: &str = "LiquidFuel";
+const DENSITY: f64 = 5.0;
+
+/// Spherical body without terrain
+#[derive(Debug, Copy, Clone)]
+pub struct Body {
+    pub mu: f64,
+    pub radius: f64,
+    /// Rotation in radians per second about the north pole
+    pub rotational_speed: f64,
+    /// Reported to the client only, there is no drag inside it
+    pub atmosphere_depth: f64,
+}
+
+impl Body {
+    pub fn kerbin() -> Self {
+        Self {
+            mu: 3.5316e12,
+            radius: 600_000.0,
+            rotational_speed: TAU / 21_549.425,
+            atmosphere_depth: 70_000.0,
+        }
+    }
+}
+
+/// Part of the vessel burning its own propellant, dropped when the next stage is activated
+/// Masses are in kg, thrust in N and specific impulse in s
+#[derive(Debug, Copy, Clone)]
+pub struct Stage {
+    pub dry_mass: f64,
+    pub propellant: f64,
+    pub thrust: f64,
+    pub isp: f64,
+}
+
+impl Stage {
+    fn mass(&self) -> f64 {
+        self.dry_mass + self.propellant
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    id: u32,
+    ut: f64,
+    prograde: f64,
+    normal: f64,
+    radial: f64,
+    /// Burn in the inertial frame, and the thrust applied since the node was added
+    burn: Vec3D,
+    applied: Vec3D,
+    /// Anti-radial, prograde and normal directions of the orbit at the node, as in kRPC's orbital frame
+    axes: [Vec3D; 3],
+}
+
+#[derive(Debug, Copy, Clone)]
+struct AutoPilot {
+    engaged: bool,
+    reference_frame: u32,
+    /// Degrees above the horizon and clockwise from north
+    pitch: f64,
+    heading: f64,
+    /// Direction in the reference frame, flown instead of the pitch and heading when set
+    direction: Option<Vec3D>,
+}
+
+/// Point mass flown around a single body, answering the procedures the autopilots need
+/// The vessel turns to the autopilot's target instantly and nothing but gravity and thrust acts on it
+/// State vectors are right-handed with z towards the north pole, as for `OrbitalElements`
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub body: Body,
+    pub ut: f64,
+    pub position: Vec3D,
+    pub velocity: Vec3D,
+    /// Stages in firing order
+    pub stages: Vec<Stage>,
+    /// Stages activated so far, the last of them burning
+    pub activated: usize,
+    pub throttle: f32,
+    /// Unit vector the vessel points along
+    pub facing: Vec3D,
+    /// Seconds of UT advanced by each step
+    pub time_step: f64,
+    landed: bool,
+    auto_pilot: AutoPilot,
+    nodes: Vec<Node>,
+    next_node: u32,
+}
+
+impl Simulation {
+    /// Vessel standing on the surface at the given latitude and longitude in degrees, at UT 0
+    pub fn landed(body: Body, latitude: f64, longitude: f64, stages: Vec<Stage>) -> Self {
+        let (sin_lat, cos_lat) = latitude.to_radians().sin_cos();
+        let (sin_lng, cos_lng) = longitude.to_radians().sin_cos();
+        let position = (cos_lat * cos_lng, cos_lat * sin_lng, sin_lat).scale(body.radius);
+        let mut sim = Self::new(body, 0.0, position, (0.0, 0.0, 0.0), stages);
+        sim.velocity = sim.rotation_velocity();
+        sim.landed = true;
+        sim
+    }
+
+    /// Vessel on the orbit at `ut` with its first stage activated, pointing prograde
+    pub fn orbiting(body: Body, orbit: &OrbitalElements, ut: f64, stages: Vec<Stage>) -> Self {
+        let (position, velocity) = orbit.state_at(ut);
+        let mut sim = Self::new(body, ut, position, velocity, stages);
+        sim.activated = 1;
+        sim.facing = velocity.normalize();
+        sim
+    }
+
+    fn new(body: Body, ut: f64, position: Vec3D, velocity: Vec3D, stages: Vec<Stage>) -> Self {
+        Self {
+            body,
+            ut,
+            position,
+            velocity,
+            stages,
+            activated: 0,
+            throttle: 0.0,
+            facing: position.normalize(),
+            time_step: 0.05,
+            landed: false,
+            auto_pilot: AutoPilot {
+                engaged: false,
+                reference_frame: SURFACE_FRAME,
+                pitch: 0.0,
+                heading: 0.0,
+                direction: None,
+            },
+            nodes: Vec::new(),
+            next_node: 0,
+        }
+    }
+
+    pub fn elements(&self) -> OrbitalElements {
+        OrbitalElements::from_state(self.body.mu, self.position, self.velocity, self.ut)
+    }
+
+    pub fn altitude(&self) -> f64 {
+        self.position.mag() - self.body.radius
+    }
+
+    /// Mass of the stages not yet dropped
+    pub fn mass(&self) -> f64 {
+        self.attached().map(|(_, stage)| stage.mass()).sum()
+    }
+
+    /// Thrust of the burning stage, nothing once it runs dry
+    pub fn available_thrust(&self) -> f64 {
+        match self.burning() {
+            Some(stage) if stage.propellant > 0.0 => stage.thrust,
+            _ => 0.0,
+        }
+    }
+
+    /// Stage number as counted by the game, down to 0 for the last stage
+    pub fn current_stage(&self) -> i32 {
+        (self.stages.len() - self.activated) as i32
+    }
+
+    pub fn activate_next_stage(&mut self) {
+        self.activated = (self.activated + 1).min(self.stages.len());
+    }
+
+    /// Advance by one time step, pointing the vessel first when the autopilot is engaged
+    pub fn step(&mut self) {
+        if self.auto_pilot.engaged {
+            if let Ok(target) = self.target() {
+                self.facing = target;
+            }
+        }
+        let dt = self.time_step;
+        let mass = self.mass();
+        let thrust = self.throttle.clamp(0.0, 1.0) as f64 * self.available_thrust();
+        let mut acceleration = (0.0, 0.0, 0.0);
+        if let (true, Some(stage)) = (thrust > 0.0, self.activated.checked_sub(1)) {
+            let stage = &mut self.stages[stage];
+            let flow = thrust / (stage.isp * G0) * dt;
+            let burnt = flow.min(stage.propellant);
+            stage.propellant -= burnt;
+            acceleration = self.facing.scale(thrust * burnt / flow / mass);
+            for node in &mut self.nodes {
+                node.applied = node.applied.add(acceleration.scale(dt));
+            }
+        }
+
+        let mu = self.body.mu;
+        let accel = |r: Vec3D| r.scale(-mu / r.mag().powi(3)).add(acceleration);
+        let (r, v) = (self.position, self.velocity);
+        let (k1r, k1v) = (v, accel(r));
+        let (k2r, k2v) = (
+            v.add(k1v.scale(dt / 2.0)),
+            accel(r.add(k1r.scale(dt / 2.0))),
+        );
+        let (k3r, k3v) = (
+            v.add(k2v.scale(dt / 2.0)),
+            accel(r.add(k2r.scale(dt / 2.0))),
+        );
+        let (k4r, k4v) = (v.add(k3v.scale(dt)), accel(r.add(k3r.scale(dt))));
+        let sum = |k1: Vec3D, k2: Vec3D, k3: Vec3D, k4: Vec3D| {
+            k1.add(k2.scale(2.0))
+                .add(k3.scale(2.0))
+                .add(k4)
+                .scale(dt / 6.0)
+        };
+        self.position = r.add(sum(k1r, k2r, k3r, k4r));
+        self.velocity = v.add(sum(k1v, k2v, k3v, k4v));
+        self.ut += dt;
+
+        // The ground holds the vessel up until the thrust lifts it
+        self.landed = self.position.mag() <= self.body.radius;
+        if self.landed {
+            self.position = self.position.normalize().scale(self.body.radius);
+            self.velocity = self.rotation_velocity();
+        }
+    }
+
+    /// Skip ahead on rails, without thrust
+    pub fn warp_to(&mut self, ut: f64) {
+        if ut <= self.ut {
+            return;
+        }
+        if self.landed {
+            self.position = rotate(self.position, self.body.rotational_speed * (ut - self.ut));
+            self.velocity = self.rotation_velocity();
+        } else {
+            (self.position, self.velocity) = self.elements().state_at(ut);
+        }
+        self.ut = ut;
+    }
+
+    /// Answer a call from the simulated state, None for procedures it does not simulate
+    pub fn answer(&mut self, call: &ProcedureCall) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let procedure = name(call);
+        let Some(member) = procedure.strip_prefix("SpaceCenter.") else {
+            return Ok(None);
+        };
+        let object = || argument::<u32>(call, 0);
+        // Parts and engines are numbered from their base, clients may pass any id
+        let stages = self.stages.len();
+        let index = |base: u32| -> Result<u32, Box<dyn Error>> {
+            let id = object()?;
+            Ok(id
+                .checked_sub(base)
+                .filter(|&index| (index as usize) < stages)
+                .ok_or_else(|| format!("No part {id}"))?)
+        };
+        let value = match member {
+            "get_UT" => encode(self.ut),
+            "get_ActiveVessel" => encode(VESSEL),
+            "WarpTo" => {
+                self.warp_to(argument(call, 0)?);
+                encode(())
+            }
+
+            "Vessel_get_Orbit" => encode(ORBIT),
+            "Vessel_get_Control" => encode(CONTROL),
+            "Vessel_get_AutoPilot" => encode(AUTO_PILOT),
+            "Vessel_get_Parts" => encode(PARTS),
+            "Vessel_get_ReferenceFrame" => encode(VESSEL_FRAME),
+            "Vessel_Flight" => {
+                let frame: u32 = argument(call, 1)?;
+                self.relative_velocity(frame)?;
+                encode(FLIGHT + frame)
+            }
+            "Vessel_Position" => encode(self.position_in(argument(call, 1)?)?),
+            "Vessel_Velocity" => {
+                let frame = argument(call, 1)?;
+                encode(self.direction_in(frame, self.relative_velocity(frame)?)?)
+            }
+            "Vessel_get_Mass" => encode(self.mass() as f32),
+            "Vessel_get_AvailableThrust" => encode(self.available_thrust() as f32),
+            "Vessel_get_Thrust" => {
+                encode((self.throttle.clamp(0.0, 1.0) as f64 * self.available_thrust()) as f32)
+            }
+            "Vessel_get_SpecificImpulse" => encode(self.burning().map_or(0.0, |s| s.isp) as f32),
+
+            "Orbit_get_Body" => encode(BODY),
+            "Orbit_get_SemiMajorAxis" => encode(self.elements().semi_major_axis),
+            "Orbit_get_Eccentricity" => encode(self.elements().eccentricity),
+            "Orbit_get_Inclination" => encode(self.elements().inclination),
+            "Orbit_get_LongitudeOfAscendingNode" => encode(self.elements().lan),
+            "Orbit_get_ArgumentOfPeriapsis" => encode(self.elements().aop),
+            "Orbit_get_MeanAnomalyAtEpoch" => encode(self.elements().mean_anomaly_at_epoch),
+            "Orbit_get_Epoch" => encode(self.elements().epoch),
+            "Orbit_get_Apoapsis" => encode(self.elements().apoapsis()),
+            "Orbit_get_Periapsis" => encode(self.elements().periapsis()),
+            "Orbit_get_ApoapsisAltitude" => encode(self.elements().apoapsis() - self.body.radius),
+            "Orbit_get_PeriapsisAltitude" => encode(self.elements().periapsis() - self.body.radius),
+            "Orbit_get_TimeToApoapsis" => encode(self.elements().time_to_apoapsis(self.ut)),
+            "Orbit_get_TimeToPeriapsis" => encode(self.elements().time_to_periapsis(self.ut)),
+            "Orbit_get_Radius" => encode(self.position.mag()),
+            "Orbit_get_Speed" => encode(self.velocity.mag()),
+            "Orbit_get_Period" => encode(self.elements().period()),
+            // The inertial frame's x axis is the reference direction of the elements
+            "Orbit_static_ReferencePlaneDirection" => {
+                encode(self.direction_in(argument(call, 0)?, (1.0, 0.0, 0.0))?)
+            }
+
+            "CelestialBody_get_GravitationalParameter" => encode(self.body.mu),
+            "CelestialBody_get_EquatorialRadius" => encode(self.body.radius),
+            "CelestialBody_get_AtmosphereDepth" => encode(self.body.atmosphere_depth),
+            "CelestialBody_get_RotationalSpeed" => encode(self.body.rotational_speed),
+            "CelestialBody_get_RotationalPeriod" => encode(TAU / self.body.rotational_speed),
+            "CelestialBody_get_ReferenceFrame" => encode(SURFACE_FRAME),
+            "CelestialBody_get_NonRotatingReferenceFrame" => encode(INERTIAL_FRAME),
+            "CelestialBody_SurfaceHeight" => encode(0.0),
+
+            "Flight_get_SurfaceAltitude" | "Flight_get_MeanAltitude" => encode(self.altitude()),
+            "Flight_get_Speed" => encode(self.flight_velocity(object()?)?.mag()),
+            "Flight_get_VerticalSpeed" => encode(self.flight_velocity(object()?)?.dot(self.up())),
+            "Flight_get_HorizontalSpeed" => {
+                encode(self.flight_velocity(object()?)?.reject(self.up()).mag())
+            }
+            "Flight_get_Pitch" => encode(self.pitch(self.facing).to_degrees() as f32),
+            "Flight_get_Heading" => encode(self.heading(self.facing).to_degrees() as f32),
+            "Flight_get_AngleOfAttack" => encode(self.angle_of_attack().to_degrees() as f32),
+            "Flight_get_Latitude" => {
+                let surface = self.surface_position();
+                encode((surface.2 / surface.mag()).asin().to_degrees())
+            }
+            "Flight_get_Longitude" => {
+                let surface = self.surface_position();
+                encode(surface.1.atan2(surface.0).to_degrees())
+            }
+
+            "Control_get_Throttle" => encode(self.throttle),
+            "Control_set_Throttle" => {
+                self.throttle = argument(call, 1)?;
+                encode(())
+            }
+            "Control_set_SAS" | "Control_set_RCS" | "Control_set_Lights" => encode(()),
+            "Control_get_CurrentStage" => encode(self.current_stage()),
+            "Control_ActivateNextStage" => {
+                self.activate_next_stage();
+                encode(Vec::<u32>::new())
+            }
+            "Control_AddNode" => encode(self.add_node(
+                argument(call, 1)?,
+                argument::<f32>(call, 2)? as f64,
+                argument::<f32>(call, 3)? as f64,
+                argument::<f32>(call, 4)? as f64,
+            )),
+            "Control_get_Nodes" => encode(self.nodes.iter().map(|n| n.id).collect::<Vec<_>>()),
+            "Control_RemoveNodes" => {
+                self.nodes.clear();
+                encode(())
+            }
+
+            "Node_get_UT" => encode(self.node(object()?)?.ut),
+            "Node_get_Prograde" => encode(self.node(object()?)?.prograde),
+            "Node_get_Normal" => encode(self.node(object()?)?.normal),
+            "Node_get_Radial" => encode(self.node(object()?)?.radial),
+            "Node_get_DeltaV" => encode(self.node(object()?)?.burn.mag()),
+            "Node_get_RemainingDeltaV" => {
+                let node = self.node(object()?)?;
+                encode(node.burn.sub(node.applied).mag())
+            }
+            "Node_get_OrbitalReferenceFrame" => {
+                encode(NODE_FRAME + self.node(object()?)?.id - NODE)
+            }
+            "Node_BurnVector" => {
+                let burn = self.node(object()?)?.burn;
+                encode(self.direction_in(argument(call, 1)?, burn)?)
+            }
+            "Node_RemainingBurnVector" => {
+                let node = self.node(object()?)?;
+                encode(self.direction_in(argument(call, 1)?, node.burn.sub(node.applied))?)
+            }
+            "Node_Remove" => {
+                let id = object()?;
+                self.nodes.retain(|node| node.id != id);
+                encode(())
+            }
+
+            "AutoPilot_Engage" => {
+                self.auto_pilot.engaged = true;
+                encode(())
+            }
+            "AutoPilot_Disengage" => {
+                self.auto_pilot.engaged = false;
+                encode(())
+            }
+            // The vessel is always pointing at the target
+            "AutoPilot_Wait" | "AutoPilot_set_TargetRoll" => encode(()),
+            "AutoPilot_set_ReferenceFrame" => {
+                self.auto_pilot.reference_frame = argument(call, 1)?;
+                encode(())
+            }
+            "AutoPilot_set_TargetDirection" => {
+                self.auto_pilot.direction = Some(argument(call, 1)?);
+                encode(())
+            }
+            "AutoPilot_set_TargetPitch" => {
+                self.auto_pilot.pitch = argument::<f32>(call, 1)? as f64;
+                self.auto_pilot.direction = None;
+                encode(())
+            }
+            "AutoPilot_set_TargetHeading" => {
+                self.auto_pilot.heading = argument::<f32>(call, 1)? as f64;
+                self.auto_pilot.direction = None;
+                encode(())
+            }
+            "AutoPilot_TargetPitchAndHeading" => {
+                self.auto_pilot.pitch = argument::<f32>(call, 1)? as f64;
+                self.auto_pilot.heading = argument::<f32>(call, 2)? as f64;
+                self.auto_pilot.direction = None;
+                encode(())
+            }
+
+            "Parts_get_All" => encode(self.attached().map(|(k, _)| PART + k).collect::<Vec<_>>()),
+            "Parts_get_Engines" => {
+                encode(self.attached().map(|(k, _)| ENGINE + k).collect::<Vec<_>>())
+            }
+            "Part_get_Mass" => encode(self.part(index(PART)?)?.mass()),
+            "Part_get_DryMass" => encode(self.part(index(PART)?)?.dry_mass),
+            "Part_get_Stage" => encode(self.activation_stage(index(PART)?)),
+            "Part_get_DecoupleStage" => encode(self.activation_stage(index(PART)?) - 1),
+            "Part_get_Crossfeed" => encode(false),
+            // Each stage is stacked on the next one to fire, the last is the root
+            "Part_get_Parent" => {
+                let index = index(PART)?;
+                encode(if index + 1 < stages as u32 {
+                    PART + index + 1
+                } else {
+                    0
+                })
+            }
+            "Part_get_FuelLinesTo" => encode(Vec::<u32>::new()),
+            "Part_get_Resources" => encode(index(PART)? + RESOURCES),
+            "Resources_get_All" => encode(vec![index(RESOURCES)? + RESOURCE]),
+            "Resource_get_Name" => encode(PROPELLANT.to_string()),
+            "Resource_get_Amount" => {
+                encode((self.stages[index(RESOURCE)? as usize].propellant / DENSITY) as f32)
+            }
+            "Resource_get_Density" => encode(DENSITY as f32),
+            "Resource_get_FlowMode" => encode(ResourceFlowMode::None),
+            "Engine_get_Part" => encode(index(ENGINE)? + PART),
+            "Engine_get_MaxVacuumThrust" => encode(self.part(index(ENGINE)?)?.thrust as f32),
+            "Engine_get_ThrustLimit" => encode(1f32),
+            "Engine_get_VacuumSpecificImpulse" => encode(self.part(index(ENGINE)?)?.isp as f32),
+            // No atmosphere to lose thrust in
+            "Engine_get_KerbinSeaLevelSpecificImpulse" => {
+                encode(self.part(index(ENGINE)?)?.isp as f32)
+            }
+            "Engine_get_PropellantRatios" => {
+                encode(HashMap::from([(PROPELLANT.to_string(), 1f32)]))
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    fn add_node(&mut self, ut: f64, prograde: f64, normal: f64, radial: f64) -> u32 {
+        let (position, velocity) = self.elements().state_at(ut);
+        let prograde_dir = velocity.normalize();
+        let normal_dir = position.cross(velocity).normalize();
+        let radial_dir = prograde_dir.cross(normal_dir);
+        let id = NODE + self.next_node;
+        self.next_node += 1;
+        self.nodes.push(Node {
+            id,
+            ut,
+            prograde,
+            normal,
+            radial,
+            burn: prograde_dir
+                .scale(prograde)
+                .add(normal_dir.scale(normal))
+                .add(radial_dir.scale(radial)),
+            applied: (0.0, 0.0, 0.0),
+            axes: [radial_dir.neg(), prograde_dir, normal_dir],
+        });
+        id
+    }
+
+    fn node(&self, id: u32) -> Result<Node, Box<dyn Error>> {
+        self.nodes
+            .iter()
+            .find(|node| node.id == id)
+            .copied()
+            .ok_or_else(|| format!("No node {id}").into())
+    }
+
+    /// Stages not yet dropped, with their index in firing order
+    fn attached(&self) -> impl Iterator<Item = (u32, &Stage)> {
+        let first = self.activated.saturating_sub(1);
+        (first..self.stages.len()).map(|k| (k as u32, &self.stages[k]))
+    }
+
+    fn burning(&self) -> Option<&Stage> {
+        self.activated.checked_sub(1).map(|k| &self.stages[k])
+    }
+
+    fn part(&self, index: u32) -> Result<Stage, Box<dyn Error>> {
+        self.attached()
+            .find(|&(k, _)| k == index)
+            .map(|(_, stage)| *stage)
+            .ok_or_else(|| format!("No part {index}").into())
+    }
+
+    /// Stage number at which the part's engine is activated, it is dropped one stage later
+    fn activation_stage(&self, index: u32) -> i32 {
+        self.stages.len() as i32 - 1 - index as i32
+    }
+
+    /// Body's rotation since UT 0
+    fn rotation_angle(&self) -> f64 {
+        self.body.rotational_speed * self.ut
+    }
+
+    fn rotation_velocity(&self) -> Vec3D {
+        (0.0, 0.0, self.body.rotational_speed).cross(self.position)
+    }
+
+    fn surface_position(&self) -> Vec3D {
+        rotate(self.position, -self.rotation_angle())
+    }
+
+    fn up(&self) -> Vec3D {
+        self.position.normalize()
+    }
+
+    /// Local east and north, along the surface
+    fn horizon(&self) -> (Vec3D, Vec3D) {
+        let up = self.up();
+        let east = (0.0, 0.0, 1.0).cross(up).normalize();
+        (east, up.cross(east))
+    }
+
+    fn pitch(&self, direction: Vec3D) -> f64 {
+        FRAC_PI_2 - direction.vang(self.up())
+    }
+
+    fn heading(&self, direction: Vec3D) -> f64 {
+        let (east, north) = self.horizon();
+        direction
+            .dot(east)
+            .atan2(direction.dot(north))
+            .rem_euclid(TAU)
+    }
+
+    /// Pitch above the surface velocity, none while standing still
+    fn angle_of_attack(&self) -> f64 {
+        let velocity = self.velocity.sub(self.rotation_velocity());
+        if velocity.mag() < 1e-3 {
+            return 0.0;
+        }
+        self.pitch(self.facing) - self.pitch(velocity)
+    }
+
+    /// Autopilot's target as an inertial unit vector
+    fn target(&self) -> Result<Vec3D, Box<dyn Error>> {
+        let auto_pilot = &self.auto_pilot;
+        if let Some(direction) = auto_pilot.direction {
+            return Ok(self
+                .direction_from(auto_pilot.reference_frame, direction)?
+                .normalize());
+        }
+        let (east, north) = self.horizon();
+        let (sin_pitch, cos_pitch) = auto_pilot.pitch.to_radians().sin_cos();
+        let (sin_heading, cos_heading) = auto_pilot.heading.to_radians().sin_cos();
+        Ok(self.up().scale(sin_pitch).add(
+            north
+                .scale(cos_heading)
+                .add(east.scale(sin_heading))
+                .scale(cos_pitch),
+        ))
+    }
+
+    /// Velocity relative to a flight's frame
+    fn flight_velocity(&self, flight: u32) -> Result<Vec3D, Box<dyn Error>> {
+        self.relative_velocity(flight.wrapping_sub(FLIGHT))
+    }
+
+    fn relative_velocity(&self, frame: u32) -> Result<Vec3D, Box<dyn Error>> {
+        match frame {
+            VESSEL_FRAME => Ok((0.0, 0.0, 0.0)),
+            SURFACE_FRAME => Ok(self.velocity.sub(self.rotation_velocity())),
+            INERTIAL_FRAME => Ok(self.velocity),
+            _ => Err(format!("Unsupported reference frame {frame}").into()),
+        }
+    }
+
+    fn position_in(&self, frame: u32) -> Result<Vec3D, Box<dyn Error>> {
+        match frame {
+            VESSEL_FRAME => Ok((0.0, 0.0, 0.0)),
+            SURFACE_FRAME | INERTIAL_FRAME => self.direction_in(frame, self.position),
+            _ => Err(format!("Unsupported reference frame {frame}").into()),
+        }
+    }
+
+    /// Inertial vector as kRPC returns it in the left-handed frame
+    fn direction_in(&self, frame: u32, vector: Vec3D) -> Result<Vec3D, Box<dyn Error>> {
+        match frame {
+            SURFACE_FRAME => Ok(rotate(vector, -self.rotation_angle()).flip_handedness()),
+            INERTIAL_FRAME => Ok(vector.flip_handedness()),
+            _ => {
+                let axes = self.node_axes(frame)?;
+                Ok((
+                    vector.dot(axes[0]),
+                    vector.dot(axes[1]),
+                    vector.dot(axes[2]),
+                ))
+            }
+        }
+    }
+
+    /// Vector in the left-handed frame back in the inertial frame
+    fn direction_from(&self, frame: u32, vector: Vec3D) -> Result<Vec3D, Box<dyn Error>> {
+        match frame {
+            SURFACE_FRAME => Ok(rotate(vector.flip_handedness(), self.rotation_angle())),
+            INERTIAL_FRAME => Ok(vector.flip_handedness()),
+            _ => {
+                let axes = self.node_axes(frame)?;
+                Ok(axes[0]
+                    .scale(vector.0)
+                    .add(axes[1].scale(vector.1))
+                    .add(axes[2].scale(vector.2)))
+            }
+        }
+    }
+
+    fn node_axes(&self, frame: u32) -> Result<[Vec3D; 3], Box<dyn Error>> {
+        let id = frame
+            .checked_sub(NODE_FRAME)
+            .ok_or_else(|| format!("Unsupported reference frame {frame}"))?;
+        Ok(self.node(NODE + id)?.axes)
+    }
+}
+
+/// Rotate about the north pole
+fn rotate(vector: Vec3D, angle: f64) -> Vec3D {
+    let (sin, cos) = angle.sin_cos();
+    (
+        vector.0 * cos - vector.1 * sin,
+        vector.0 * sin + vector.1 * cos,
+        vector.2,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        orbit::OrbitalElements,
+        sim::{Body, Simulation, Stage},
+        stage::G0,
+        vector::Vector,
+    };
+
+    fn stages() -> Vec<Stage> {
+        vec![
+            Stage {
+                dry_mass: 4000.0,
+                propellant: 16000.0,
+                thrust: 300_000.0,
+                isp: 300.0,
+            },
+            Stage {
+                dry_mass: 1500.0,
+                propellant: 3500.0,
+                thrust: 60_000.0,
+                isp: 350.0,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_coasting_follows_kepler() {
+        let orbit = OrbitalElements {
+            semi_major_axis: 800_000.0,
+            eccentricity: 0.1,
+            inclination: 0.4,
+            lan: 1.0,
+            aop: 2.0,
+            mean_anomaly_at_epoch: 0.5,
+            epoch: 0.0,
+            mu: Body::kerbin().mu,
+        };
+        let mut sim = Simulation::orbiting(Body::kerbin(), &orbit, 100.0, stages());
+        for _ in 0..20000 {
+            sim.step();
+        }
+        let (position, velocity) = orbit.state_at(sim.ut);
+        assert!(sim.position.sub(position).mag() < 1.0);
+        assert!(sim.velocity.sub(velocity).mag() < 1e-3);
+
+        sim.warp_to(5000.0);
+        let (position, _) = orbit.state_at(5000.0);
+        assert!(sim.position.sub(position).mag() < 1.0);
+    }
+
+    #[test]
+    fn test_climbs_and_drops_spent_stage() {
+        let mut sim = Simulation::landed(Body::kerbin(), 0.0, 0.0, stages());
+        assert_eq!(sim.current_stage(), 2);
+        assert_eq!(sim.available_thrust(), 0.0);
+        // Held on the pad until the engines light
+        sim.step();
+        assert!(sim.altitude().abs() < 1e-9);
+
+        // Straight up, losing at most surface gravity over the burn
+        sim.auto_pilot.engaged = true;
+        sim.auto_pilot.pitch = 90.0;
+        sim.throttle = 1.0;
+        sim.activate_next_stage();
+        let start = sim.ut;
+        while sim.available_thrust() > 0.0 {
+            sim.step();
+        }
+        let ideal = 300.0 * G0 * (25_000.0f64 / 9_000.0).ln();
+        let gravity = sim.body.mu / sim.body.radius.powi(2) * (sim.ut - start);
+        let climb = sim.velocity.dot(sim.position.normalize());
+        assert!(climb < ideal && climb > ideal - gravity);
+        assert!(sim.altitude() > 0.0);
+        assert_eq!(sim.mass(), 9_000.0);
+
+        sim.activate_next_stage();
+        assert_eq!(sim.current_stage(), 0);
+        assert_eq!(sim.mass(), 5_000.0);
+        assert_eq!(sim.available_thrust(), 60_000.0);
+    }
+}
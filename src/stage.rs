@@ -0,0 +1,179 @@
+use std::error::Error;
+
+use krpc_mars::RPCClient;
+
+use crate::{
+    deltav::{StageDeltaV, Vehicle},
+    error,
+    services::space_center::Vessel,
+};
+
+/// Standard gravity used by KSP to convert specific impulse to exhaust velocity
+pub const G0: f64 = 9.80665;
+
+/// Delta-v shortfall small enough to be rounding error when summing stages
+const DV_TOLERANCE: f64 = 1e-6;
+
+/// Vessel between two staging events
+/// Masses are in kg, thrust in N and specific impulse in s, all in vacuum
+#[derive(Debug, Copy, Clone)]
+pub struct Stage {
+    pub number: i32,
+    /// Mass when the stage starts burning
+    pub wet_mass: f64,
+    /// Mass when the stage has burnt its propellant, before anything is decoupled
+    pub dry_mass: f64,
+    pub thrust: f64,
+    pub isp: f64,
+}
+
+impl Stage {
+    pub fn exhaust_velocity(&self) -> f64 {
+        self.isp * G0
+    }
+
+    pub fn mass_flow(&self) -> f64 {
+        self.thrust / self.exhaust_velocity()
+    }
+
+    pub fn delta_v(&self) -> f64 {
+        if self.thrust <= 0.0 {
+            return 0.0;
+        }
+        self.exhaust_velocity() * (self.wet_mass / self.dry_mass).ln()
+    }
+
+    pub fn burn_time(&self) -> f64 {
+        if self.thrust <= 0.0 {
+            return 0.0;
+        }
+        (self.wet_mass - self.dry_mass) / self.mass_flow()
+    }
+}
+
+impl From<&StageDeltaV> for Stage {
+    /// Thrust and specific impulse are averaged over the stage's burn, keeping its delta-v and burn time
+    fn from(stage: &StageDeltaV) -> Self {
+        let burnt = stage.start_mass - stage.end_mass;
+        let (thrust, isp) = if burnt > 0.0 && stage.burn_time > 0.0 {
+            let isp = stage.vacuum_delta_v / (G0 * (stage.start_mass / stage.end_mass).ln());
+            (burnt / stage.burn_time * isp * G0, isp)
+        } else {
+            (0.0, 0.0)
+        };
+        Self {
+            number: stage.number,
+            wet_mass: stage.start_mass,
+            dry_mass: stage.end_mass,
+            thrust,
+            isp,
+        }
+    }
+}
+
+/// Walk the vessel's parts and engines and split them into stages, next to burn first
+pub fn stages(client: &mut RPCClient, vessel: &Vessel) -> Result<Vec<Stage>, Box<dyn Error>> {
+    Ok(split(&Vehicle::snapshot(client, vessel)?))
+}
+
+/// Stages from the current one down to 0, burnt as the delta-v readout burns them
+pub fn split(vehicle: &Vehicle) -> Vec<Stage> {
+    vehicle.analyze(G0).iter().map(Stage::from).collect()
+}
+
+/// Time needed to gain `delta_v`, burning the stages in order
+/// Stages left dry are skipped, so staging itself is assumed instantaneous
+pub fn burn_time(stages: &[Stage], delta_v: f64) -> Result<f64, Box<dyn Error>> {
+    let mut remaining = delta_v;
+    let mut time = 0.0;
+    for stage in stages.iter().filter(|s| s.thrust > 0.0) {
+        let stage_dv = stage.delta_v();
+        if remaining <= stage_dv + DV_TOLERANCE {
+            let remaining = remaining.min(stage_dv);
+            let end_mass = stage.wet_mass / (remaining / stage.exhaust_velocity()).exp();
+            return Ok(time + (stage.wet_mass - end_mass) / stage.mass_flow());
+        }
+        remaining -= stage_dv;
+        time += stage.burn_time();
+    }
+    Err(error::Error::Performance(format!("Not enough delta-v, {remaining} m/s short")).into())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        deltav::{EngineNode, Flow, PartNode, Tank, Vehicle},
+        error::Error,
+        stage::{burn_time, split, G0},
+    };
+
+    fn part(decouple_stage: i32, parent: Option<usize>, fuel: f64) -> PartNode {
+        PartNode {
+            decouple_stage,
+            dry_mass: 500.0,
+            crossfeed: true,
+            parent,
+            fuel_lines: vec![],
+            tanks: vec![Tank {
+                name: "LiquidFuel".into(),
+                amount: fuel / 5.0,
+                density: 5.0,
+                flow: Flow::Crossfeed,
+            }],
+        }
+    }
+
+    fn engine(part: usize, stage: i32, thrust: f64) -> EngineNode {
+        EngineNode {
+            part,
+            stage,
+            thrust,
+            vacuum_isp: 320.0,
+            sea_level_isp: 280.0,
+            propellants: vec![("LiquidFuel".into(), 1.0)],
+        }
+    }
+
+    /// Upper tank and engine, a decoupler, then the lower tank and engine dropped by stage 1
+    fn two_stage() -> Vehicle {
+        let mut decoupler = part(1, Some(1), 0.0);
+        decoupler.crossfeed = false;
+        Vehicle {
+            current_stage: 2,
+            parts: vec![
+                part(-1, None, 2000.0),
+                part(-1, Some(0), 0.0),
+                decoupler,
+                part(1, Some(2), 8000.0),
+                part(1, Some(3), 0.0),
+            ],
+            engines: vec![engine(4, 2, 200_000.0), engine(1, 1, 60_000.0)],
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let stages = split(&two_stage());
+        assert_eq!(stages.len(), 3);
+        assert!((stages[0].wet_mass - 12500.0).abs() < 1e-6);
+        assert!((stages[0].dry_mass - 4500.0).abs() < 1e-6);
+        assert!((stages[0].thrust - 200_000.0).abs() < 1e-6);
+        assert!((stages[1].wet_mass - 3000.0).abs() < 1e-6);
+        assert!((stages[1].dry_mass - 1000.0).abs() < 1e-6);
+        assert!((stages[1].thrust - 60_000.0).abs() < 1e-6);
+        assert_eq!(stages[2].thrust, 0.0);
+        let expected = 320.0 * G0 * (12500f64 / 4500.0).ln();
+        assert!((stages[0].delta_v() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_burn_time_spans_stages() {
+        let stages = split(&two_stage());
+        let first = stages[0].delta_v();
+        assert!((burn_time(&stages, first).unwrap() - stages[0].burn_time()).abs() < 1e-9);
+        let total = burn_time(&stages, first + stages[1].delta_v()).unwrap();
+        assert!((total - stages[0].burn_time() - stages[1].burn_time()).abs() < 1e-9);
+        let short = burn_time(&stages, first + stages[1].delta_v() + 1.0).map_err(Error::from);
+        assert!(matches!(short, Err(Error::Performance(_))));
+    }
+}
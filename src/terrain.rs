@@ -0,0 +1,302 @@
+use std::{error::Error, fs::File, io::Write, path::Path};
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    services::{li_dar::Laser, space_center::CelestialBody},
+    vector::{Quat, Quaternion, Vec3D, Vector},
+};
+
+/// LiDAR points gathered over several scans
+/// Points are right-handed in the body's rotating frame, z towards the north pole
+#[derive(Debug, Clone, Default)]
+pub struct Terrain {
+    pub points: Vec<Vec3D>,
+}
+
+impl Terrain {
+    /// Add a scan from the laser, returning the number of points it held
+    /// The laser reports its cloud in its part's reference frame
+    pub fn scan(
+        &mut self,
+        client: &mut RPCClient,
+        laser: &Laser,
+        body: &CelestialBody,
+    ) -> Result<usize, Box<dyn Error>> {
+        let (part, rf) =
+            batch_call_unwrap!(client, (&laser.get_part(), &body.get_reference_frame()))?;
+        let (cloud, position, rotation): (Vec<f64>, Vec3D, Quat) = batch_call_unwrap!(
+            client,
+            (&laser.get_cloud(), &part.position(rf), &part.rotation(rf))
+        )?;
+        let before = self.points.len();
+        self.points.extend(cloud.chunks_exact(3).map(|p| {
+            rotation
+                .rotate((p[0], p[1], p[2]))
+                .add(position)
+                .flip_handedness()
+        }));
+        Ok(self.points.len() - before)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SiteOptions {
+    /// Radius of ground the lander needs
+    pub footprint: f64,
+    /// Steepest slope in radians anywhere under the footprint
+    pub max_slope: f64,
+    /// Roughest cell under the footprint, as the spread of its heights
+    pub max_roughness: f64,
+}
+
+impl Default for SiteOptions {
+    fn default() -> Self {
+        Self {
+            footprint: 5.0,
+            max_slope: 10f64.to_radians(),
+            max_roughness: 0.5,
+        }
+    }
+}
+
+/// Candidate landing site, lower scores being better
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    /// Centre on the map, east and north of its origin
+    pub east: f64,
+    pub north: f64,
+    /// Centre in the same frame as the terrain's points
+    pub position: Vec3D,
+    pub slope: f64,
+    pub roughness: f64,
+    pub score: f64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Cell {
+    count: usize,
+    sum: f64,
+    sum_sq: f64,
+}
+
+/// Square grid of heights on the plane tangent to the body at its origin
+/// Heights are radial distances above the origin
+#[derive(Debug, Clone)]
+pub struct HeightMap {
+    origin: Vec3D,
+    east: Vec3D,
+    north: Vec3D,
+    pub cell_size: f64,
+    /// Cells along each side, centred on the origin
+    pub size: usize,
+    cells: Vec<Cell>,
+}
+
+impl HeightMap {
+    pub fn build(points: &[Vec3D], origin: Vec3D, cell_size: f64, size: usize) -> Self {
+        let up = origin.normalize();
+        let east = (0.0, 0.0, 1.0).cross(up);
+        // Over a pole any horizontal direction will do
+        let east = if east.mag() < 1e-9 {
+            (0.0, 1.0, 0.0)
+        } else {
+            east.normalize()
+        };
+        let mut map = Self {
+            origin,
+            east,
+            north: up.cross(east),
+            cell_size,
+            size,
+            cells: vec![Cell::default(); size * size],
+        };
+        let half = size as f64 * cell_size / 2.0;
+        for &point in points {
+            let offset = point.sub(origin);
+            let i = ((offset.dot(map.east) + half) / cell_size).floor();
+            let j = ((offset.dot(map.north) + half) / cell_size).floor();
+            if i < 0.0 || j < 0.0 || i >= size as f64 || j >= size as f64 {
+                continue;
+            }
+            let height = point.mag() - origin.mag();
+            let cell = &mut map.cells[j as usize * size + i as usize];
+            cell.count += 1;
+            cell.sum += height;
+            cell.sum_sq += height.powi(2);
+        }
+        map
+    }
+
+    fn cell(&self, i: usize, j: usize) -> Option<&Cell> {
+        (i < self.size && j < self.size)
+            .then(|| &self.cells[j * self.size + i])
+            .filter(|c| c.count > 0)
+    }
+
+    /// Mean height of the cell, east index first
+    pub fn height(&self, i: usize, j: usize) -> Option<f64> {
+        self.cell(i, j).map(|c| c.sum / c.count as f64)
+    }
+
+    /// Standard deviation of the cell's heights
+    pub fn roughness(&self, i: usize, j: usize) -> Option<f64> {
+        self.cell(i, j).map(|c| {
+            let mean = c.sum / c.count as f64;
+            (c.sum_sq / c.count as f64 - mean.powi(2)).max(0.0).sqrt()
+        })
+    }
+
+    /// Slope in radians from the heights of the four neighbours
+    pub fn slope(&self, i: usize, j: usize) -> Option<f64> {
+        if i == 0 || j == 0 {
+            return None;
+        }
+        let d_east = self.height(i + 1, j)? - self.height(i - 1, j)?;
+        let d_north = self.height(i, j + 1)? - self.height(i, j - 1)?;
+        let gradient = (d_east.powi(2) + d_north.powi(2)).sqrt() / (2.0 * self.cell_size);
+        Some(gradient.atan())
+    }
+
+    /// Centre of the cell, east and north of the origin
+    pub fn centre(&self, i: usize, j: usize) -> (f64, f64) {
+        let half = self.size as f64 / 2.0;
+        (
+            (i as f64 + 0.5 - half) * self.cell_size,
+            (j as f64 + 0.5 - half) * self.cell_size,
+        )
+    }
+
+    /// Sites whose whole footprint is mapped and within the limits, best first
+    pub fn sites(&self, options: &SiteOptions) -> Vec<Site> {
+        let reach = (options.footprint / self.cell_size).ceil() as isize;
+        let mut sites = Vec::new();
+        for j in 0..self.size {
+            for i in 0..self.size {
+                let Some((slope, roughness)) = self.worst(i, j, reach) else {
+                    continue;
+                };
+                if slope > options.max_slope || roughness > options.max_roughness {
+                    continue;
+                }
+                let (east, north) = self.centre(i, j);
+                let height = self.height(i, j).unwrap_or_default();
+                let position = self
+                    .origin
+                    .add(self.east.scale(east))
+                    .add(self.north.scale(north))
+                    .normalize()
+                    .scale(self.origin.mag() + height);
+                sites.push(Site {
+                    east,
+                    north,
+                    position,
+                    slope,
+                    roughness,
+                    score: slope / options.max_slope + roughness / options.max_roughness,
+                });
+            }
+        }
+        sites.sort_by(|a, b| a.score.total_cmp(&b.score));
+        sites
+    }
+
+    /// Steepest slope and roughest cell within `reach` cells, if all of them are mapped
+    fn worst(&self, i: usize, j: usize, reach: isize) -> Option<(f64, f64)> {
+        let mut worst = (0.0f64, 0.0f64);
+        for dj in -reach..=reach {
+            for di in -reach..=reach {
+                if di * di + dj * dj > reach * reach {
+                    continue;
+                }
+                let (ci, cj) = (i as isize + di, j as isize + dj);
+                if ci < 0 || cj < 0 {
+                    return None;
+                }
+                let (ci, cj) = (ci as usize, cj as usize);
+                worst.0 = worst.0.max(self.slope(ci, cj)?);
+                worst.1 = worst.1.max(self.roughness(ci, cj)?);
+            }
+        }
+        Some(worst)
+    }
+
+    /// Mapped cells with their slope and roughness, slope empty at the map's edge
+    fn rows(&self) -> impl Iterator<Item = (f64, f64, f64, Option<f64>, f64)> + '_ {
+        (0..self.size).flat_map(move |j| {
+            (0..self.size).filter_map(move |i| {
+                let (east, north) = self.centre(i, j);
+                Some((
+                    east,
+                    north,
+                    self.height(i, j)?,
+                    self.slope(i, j),
+                    self.roughness(i, j)?,
+                ))
+            })
+        })
+    }
+
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(path)?;
+        writeln!(file, "east,north,height,slope,roughness")?;
+        for (east, north, height, slope, roughness) in self.rows() {
+            let slope = slope.map(|s| s.to_string()).unwrap_or_default();
+            writeln!(file, "{east},{north},{height},{slope},{roughness}")?;
+        }
+        Ok(())
+    }
+
+    /// ASCII point cloud of the cell centres, x east, y north and z up
+    pub fn write_ply(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let rows: Vec<_> = self.rows().collect();
+        let mut file = File::create(path)?;
+        writeln!(file, "ply\nformat ascii 1.0")?;
+        writeln!(file, "element vertex {}", rows.len())?;
+        for property in ["x", "y", "z", "slope", "roughness"] {
+            writeln!(file, "property float {property}")?;
+        }
+        writeln!(file, "end_header")?;
+        for (east, north, height, slope, roughness) in rows {
+            writeln!(
+                file,
+                "{east} {north} {height} {} {roughness}",
+                slope.unwrap_or(f64::NAN)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        terrain::{HeightMap, SiteOptions},
+        vector::Vector,
+    };
+
+    #[test]
+    fn test_sites_avoid_slopes() {
+        let radius = 600_000.0;
+        let origin = (radius, 0.0, 0.0);
+        // Flat to the west, rising one in two to the east
+        let mut points = Vec::new();
+        for e in -50..50 {
+            for n in -50..50 {
+                let (e, n) = (e as f64 + 0.5, n as f64 + 0.5);
+                let height = if e < 0.0 { 0.0 } else { 0.5 * e };
+                points.push(origin.add((height, e, n)));
+            }
+        }
+        let map = HeightMap::build(&points, origin, 5.0, 20);
+        assert!(map.slope(3, 10).unwrap() < 1e-3);
+        assert!((map.slope(15, 10).unwrap() - 0.5f64.atan()).abs() < 1e-3);
+        assert!(map.slope(0, 10).is_none());
+
+        let sites = map.sites(&SiteOptions::default());
+        assert!(!sites.is_empty());
+        assert!(sites.iter().all(|s| s.east < 0.0));
+        assert!(sites[0].slope < 1e-3 && sites[0].roughness < 1e-2);
+        assert!((sites[0].position.mag() - radius).abs() < 1.0);
+    }
+}
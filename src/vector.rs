@@ -1,10 +1,24 @@
 pub type Vec3D = (f64, f64, f64);
 
+/// Quaternion as returned by kRPC for rotations: (x, y, z, w)
+pub type Quat = (f64, f64, f64, f64);
+
 pub trait Vector {
     fn mag(self) -> f64;
     fn dot(self, other: Self) -> f64;
     fn cross(self, other: Self) -> Self;
     fn vang(self, other: Self) -> f64;
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+    fn scale(self, factor: f64) -> Self;
+    fn neg(self) -> Self;
+    fn normalize(self) -> Self;
+    /// Component of self along other
+    fn project(self, other: Self) -> Self;
+    /// Component of self perpendicular to other
+    fn reject(self, other: Self) -> Self;
+    /// Swap y and z, converting between kRPC's left-handed frames and right-handed ones
+    fn flip_handedness(self) -> Self;
 }
 
 impl Vector for Vec3D {
@@ -18,13 +32,220 @@ impl Vector for Vec3D {
 
     fn cross(self, other: Self) -> Self {
         (
-            self.2 * other.2 - self.2 * other.1,
-            self.0 * other.0 - self.0 * other.2,
-            self.1 * other.1 - self.1 * other.0,
+            self.1 * other.2 - self.2 * other.1,
+            self.2 * other.0 - self.0 * other.2,
+            self.0 * other.1 - self.1 * other.0,
         )
     }
 
     fn vang(self, other: Self) -> f64 {
-        (self.dot(other) / (self.mag() * other.mag())).acos()
+        (self.dot(other) / (self.mag() * other.mag()))
+            .clamp(-1.0, 1.0)
+            .acos()
+    }
+
+    fn add(self, other: Self) -> Self {
+        (self.0 + other.0, self.1 + other.1, self.2 + other.2)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        (self.0 - other.0, self.1 - other.1, self.2 - other.2)
+    }
+
+    fn scale(self, factor: f64) -> Self {
+        (self.0 * factor, self.1 * factor, self.2 * factor)
+    }
+
+    fn neg(self) -> Self {
+        (-self.0, -self.1, -self.2)
+    }
+
+    fn normalize(self) -> Self {
+        self.scale(1.0 / self.mag())
+    }
+
+    fn project(self, other: Self) -> Self {
+        other.scale(self.dot(other) / other.dot(other))
+    }
+
+    fn reject(self, other: Self) -> Self {
+        self.sub(self.project(other))
+    }
+
+    fn flip_handedness(self) -> Self {
+        (self.0, self.2, self.1)
+    }
+}
+
+/// Rotations follow the same convention as kRPC, so a rotation read from a
+/// `ReferenceFrame` can be applied locally instead of calling `transform_direction`
+pub trait Quaternion {
+    fn identity() -> Self;
+    /// Rotation of `angle` radians about `axis`
+    fn from_axis_angle(axis: Vec3D, angle: f64) -> Self;
+    /// Shortest rotation taking the direction of `from` onto `to`
+    fn between(from: Vec3D, to: Vec3D) -> Self;
+    fn to_axis_angle(self) -> (Vec3D, f64);
+    fn norm(self) -> f64;
+    fn normalize(self) -> Self;
+    fn conjugate(self) -> Self;
+    fn inverse(self) -> Self;
+    /// Hamilton product, applying other first and then self
+    fn mul(self, other: Self) -> Self;
+    fn rotate(self, v: Vec3D) -> Vec3D;
+}
+
+impl Quaternion for Quat {
+    fn identity() -> Self {
+        (0.0, 0.0, 0.0, 1.0)
+    }
+
+    fn from_axis_angle(axis: Vec3D, angle: f64) -> Self {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        let (x, y, z) = axis.normalize().scale(sin);
+        (x, y, z, cos)
+    }
+
+    fn between(from: Vec3D, to: Vec3D) -> Self {
+        let from = from.normalize();
+        let to = to.normalize();
+        let d = from.dot(to);
+        if d < -1.0 + 1e-12 {
+            // Opposite directions, rotate half a turn about any perpendicular axis
+            let mut axis = (1.0, 0.0, 0.0).cross(from);
+            if axis.mag() < 1e-6 {
+                axis = (0.0, 1.0, 0.0).cross(from);
+            }
+            return Self::from_axis_angle(axis, std::f64::consts::PI);
+        }
+        let (x, y, z) = from.cross(to);
+        (x, y, z, 1.0 + d).normalize()
+    }
+
+    fn to_axis_angle(self) -> (Vec3D, f64) {
+        let (x, y, z, w) = self.normalize();
+        let sin = (x * x + y * y + z * z).sqrt();
+        if sin < 1e-12 {
+            return ((1.0, 0.0, 0.0), 0.0);
+        }
+        ((x / sin, y / sin, z / sin), 2.0 * sin.atan2(w))
+    }
+
+    fn norm(self) -> f64 {
+        (self.0.powi(2) + self.1.powi(2) + self.2.powi(2) + self.3.powi(2)).sqrt()
+    }
+
+    fn normalize(self) -> Self {
+        let n = self.norm();
+        (self.0 / n, self.1 / n, self.2 / n, self.3 / n)
+    }
+
+    fn conjugate(self) -> Self {
+        (-self.0, -self.1, -self.2, self.3)
+    }
+
+    fn inverse(self) -> Self {
+        let n = self.0.powi(2) + self.1.powi(2) + self.2.powi(2) + self.3.powi(2);
+        let (x, y, z, w) = self.conjugate();
+        (x / n, y / n, z / n, w / n)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let (x1, y1, z1, w1) = self;
+        let (x2, y2, z2, w2) = other;
+        (
+            w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
+            w1 * y2 - x1 * z2 + y1 * w2 + z1 * x2,
+            w1 * z2 + x1 * y2 - y1 * x2 + z1 * w2,
+            w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
+        )
+    }
+
+    fn rotate(self, v: Vec3D) -> Vec3D {
+        let u = (self.0, self.1, self.2);
+        let w = self.3;
+        let t = u.cross(v).scale(2.0);
+        v.add(t.scale(w)).add(u.cross(t))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    use proptest::prelude::*;
+
+    use crate::vector::{Quat, Quaternion, Vec3D, Vector};
+
+    fn close(a: Vec3D, b: Vec3D, scale: f64) -> bool {
+        a.sub(b).mag() <= 1e-9 * scale.max(1.0)
+    }
+
+    fn vec3() -> impl Strategy<Value = Vec3D> {
+        (-1e3..1e3, -1e3..1e3, -1e3..1e3)
+    }
+
+    fn axis() -> impl Strategy<Value = Vec3D> {
+        vec3().prop_filter("non-zero axis", |v| v.mag() > 1e-3)
+    }
+
+    #[test]
+    fn test_cross_basis() {
+        assert_eq!((1.0, 0.0, 0.0).cross((0.0, 1.0, 0.0)), (0.0, 0.0, 1.0));
+        assert_eq!((0.0, 1.0, 0.0).cross((0.0, 0.0, 1.0)), (1.0, 0.0, 0.0));
+        assert_eq!((0.0, 0.0, 1.0).cross((1.0, 0.0, 0.0)), (0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_rotate_quarter_turn() {
+        let q = Quat::from_axis_angle((0.0, 0.0, 1.0), FRAC_PI_2);
+        assert!(close(q.rotate((1.0, 0.0, 0.0)), (0.0, 1.0, 0.0), 1.0));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_cross_is_orthogonal(a in vec3(), b in vec3()) {
+            let c = a.cross(b);
+            let scale = a.mag() * b.mag();
+            prop_assert!(c.dot(a).abs() <= 1e-9 * scale * a.mag().max(1.0));
+            prop_assert!(c.dot(b).abs() <= 1e-9 * scale * b.mag().max(1.0));
+            prop_assert!(close(c, b.cross(a).neg(), scale));
+        }
+
+        #[test]
+        fn prop_projection_splits_vector(a in vec3(), b in axis()) {
+            let sum = a.project(b).add(a.reject(b));
+            prop_assert!(close(sum, a, a.mag()));
+            prop_assert!(a.reject(b).dot(b).abs() <= 1e-6 * a.mag() * b.mag());
+        }
+
+        #[test]
+        fn prop_rotation_preserves_length(v in vec3(), axis in axis(), angle in -PI..PI) {
+            let q = Quat::from_axis_angle(axis, angle);
+            let rotated = q.rotate(v);
+            prop_assert!((rotated.mag() - v.mag()).abs() <= 1e-9 * v.mag().max(1.0));
+            prop_assert!(close(q.conjugate().rotate(rotated), v, v.mag()));
+            prop_assert!(close(q.inverse().rotate(rotated), v, v.mag()));
+        }
+
+        #[test]
+        fn prop_axis_angle_round_trip(axis in axis(), angle in 1e-3..PI) {
+            let (out_axis, out_angle) = Quat::from_axis_angle(axis, angle).to_axis_angle();
+            prop_assert!((out_angle - angle).abs() <= 1e-9);
+            prop_assert!(close(out_axis, axis.normalize(), 1.0));
+        }
+
+        #[test]
+        fn prop_composition(v in vec3(), a1 in axis(), a2 in axis(), t1 in -PI..PI, t2 in -PI..PI) {
+            let q1 = Quat::from_axis_angle(a1, t1);
+            let q2 = Quat::from_axis_angle(a2, t2);
+            prop_assert!(close(q1.mul(q2).rotate(v), q1.rotate(q2.rotate(v)), v.mag()));
+        }
+
+        #[test]
+        fn prop_between_aligns(from in axis(), to in axis()) {
+            let q = Quat::between(from, to);
+            prop_assert!(close(q.rotate(from.normalize()), to.normalize(), 1.0));
+        }
     }
 }
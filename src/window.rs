@@ -0,0 +1,240 @@
+use std::{
+    error::Error,
+    f64::consts::{PI, TAU},
+};
+
+use krpc_mars::{batch_call_unwrap, RPCClient};
+
+use crate::{
+    orbit::OrbitalElements,
+    services::space_center::{self, CelestialBody, Orbit, Vessel},
+    vector::{Vec3D, Vector},
+};
+
+/// Launch site in the body's inertial frame, angles in radians
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    pub latitude: f64,
+    /// Longitude from the reference direction used for the longitude of the ascending node, at `ut`
+    pub longitude: f64,
+    pub ut: f64,
+    /// Distance from the body's centre
+    pub radius: f64,
+    /// Body rotation in radians per second
+    pub rotational_speed: f64,
+}
+
+impl Site {
+    /// Read the vessel's position in the body's inertial frame, the orbit of a landed vessel is too
+    /// close to parabolic to place it
+    pub fn snapshot(client: &mut RPCClient, vessel: &Vessel) -> Result<Self, Box<dyn Error>> {
+        let body = vessel
+            .get_orbit()
+            .mk_call(client)?
+            .get_body()
+            .mk_call(client)?;
+        let rotational_speed = body.get_rotational_speed().mk_call(client)?;
+        let (ut, position) = inertial_position(client, vessel, &body)?;
+        let radius = position.mag();
+        Ok(Self {
+            latitude: (position.2 / radius).asin(),
+            longitude: position.1.atan2(position.0),
+            ut,
+            radius,
+            rotational_speed,
+        })
+    }
+
+    /// Point on the terrain at the given latitude and longitude in degrees
+    /// The vessel's own longitude ties the body's longitudes to its orbit's reference direction
+    pub fn surface(
+        client: &mut RPCClient,
+        vessel: &Vessel,
+        latitude: f64,
+        longitude: f64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let body = vessel
+            .get_orbit()
+            .mk_call(client)?
+            .get_body()
+            .mk_call(client)?;
+        let (rotational_speed, body_radius, height, rf) = batch_call_unwrap!(
+            client,
+            (
+                &body.get_rotational_speed(),
+                &body.get_equatorial_radius(),
+                &body.surface_height(latitude, longitude),
+                &body.get_reference_frame(),
+            )
+        )?;
+        let vessel_longitude = vessel
+            .flight(rf)
+            .mk_call(client)?
+            .get_longitude()
+            .mk_call(client)?;
+        let (ut, position) = inertial_position(client, vessel, &body)?;
+        Ok(Self {
+            latitude: latitude.to_radians(),
+            longitude: position.1.atan2(position.0) + (longitude - vessel_longitude).to_radians(),
+            ut,
+            radius: body_radius + height,
+            rotational_speed,
+        })
+    }
+
+    /// Eastward speed of the surface
+    pub fn surface_speed(&self) -> f64 {
+        self.rotational_speed * self.radius * self.latitude.cos()
+    }
+
+    /// Position carried round by the body's rotation, in the frame of the orbits
+    pub fn position_at(&self, ut: f64) -> Vec3D {
+        let longitude = self.longitude + self.rotational_speed * (ut - self.ut);
+        let (sin_lat, cos_lat) = self.latitude.sin_cos();
+        let (sin_lng, cos_lng) = longitude.sin_cos();
+        (cos_lat * cos_lng, cos_lat * sin_lng, sin_lat).scale(self.radius)
+    }
+}
+
+/// UT and vessel position in the body's inertial frame, right-handed with z towards the north pole
+/// and x along the direction the orbits' longitude of the ascending node is measured from
+fn inertial_position(
+    client: &mut RPCClient,
+    vessel: &Vessel,
+    body: &CelestialBody,
+) -> Result<(f64, Vec3D), Box<dyn Error>> {
+    let rf = body.get_non_rotating_reference_frame().mk_call(client)?;
+    let (ut, position, reference) = batch_call_unwrap!(
+        client,
+        (
+            &space_center::get_ut(),
+            &vessel.position(rf),
+            &Orbit::reference_plane_direction(rf),
+        )
+    )?;
+    let (position, x) = (position.flip_handedness(), reference.flip_handedness());
+    let y = (0.0, 0.0, 1.0).cross(x);
+    Ok((ut, (position.dot(x), position.dot(y), position.2)))
+}
+
+/// Moment the site passes under the target plane
+#[derive(Debug, Copy, Clone)]
+pub struct Window {
+    pub ut: f64,
+    /// Heading clockwise from north to fly relative to the surface, in radians
+    pub azimuth: f64,
+    /// Heading in the inertial frame, without the body's rotation
+    pub inertial_azimuth: f64,
+    pub northbound: bool,
+}
+
+/// Northbound and southbound windows into the target's plane after the site's snapshot
+/// `orbit_speed` is the speed at insertion, used to cancel the surface's rotation from the heading
+/// When the site's latitude exceeds the inclination, both windows launch due east or west
+/// as the site passes closest to the plane
+pub fn windows(site: &Site, target: &OrbitalElements, orbit_speed: f64) -> (Window, Window) {
+    let (sin_inc, cos_inc) = target.inclination.sin_cos();
+    let cos_lat = site.latitude.cos();
+    // Longitude east of the ascending node where the plane crosses the site's latitude,
+    // from sin(dlng) = tan(lat) / tan(inc)
+    let node_offset = (site.latitude.sin() * cos_inc / (cos_lat * sin_inc))
+        .clamp(-1.0, 1.0)
+        .asin();
+    let inertial = (cos_inc / cos_lat).clamp(-1.0, 1.0).asin();
+    let window = |offset: f64, inertial_azimuth: f64, northbound: bool| {
+        let delta = (target.lan + offset - site.longitude) * site.rotational_speed.signum();
+        Window {
+            ut: site.ut + delta.rem_euclid(TAU) / site.rotational_speed.abs(),
+            azimuth: surface_azimuth(site, inertial_azimuth, orbit_speed),
+            inertial_azimuth: inertial_azimuth.rem_euclid(TAU),
+            northbound,
+        }
+    };
+    (
+        window(node_offset, inertial, true),
+        window(PI - node_offset, PI - inertial, false),
+    )
+}
+
+/// Northbound heading into an orbit of the given inclination when launching right away,
+/// wherever that puts the ascending node
+pub fn azimuth(site: &Site, inclination: f64, orbit_speed: f64) -> f64 {
+    let inertial = (inclination.cos() / site.latitude.cos())
+        .clamp(-1.0, 1.0)
+        .asin();
+    surface_azimuth(site, inertial, orbit_speed)
+}
+
+/// Heading relative to the surface that flies the inertial heading at `orbit_speed`
+fn surface_azimuth(site: &Site, inertial_azimuth: f64, orbit_speed: f64) -> f64 {
+    let (east, north) = inertial_azimuth.sin_cos();
+    (orbit_speed * east - site.surface_speed())
+        .atan2(orbit_speed * north)
+        .rem_euclid(TAU)
+}
+
+/// Earliest of the two windows
+pub fn next(site: &Site, target: &OrbitalElements, orbit_speed: f64) -> Window {
+    let (north, south) = windows(site, target, orbit_speed);
+    if south.ut < north.ut {
+        south
+    } else {
+        north
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        orbit::OrbitalElements,
+        vector::{Vec3D, Vector},
+        window::{azimuth, windows, Site},
+    };
+
+    #[test]
+    fn test_windows_lie_in_target_plane() {
+        let site = Site {
+            latitude: 0.2,
+            longitude: 2.5,
+            ut: 1000.0,
+            radius: 600_000.0,
+            rotational_speed: 2.9e-4,
+        };
+        let target = OrbitalElements {
+            semi_major_axis: 700_000.0,
+            eccentricity: 0.0,
+            inclination: 0.6,
+            lan: 1.0,
+            aop: 0.0,
+            mean_anomaly_at_epoch: 0.0,
+            epoch: 0.0,
+            mu: 3.5316e12,
+        };
+        let (sin_lan, cos_lan) = target.lan.sin_cos();
+        let (sin_inc, cos_inc) = target.inclination.sin_cos();
+        let normal: Vec3D = (sin_inc * sin_lan, -sin_inc * cos_lan, cos_inc);
+
+        let (north, south) = windows(&site, &target, 2300.0);
+        for window in [north, south] {
+            assert!(window.ut > site.ut);
+            let longitude = site.longitude + site.rotational_speed * (window.ut - site.ut);
+            let (sin_lat, cos_lat) = site.latitude.sin_cos();
+            let (sin_lng, cos_lng) = longitude.sin_cos();
+            let position = (cos_lat * cos_lng, cos_lat * sin_lng, sin_lat);
+            assert!(position.dot(normal).abs() < 1e-9);
+
+            // Inertial heading along the local east and north
+            let east = (-sin_lng, cos_lng, 0.0);
+            let north = (-sin_lat * cos_lng, -sin_lat * sin_lng, cos_lat);
+            let (sin_az, cos_az) = window.inertial_azimuth.sin_cos();
+            let heading = east.scale(sin_az).add(north.scale(cos_az));
+            assert!(heading.dot(normal).abs() < 1e-9);
+            assert_eq!(heading.2 > 0.0, window.northbound);
+            // The surface's eastward speed is cancelled by heading further from east
+            assert!(window.azimuth.sin() < window.inertial_azimuth.sin());
+        }
+        assert!(north.ut != south.ut);
+        // Launching now flies the northbound window's heading
+        assert!((azimuth(&site, target.inclination, 2300.0) - north.azimuth).abs() < 1e-12);
+    }
+}
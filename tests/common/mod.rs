@@ -0,0 +1,25 @@
+use betterjeb::{
+    mock::MockServer,
+    sim::{Body, Simulation, Stage},
+};
+
+/// Two stages from the equator, the upper one finishing the ascent
+pub fn server() -> MockServer {
+    let mock = MockServer::start().unwrap();
+    let stages = vec![
+        Stage {
+            dry_mass: 4000.0,
+            propellant: 16000.0,
+            thrust: 300_000.0,
+            isp: 300.0,
+        },
+        Stage {
+            dry_mass: 1500.0,
+            propellant: 3500.0,
+            thrust: 60_000.0,
+            isp: 350.0,
+        },
+    ];
+    mock.simulate(Simulation::landed(Body::kerbin(), 0.0, 0.0, stages));
+    mock
+}
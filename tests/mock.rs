@@ -0,0 +1,376 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use betterjeb::{
+    circ::circ,
+    cli::{target, Target},
+    error::{Error, Missing},
+    launch::{launch, AscentProfile},
+    maneuver::{maneuver, BurnStatus},
+    mock::{argument, MockServer, Update},
+    orbit::OrbitalElements,
+    services::space_center::{self, ResourceFlowMode},
+};
+
+const MU: f64 = 3.5316e12;
+
+/// Ids the mock hands out for the game's objects
+const VESSEL: u32 = 1;
+const ORBIT: u32 = 2;
+const BODY: u32 = 3;
+const CONTROL: u32 = 4;
+const AUTO_PILOT: u32 = 5;
+const NODE: u32 = 6;
+const FRAME: u32 = 7;
+const FLIGHT: u32 = 8;
+const PARTS: u32 = 9;
+const PART: u32 = 10;
+const ENGINE: u32 = 11;
+const RESOURCES: u32 = 12;
+const RESOURCE: u32 = 13;
+
+fn server() -> MockServer {
+    let mock = MockServer::start().unwrap();
+    mock.respond("SpaceCenter.get_ActiveVessel", VESSEL);
+    mock.respond("SpaceCenter.Vessel_get_Orbit", ORBIT);
+    mock.respond("SpaceCenter.Orbit_get_Body", BODY);
+    mock.respond("SpaceCenter.CelestialBody_get_GravitationalParameter", MU);
+    mock.respond("SpaceCenter.Vessel_get_Control", CONTROL);
+    mock.respond("SpaceCenter.Vessel_get_AutoPilot", AUTO_PILOT);
+    for setter in [
+        "Control_set_Throttle",
+        "Control_set_SAS",
+        "Control_set_Lights",
+        "AutoPilot_set_ReferenceFrame",
+        "AutoPilot_set_TargetDirection",
+        "AutoPilot_set_TargetPitch",
+        "AutoPilot_TargetPitchAndHeading",
+        "AutoPilot_Engage",
+        "AutoPilot_Wait",
+        "AutoPilot_Disengage",
+        "WarpTo",
+    ] {
+        mock.respond(&format!("SpaceCenter.{setter}"), ());
+    }
+    mock
+}
+
+fn respond_orbit(mock: &MockServer, orbit: &OrbitalElements) {
+    mock.respond("SpaceCenter.Orbit_get_SemiMajorAxis", orbit.semi_major_axis);
+    mock.respond("SpaceCenter.Orbit_get_Eccentricity", orbit.eccentricity);
+    mock.respond("SpaceCenter.Orbit_get_Inclination", orbit.inclination);
+    mock.respond("SpaceCenter.Orbit_get_LongitudeOfAscendingNode", orbit.lan);
+    mock.respond("SpaceCenter.Orbit_get_ArgumentOfPeriapsis", orbit.aop);
+    mock.respond(
+        "SpaceCenter.Orbit_get_MeanAnomalyAtEpoch",
+        orbit.mean_anomaly_at_epoch,
+    );
+    mock.respond("SpaceCenter.Orbit_get_Epoch", orbit.epoch);
+}
+
+/// Single stage of one part with one engine, burning the 5 t of fuel in the part
+fn respond_stages(mock: &MockServer) {
+    mock.respond("SpaceCenter.Vessel_get_Parts", PARTS);
+    mock.respond("SpaceCenter.Control_get_CurrentStage", 0i32);
+    mock.respond("SpaceCenter.Parts_get_All", vec![PART]);
+    mock.respond("SpaceCenter.Parts_get_Engines", vec![ENGINE]);
+    mock.respond("SpaceCenter.Part_get_DecoupleStage", -1i32);
+    mock.respond("SpaceCenter.Part_get_Stage", 0i32);
+    mock.respond("SpaceCenter.Part_get_DryMass", 5000.0);
+    mock.respond("SpaceCenter.Part_get_Crossfeed", true);
+    mock.respond("SpaceCenter.Part_get_Parent", 0u32);
+    mock.respond("SpaceCenter.Part_get_FuelLinesTo", Vec::<u32>::new());
+    mock.respond("SpaceCenter.Part_get_Resources", RESOURCES);
+    mock.respond("SpaceCenter.Resources_get_All", vec![RESOURCE]);
+    mock.respond("SpaceCenter.Resource_get_Name", "LiquidFuel".to_string());
+    mock.respond("SpaceCenter.Resource_get_Amount", 1000f32);
+    mock.respond("SpaceCenter.Resource_get_Density", 5f32);
+    mock.respond("SpaceCenter.Resource_get_FlowMode", ResourceFlowMode::Stage);
+    mock.respond("SpaceCenter.Engine_get_Part", PART);
+    mock.respond("SpaceCenter.Engine_get_MaxVacuumThrust", 200000f32);
+    mock.respond("SpaceCenter.Engine_get_ThrustLimit", 1f32);
+    mock.respond("SpaceCenter.Engine_get_VacuumSpecificImpulse", 300f32);
+    mock.respond(
+        "SpaceCenter.Engine_get_KerbinSeaLevelSpecificImpulse",
+        280f32,
+    );
+    mock.respond(
+        "SpaceCenter.Engine_get_PropellantRatios",
+        HashMap::from([("LiquidFuel".to_string(), 1f32)]),
+    );
+}
+
+fn throttles(mock: &MockServer) -> Vec<f32> {
+    mock.calls_to("SpaceCenter.Control_set_Throttle")
+        .iter()
+        .map(|call| argument(call, 1).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_circ_adds_node_at_apoapsis() {
+    let mock = server();
+    let orbit = OrbitalElements {
+        semi_major_axis: 700_000.0,
+        eccentricity: 0.02,
+        inclination: 0.1,
+        lan: 0.5,
+        aop: 1.0,
+        mean_anomaly_at_epoch: 0.3,
+        epoch: 0.0,
+        mu: MU,
+    };
+    respond_orbit(&mock, &orbit);
+    mock.respond("SpaceCenter.get_UT", 100.0);
+    mock.respond("SpaceCenter.Control_AddNode", NODE);
+
+    let (mut client, _stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    circ(&mut client, &ship).unwrap();
+
+    let nodes = mock.calls_to("SpaceCenter.Control_AddNode");
+    assert_eq!(nodes.len(), 1);
+    let ut: f64 = argument(&nodes[0], 1).unwrap();
+    let prograde: f32 = argument(&nodes[0], 2).unwrap();
+    let apoapsis = orbit.apoapsis();
+    let expected = (MU / apoapsis).sqrt() - orbit.speed_at_radius(apoapsis);
+    assert!((ut - orbit.ut_at_true_anomaly(PI, 100.0)).abs() < 1e-6);
+    assert!((prograde as f64 - expected).abs() < 1e-3);
+    assert_eq!(argument::<f32>(&nodes[0], 3).unwrap(), 0.0);
+}
+
+#[test]
+fn test_maneuver_burns_to_cutoff() {
+    let mock = server();
+    respond_stages(&mock);
+    mock.respond("SpaceCenter.Control_get_Nodes", vec![NODE]);
+    mock.respond("SpaceCenter.Node_get_OrbitalReferenceFrame", FRAME);
+    mock.respond("SpaceCenter.Node_get_UT", 1000.0);
+    mock.respond("SpaceCenter.Node_get_DeltaV", 100.0);
+    mock.respond("SpaceCenter.Node_BurnVector", (100.0, 0.0, 0.0));
+    mock.respond("SpaceCenter.Node_RemainingBurnVector", (0.05, 0.0, 0.0));
+    mock.respond("SpaceCenter.get_UT", 1003.0);
+    mock.respond("SpaceCenter.Vessel_get_AvailableThrust", 200000f32);
+    mock.respond("SpaceCenter.Vessel_get_Mass", 10000f32);
+
+    // At the burn start, halfway through, then inside the cutoff
+    mock.push(
+        Update::new()
+            .set("SpaceCenter.get_UT", 1000.0)
+            .set("SpaceCenter.Node_RemainingBurnVector", (100.0, 0.0, 0.0))
+            .set("SpaceCenter.Vessel_get_AvailableThrust", 200000f32)
+            .set("SpaceCenter.Vessel_get_Mass", 10000f32)
+            .set("SpaceCenter.Control_get_CurrentStage", 0i32),
+    );
+    mock.push(Update::new().set("SpaceCenter.Node_RemainingBurnVector", (50.0, 0.0, 0.0)));
+    mock.push(Update::new().set("SpaceCenter.Node_RemainingBurnVector", (0.05, 0.0, 0.0)));
+
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let result = maneuver(&mut client, &mut stream_client, &ship).unwrap();
+
+    assert_eq!(result.status, BurnStatus::Complete);
+    assert!((result.residual_dv - 0.05).abs() < 1e-9);
+    assert_eq!(throttles(&mock), vec![0.0, 1.0, 0.0]);
+    let warp = &mock.calls_to("SpaceCenter.WarpTo")[0];
+    let warp_ut: f64 = argument(warp, 0).unwrap();
+    assert!(warp_ut < 1000.0 - 60.0 && warp_ut > 1000.0 - 60.0 - 10.0);
+    // Every stream opened is closed again
+    assert_eq!(
+        mock.calls_to("KRPC.AddStream").len(),
+        mock.calls_to("KRPC.RemoveStream").len()
+    );
+}
+
+#[test]
+fn test_maneuver_without_node_is_missing() {
+    let mock = server();
+    mock.respond("SpaceCenter.Control_get_Nodes", Vec::<u32>::new());
+
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let result = maneuver(&mut client, &mut stream_client, &ship);
+
+    assert_eq!(result.unwrap_err(), Error::Missing(Missing::Node));
+}
+
+#[test]
+fn test_maneuver_stops_on_abort() {
+    let mock = server();
+    respond_stages(&mock);
+    mock.respond("SpaceCenter.Control_get_Nodes", vec![NODE]);
+    mock.respond("SpaceCenter.Node_get_OrbitalReferenceFrame", FRAME);
+    mock.respond("SpaceCenter.Node_get_UT", 1000.0);
+    mock.respond("SpaceCenter.Node_get_DeltaV", 100.0);
+    mock.respond("SpaceCenter.Node_BurnVector", (100.0, 0.0, 0.0));
+    mock.push(
+        Update::new()
+            .set("SpaceCenter.get_UT", 900.0)
+            .set("SpaceCenter.Control_get_Abort", true),
+    );
+
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let result = maneuver(&mut client, &mut stream_client, &ship);
+
+    assert_eq!(result.unwrap_err(), Error::Aborted);
+    assert_eq!(throttles(&mock), vec![0.0, 0.0]);
+    assert_eq!(mock.calls_to("SpaceCenter.AutoPilot_Disengage").len(), 1);
+    assert_eq!(
+        mock.calls_to("KRPC.AddStream").len(),
+        mock.calls_to("KRPC.RemoveStream").len()
+    );
+}
+
+#[test]
+fn test_server_exception_names_procedure() {
+    let mock = server();
+    respond_orbit(
+        &mock,
+        &OrbitalElements {
+            semi_major_axis: 700_000.0,
+            eccentricity: 0.02,
+            inclination: 0.1,
+            lan: 0.5,
+            aop: 1.0,
+            mean_anomaly_at_epoch: 0.3,
+            epoch: 0.0,
+            mu: MU,
+        },
+    );
+    mock.fail("SpaceCenter.get_UT", "Game is paused");
+
+    let (mut client, _stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    match circ(&mut client, &ship) {
+        Err(Error::Server {
+            procedure,
+            description,
+        }) => {
+            assert_eq!(procedure, "SpaceCenter.get_UT");
+            assert!(description.contains("Game is paused"));
+        }
+        result => panic!("{result:?}"),
+    }
+}
+
+#[test]
+fn test_launch_turns_and_coasts_out() {
+    let mock = server();
+    mock.respond("SpaceCenter.CelestialBody_get_EquatorialRadius", 600_000.0);
+    mock.respond("SpaceCenter.CelestialBody_get_AtmosphereDepth", 70_000.0);
+    mock.respond("SpaceCenter.Vessel_get_ReferenceFrame", FRAME);
+    mock.respond("SpaceCenter.CelestialBody_get_ReferenceFrame", FRAME);
+    mock.respond(
+        "SpaceCenter.CelestialBody_get_NonRotatingReferenceFrame",
+        FRAME,
+    );
+    mock.respond("SpaceCenter.Vessel_Flight", FLIGHT);
+    mock.respond("SpaceCenter.Control_ActivateNextStage", Vec::<u32>::new());
+    for (procedure, value) in [
+        ("get_UT", 0.0),
+        ("Flight_get_SurfaceAltitude", 0.0),
+        ("Flight_get_Speed", 0.0),
+        ("Orbit_get_ApoapsisAltitude", 0.0),
+        ("Orbit_get_PeriapsisAltitude", 0.0),
+        ("Orbit_get_TimeToApoapsis", 0.0),
+        ("Orbit_get_Radius", 600_000.0),
+        ("Flight_get_VerticalSpeed", 0.0),
+        ("Flight_get_HorizontalSpeed", 0.0),
+    ] {
+        mock.respond(&format!("SpaceCenter.{procedure}"), value);
+    }
+    for (procedure, value) in [
+        ("Flight_get_AngleOfAttack", 0f32),
+        ("Flight_get_Pitch", 90f32),
+        ("Vessel_get_AvailableThrust", 200000f32),
+        ("Vessel_get_Mass", 10000f32),
+        ("Vessel_get_SpecificImpulse", 300f32),
+    ] {
+        mock.respond(&format!("SpaceCenter.{procedure}"), value);
+    }
+    mock.respond("SpaceCenter.Control_get_CurrentStage", 1i32);
+    mock.respond("SpaceCenter.Vessel_Position", (600_000.0, 0.0, 0.0));
+    mock.respond("SpaceCenter.Vessel_Velocity", (0.0, 0.0, 0.0));
+
+    let profile = AscentProfile::default();
+    mock.push(
+        Update::new()
+            .set("SpaceCenter.Vessel_get_AvailableThrust", 200000f32)
+            .set("SpaceCenter.Control_get_CurrentStage", 1i32),
+    );
+    mock.push(
+        Update::new()
+            .set("SpaceCenter.Flight_get_SurfaceAltitude", 2000.0)
+            .set("SpaceCenter.Flight_get_Speed", 150.0),
+    );
+    mock.push(
+        Update::new()
+            .set("SpaceCenter.Flight_get_SurfaceAltitude", 10000.0)
+            .set("SpaceCenter.Flight_get_Pitch", 70f32)
+            .set("SpaceCenter.Flight_get_AngleOfAttack", 2f32),
+    );
+    mock.push(Update::new().set("SpaceCenter.Orbit_get_ApoapsisAltitude", 100_500.0));
+    mock.push(Update::new().set("SpaceCenter.Flight_get_SurfaceAltitude", 71000.0));
+    mock.push(Update::new());
+
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let inserted = launch(&mut client, &mut stream_client, &ship, 90.0, None, &profile).unwrap();
+
+    assert!(!inserted);
+    let lift_off = &mock.calls_to("SpaceCenter.AutoPilot_TargetPitchAndHeading")[0];
+    assert_eq!(argument::<f32>(lift_off, 1).unwrap(), 90.0);
+    assert_eq!(argument::<f32>(lift_off, 2).unwrap(), 90.0);
+    assert_eq!(
+        mock.calls_to("SpaceCenter.Control_ActivateNextStage").len(),
+        1
+    );
+
+    // Pitching over along the profile without exceeding the angle of attack limit
+    let pitches: Vec<f32> = mock
+        .calls_to("SpaceCenter.AutoPilot_set_TargetPitch")
+        .iter()
+        .map(|call| argument(call, 1).unwrap())
+        .collect();
+    let limit = profile.aoa_limit(10000.0).unwrap();
+    assert_eq!(pitches[0], profile.pitch(10000.0).max(70.0 - 2.0 - limit));
+    assert_eq!(pitches.len(), 2);
+    assert_eq!(throttles(&mock), vec![1.0, 0.0, 0.0]);
+    assert_eq!(
+        mock.calls_to("KRPC.AddStream").len(),
+        mock.calls_to("KRPC.RemoveStream").len()
+    );
+}
+
+#[test]
+fn test_target_by_name() {
+    let mock = server();
+    mock.respond("SpaceCenter.get_Vessels", vec![VESSEL, 12u32]);
+    mock.respond("SpaceCenter.Vessel_get_Name", "Ship".to_string());
+    mock.respond("SpaceCenter.Vessel_get_Name", "Station".to_string());
+    mock.respond("SpaceCenter.get_TargetVessel", 0u32);
+    mock.respond("SpaceCenter.get_TargetBody", 0u32);
+
+    let (mut client, _stream_client) = mock.connect().unwrap();
+    match target(&mut client, Some("Station")).unwrap() {
+        Target::Vessel(vessel) => assert_eq!(format!("{vessel:?}"), "Vessel(12)"),
+        target => panic!("{target:?}"),
+    }
+    // Nothing targeted in the game
+    assert_eq!(
+        target(&mut client, None).unwrap_err(),
+        Error::Missing(Missing::Target)
+    );
+}
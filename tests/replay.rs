@@ -0,0 +1,61 @@
+use std::{env, net::TcpListener, thread};
+
+use betterjeb::{
+    launch::{launch, AscentProfile},
+    replay::{load, record, Entry, ReplayServer},
+    services::space_center,
+};
+use krpc_mars::{RPCClient, StreamClient};
+
+mod common;
+
+use common::server;
+
+fn fly(client: &mut RPCClient, stream_client: &mut StreamClient) -> bool {
+    let ship = space_center::get_active_vessel().mk_call(client).unwrap();
+    let profile = AscentProfile::default();
+    launch(client, stream_client, &ship, 90.0, None, &profile).unwrap()
+}
+
+#[test]
+fn test_replay_reproduces_recorded_launch() {
+    let mock = server();
+    let rpc = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (rpc_address, stream_address) = (rpc.local_addr().unwrap(), stream.local_addr().unwrap());
+    let path = env::temp_dir().join(format!("betterjeb-replay-{}.log", std::process::id()));
+
+    let log = path.clone();
+    let (server_rpc, server_stream) = (mock.rpc_address(), mock.stream_address());
+    let recorder =
+        thread::spawn(move || record(&rpc, &stream, server_rpc, server_stream, &log).is_ok());
+    let recorded = {
+        let mut client = RPCClient::connect("test", rpc_address).unwrap();
+        let mut stream_client = StreamClient::connect(&client, stream_address).unwrap();
+        fly(&mut client, &mut stream_client)
+    };
+    assert!(recorder.join().unwrap());
+
+    let entries = load(&path).unwrap();
+    assert!(entries
+        .iter()
+        .any(|entry| matches!(entry, Entry::Update { .. })));
+    // Every call reached the server through the recorder
+    assert_eq!(calls_in_requests(&entries), mock.calls().len());
+
+    let replay = ReplayServer::start(entries).unwrap();
+    let (mut client, mut stream_client) = replay.connect().unwrap();
+    assert_eq!(fly(&mut client, &mut stream_client), recorded);
+    assert_eq!(replay.remaining(), 0);
+    std::fs::remove_file(path).unwrap();
+}
+
+fn calls_in_requests(entries: &[Entry]) -> usize {
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::Request { calls, .. } => calls.len(),
+            Entry::Update { .. } => 0,
+        })
+        .sum()
+}
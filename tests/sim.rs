@@ -0,0 +1,63 @@
+use betterjeb::{
+    circ::circ,
+    launch::{launch, AscentProfile},
+    maneuver::{maneuver, BurnStatus},
+    mock::MockServer,
+    services::space_center,
+};
+
+mod common;
+
+use common::server;
+
+/// Apoapsis and periapsis altitudes of the simulated vessel
+fn apsides(mock: &MockServer) -> (f64, f64) {
+    let sim = mock.simulation().unwrap();
+    let orbit = sim.elements();
+    (
+        orbit.apoapsis() - sim.body.radius,
+        orbit.periapsis() - sim.body.radius,
+    )
+}
+
+#[test]
+fn test_guided_launch_inserts_into_orbit() {
+    let mock = server();
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let profile = AscentProfile {
+        guidance_altitude: Some(35000.0),
+        ..Default::default()
+    };
+    let inserted = launch(&mut client, &mut stream_client, &ship, 90.0, None, &profile).unwrap();
+
+    assert!(inserted);
+    let (apoapsis, periapsis) = apsides(&mock);
+    assert!((apoapsis - 100_000.0).abs() < 5000.0, "{apoapsis}");
+    assert!((periapsis - 100_000.0).abs() < 5000.0, "{periapsis}");
+    assert_eq!(mock.simulation().unwrap().current_stage(), 0);
+}
+
+#[test]
+fn test_coast_out_and_circularize() {
+    let mock = server();
+    let (mut client, mut stream_client) = mock.connect().unwrap();
+    let ship = space_center::get_active_vessel()
+        .mk_call(&mut client)
+        .unwrap();
+    let profile = AscentProfile::default();
+    let inserted = launch(&mut client, &mut stream_client, &ship, 90.0, None, &profile).unwrap();
+    assert!(!inserted);
+    let (apoapsis, periapsis) = apsides(&mock);
+    assert!(apoapsis > 100_000.0 && periapsis < 0.0);
+
+    circ(&mut client, &ship).unwrap();
+    let result = maneuver(&mut client, &mut stream_client, &ship).unwrap();
+
+    assert_eq!(result.status, BurnStatus::Complete);
+    let (apoapsis, periapsis) = apsides(&mock);
+    assert!((apoapsis - 100_000.0).abs() < 5000.0, "{apoapsis}");
+    assert!((periapsis - 100_000.0).abs() < 5000.0, "{periapsis}");
+}